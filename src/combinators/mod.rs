@@ -1,6 +1,7 @@
 use nom::branch::alt;
 use nom::bytes::complete::{is_a, is_not, take, take_until1};
 use nom::bytes::streaming::{tag, tag_no_case, take_while};
+use nom::character::complete::space0;
 use nom::character::is_alphanumeric;
 // use std::str::{self, from_utf8};
 use nom::IResult;
@@ -17,8 +18,9 @@ pub fn colon(i: &str) -> IResult<&str, &str> {
   tag(":")(i)
 }
 
-pub fn space(i: &str) -> IResult<&str, &str> {
-  tag(" ")(i)
+// optional whitespace (OWS in RFC 9110): any run of spaces and tabs
+pub fn ows(i: &str) -> IResult<&str, &str> {
+  space0(i)
 }
 
 // ***************************************************************************
@@ -100,13 +102,13 @@ pub fn http_header_name(s: &str) -> IResult<&str, &str> {
 #[cfg(  feature = "tolerant-http1-parser")]
 pub fn is_header_value_char(ch: char) -> bool {
   let ch_u8 = ch as u8;
-  ch_u8 == 9 || (ch_u8 >= 32 && ch_u8 <= 126) || i >= 160
+  ch_u8 == 9 || (32..=126).contains(&ch_u8) || ch_u8 >= 160
 }
 
 #[cfg(not(feature = "tolerant-http1-parser"))]
 pub fn is_header_value_char(ch: char) -> bool {
   let ch_u8 = ch as u8;
-  ch_u8 == 9 || (ch_u8 >= 32 && ch_u8 <= 126)
+  ch_u8 == 9 || (32..=126).contains(&ch_u8)
 }
 
 pub fn header_value(s: &str) -> IResult<&str, &str> {
@@ -145,14 +147,10 @@ mod tests {
   }
 
   #[test]
-  fn test_space() {
-    assert_eq!(space(" "), Ok(("", " ")));
-    assert_eq!(space(" Hello"), Ok(("Hello", " ")));
-    assert_eq!(space("      "), Ok(("     ", " ")));
-
-    let resp = space("Rust World");
-    let err = Err(Err::Error(Error::new("Rust World", ErrorKind::Tag)));
-    assert_eq!(resp, err);
+  fn test_ows() {
+    assert_eq!(ows(" "), Ok(("", " ")));
+    assert_eq!(ows(" \tHello"), Ok(("Hello", " \t")));
+    assert_eq!(ows("Rust World"), Ok(("Rust World", "")));
   }
 
   #[test]
@@ -0,0 +1,275 @@
+use super::{
+    error_handlers::ErrorRegistry,
+    errors::FlaskError,
+    middleware::{AfterHook, BeforeHook},
+    router::Router,
+    server::Handler
+};
+
+use http::{Method, Request, Response, StatusCode};
+use std::sync::Arc;
+
+#[derive(Default)]
+struct Hooks {
+    before: Vec<BeforeHook>,
+    after: Vec<AfterHook>,
+    error_handlers: ErrorRegistry,
+}
+
+// a blueprint route as mounted on the router: the view wrapped in the blueprint's hooks
+struct HookedHandler {
+    hooks: Arc<Hooks>,
+    view: Box<dyn Handler>,
+}
+
+impl Handler for HookedHandler {
+    fn handle(&self, mut req: Request<Vec<u8>>) -> Response<Vec<u8>> {
+        let short_circuit = self.hooks.before.iter().find_map(|hook| hook(&mut req));
+        let mut resp = match short_circuit {
+            Some(resp) => resp,
+            None => self.hooks.error_handlers.handle(&*self.view, req)
+        };
+        // like Flask, the last registered after hook runs first
+        for hook in self.hooks.after.iter().rev() {
+            resp = hook(resp);
+        }
+        resp
+    }
+}
+
+struct BlueprintRoute {
+    methods: Vec<Method>,
+    pattern: String,
+    name: Option<String>,
+    view: Box<dyn Handler>,
+}
+
+/// A group of routes mounted on a `Router` under a URL prefix, and optionally
+/// only for one host. Its hooks and error handlers apply to its own routes only.
+///
+/// Route names are qualified with the blueprint name, so a route named `show`
+/// in blueprint `users` is built with `url_for("users.show", ...)`.
+///
+/// Error handlers see the responses of the blueprint's views, including the 500
+/// a panicking view turns into, and take precedence over the app's handlers. A
+/// request under the prefix that matches no route is answered by the router,
+/// not the blueprint.
+pub struct Blueprint {
+    name: String,
+    prefix: String,
+    host: Option<String>,
+    routes: Vec<BlueprintRoute>,
+    hooks: Hooks,
+}
+
+impl Blueprint {
+    pub fn new(name: &str, prefix: &str) -> Blueprint {
+        Blueprint {
+            name: name.to_string(),
+            prefix: prefix.trim_end_matches('/').to_string(),
+            host: None,
+            routes: Vec::new(),
+            hooks: Hooks::default(),
+        }
+    }
+
+    /// Only serve these routes for requests to `host` (matched without the port).
+    pub fn host(mut self, host: &str) -> Blueprint {
+        self.host = Some(host.to_string());
+        self
+    }
+
+    /// Add a route; `pattern` is relative to the prefix and uses router syntax.
+    pub fn route<H: Handler>(mut self, methods: &[Method], pattern: &str, handler: H) -> Blueprint {
+        self.routes.push(BlueprintRoute {
+            methods: methods.to_vec(),
+            pattern: pattern.to_string(),
+            name: None,
+            view: Box::new(handler),
+        });
+        self
+    }
+
+    pub fn get<H: Handler>(self, pattern: &str, handler: H) -> Blueprint {
+        self.route(&[Method::GET], pattern, handler)
+    }
+
+    pub fn post<H: Handler>(self, pattern: &str, handler: H) -> Blueprint {
+        self.route(&[Method::POST], pattern, handler)
+    }
+
+    pub fn put<H: Handler>(self, pattern: &str, handler: H) -> Blueprint {
+        self.route(&[Method::PUT], pattern, handler)
+    }
+
+    pub fn patch<H: Handler>(self, pattern: &str, handler: H) -> Blueprint {
+        self.route(&[Method::PATCH], pattern, handler)
+    }
+
+    pub fn delete<H: Handler>(self, pattern: &str, handler: H) -> Blueprint {
+        self.route(&[Method::DELETE], pattern, handler)
+    }
+
+    /// Name the route added last.
+    ///
+    /// # Panics
+    /// When no route was added yet.
+    pub fn name(mut self, name: &str) -> Blueprint {
+        match self.routes.last_mut() {
+            Some(route) => route.name = Some(name.to_string()),
+            None => panic!("Blueprint::name({}) called before any route was added", name)
+        }
+        self
+    }
+
+    /// Run `hook` before each view of this blueprint. The hook may modify the
+    /// request, or return a response to answer without calling the view.
+    pub fn before_request<F>(mut self, hook: F) -> Blueprint
+    where
+        F: Fn(&mut Request<Vec<u8>>) -> Option<Response<Vec<u8>>> + Send + Sync + 'static
+    {
+        self.hooks.before.push(Box::new(hook));
+        self
+    }
+
+    /// Run `hook` on every response of this blueprint, including responses from
+    /// before hooks and error handlers.
+    pub fn after_request<F>(mut self, hook: F) -> Blueprint
+    where
+        F: Fn(Response<Vec<u8>>) -> Response<Vec<u8>> + Send + Sync + 'static
+    {
+        self.hooks.after.push(Box::new(hook));
+        self
+    }
+
+    /// Replace view responses with status `status` by what `handler` makes of them.
+    pub fn error_handler<F>(mut self, status: StatusCode, handler: F) -> Blueprint
+    where
+        F: Fn(Response<Vec<u8>>) -> Response<Vec<u8>> + Send + Sync + 'static
+    {
+        self.hooks.error_handlers.on_status(status, Box::new(handler));
+        self
+    }
+
+    /// Replace view responses made from one `FlaskError` variant, named by its
+    /// constructor: `.on_error(FlaskError::BadRequest, ...)`.
+    pub fn on_error<F>(mut self, variant: fn(String) -> FlaskError, handler: F) -> Blueprint
+    where
+        F: Fn(Response<Vec<u8>>) -> Response<Vec<u8>> + Send + Sync + 'static
+    {
+        self.hooks.error_handlers.on_error(variant, Box::new(handler));
+        self
+    }
+
+    pub(crate) fn mount(self, mut router: Router) -> Router {
+        let hooks = Arc::new(self.hooks);
+        for route in self.routes {
+            let pattern = format!("{}{}", self.prefix, route.pattern);
+            let handler = HookedHandler { hooks: hooks.clone(), view: route.view };
+            router = router.add_route(&route.methods, &pattern, self.host.clone(), Arc::new(handler));
+            if let Some(name) = route.name {
+                router = router.name(&format!("{}.{}", self.name, name));
+            }
+        }
+        router
+    }
+}
+
+
+//#################################################################################################################
+// test cases go below here
+//#################################################################################################################
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::{header, HeaderValue};
+
+    fn text(body: &'static str) -> impl Handler {
+        move |_req: Request<Vec<u8>>| Response::new(body.as_bytes().to_vec())
+    }
+
+    fn call(router: &Router, path: &str, host: &str) -> Response<Vec<u8>> {
+        let req = Request::get(path).header(header::HOST, host).body(Vec::new()).unwrap();
+        router.handle(req)
+    }
+
+    fn body(resp: &Response<Vec<u8>>) -> &str {
+        std::str::from_utf8(resp.body()).unwrap()
+    }
+
+    #[test]
+    fn test_prefix_and_qualified_names() {
+        let users = Blueprint::new("users", "/api/users/")
+            .get("/", text("list")).name("index")
+            .get("/<int:id>", text("one")).name("show");
+        let router = Router::new().get("/", text("home")).blueprint(users);
+
+        assert_eq!(body(&call(&router, "/api/users/", "example.com")), "list");
+        assert_eq!(body(&call(&router, "/api/users/5", "example.com")), "one");
+        assert_eq!(body(&call(&router, "/", "example.com")), "home");
+        assert_eq!(router.url_for("users.show", &[("id", &5)]).unwrap(), "/api/users/5");
+        assert!(router.url_for("show", &[("id", &5)]).is_err());
+    }
+
+    #[test]
+    fn test_host_bound_blueprint() {
+        let admin = Blueprint::new("admin", "").host("Admin.Example.com").get("/", text("admin home"));
+        let router = Router::new().get("/", text("public home")).blueprint(admin);
+
+        assert_eq!(body(&call(&router, "/", "admin.example.com:8080")), "admin home");
+        assert_eq!(body(&call(&router, "/", "www.example.com")), "public home");
+    }
+
+    #[test]
+    fn test_hooks_apply_to_own_routes_only() {
+        let api = Blueprint::new("api", "/api")
+            .before_request(|req: &mut Request<Vec<u8>>| {
+                match req.headers().contains_key(header::AUTHORIZATION) {
+                    true => None,
+                    false => Some(Response::builder().status(StatusCode::UNAUTHORIZED).body(Vec::new()).unwrap())
+                }
+            })
+            .after_request(|mut resp: Response<Vec<u8>>| {
+                resp.headers_mut().insert("x-order", HeaderValue::from_static("first"));
+                resp
+            })
+            .after_request(|mut resp: Response<Vec<u8>>| {
+                resp.headers_mut().insert("x-order", HeaderValue::from_static("second"));
+                resp.headers_mut().insert("x-api", HeaderValue::from_static("1"));
+                resp
+            })
+            .get("/data", text("data"));
+        let router = Router::new().get("/open", text("open")).blueprint(api);
+
+        let resp = call(&router, "/api/data", "example.com");
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(resp.headers()["x-order"], "first");
+
+        let req = Request::get("/api/data").header(header::AUTHORIZATION, "Bearer t").body(Vec::new()).unwrap();
+        let resp = router.handle(req);
+        assert_eq!(body(&resp), "data");
+        assert_eq!(resp.headers()["x-api"], "1");
+
+        let resp = call(&router, "/open", "example.com");
+        assert_eq!(body(&resp), "open");
+        assert!(!resp.headers().contains_key("x-api"));
+    }
+
+    #[test]
+    fn test_blueprint_error_handler() {
+        let api = Blueprint::new("api", "/api")
+            .error_handler(StatusCode::NOT_FOUND, |mut resp: Response<Vec<u8>>| {
+                *resp.body_mut() = b"{\"error\": \"not found\"}".to_vec();
+                resp
+            })
+            .get("/items/<int:id>", |_req: Request<Vec<u8>>| {
+                Response::builder().status(StatusCode::NOT_FOUND).body(Vec::new()).unwrap()
+            });
+        let router = Router::new()
+            .get("/missing", |_req: Request<Vec<u8>>| Response::builder().status(StatusCode::NOT_FOUND).body(Vec::new()).unwrap())
+            .blueprint(api);
+
+        assert_eq!(body(&call(&router, "/api/items/1", "example.com")), "{\"error\": \"not found\"}");
+        assert_eq!(body(&call(&router, "/missing", "example.com")), "");
+    }
+}
@@ -0,0 +1,384 @@
+use super::errors::FlaskError;
+
+use http::{header, HeaderMap, HeaderValue};
+use std::io::{self, prelude::*};
+
+// the largest body read into memory, whatever its framing
+pub(crate) const MAX_BODY_SIZE: usize = 64 << 20;
+// a chunk-size line, extensions included
+const MAX_CHUNK_LINE: usize = 4096;
+// all trailer fields after the last chunk together
+const MAX_TRAILER_SIZE: usize = 8 << 10;
+
+/// How the end of a message body is found (RFC 9112 section 6.3).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum BodyFraming {
+    Empty,
+    Length(usize),
+    Chunked,
+    UntilClose,
+}
+
+impl BodyFraming {
+    /// Framing is delimited when the connection can carry another message afterwards.
+    pub(crate) fn is_delimited(&self) -> bool {
+        *self != BodyFraming::UntilClose
+    }
+}
+
+// true if chunked is the final transfer coding of a Transfer-Encoding value
+pub(crate) fn is_chunked(transfer_encoding: &str) -> bool {
+    match transfer_encoding.rsplit(',').next() {
+        Some(coding) => coding.trim().eq_ignore_ascii_case("chunked"),
+        None => false
+    }
+}
+
+// a line of at most `limit` bytes; a longer one is a 413
+fn read_line<R: BufRead>(reader: &mut R, limit: usize) -> Result<String, FlaskError> {
+    let mut line = String::new();
+    match reader.take(limit as u64).read_line(&mut line) {
+        Ok(0) => Err( FlaskError::BadRequest("Unexpected end of stream in chunked body".to_string()) ),
+        Ok(num_bytes) if num_bytes == limit && !line.ends_with('\n') => {
+            Err( FlaskError::PayloadTooLarge(format!("Line in chunked body exceeds {} bytes", limit)) )
+        },
+        Ok(_) => Ok(line),
+        Err(io_err) => {
+            let msg = format!("Error reading chunked body: {}", io_err);
+            Err( FlaskError::ClientClosedRequest(msg) )
+        }
+    }
+}
+
+fn body_too_large() -> FlaskError {
+    FlaskError::PayloadTooLarge(format!("Body exceeds {} bytes", MAX_BODY_SIZE))
+}
+
+// read a chunk-size line; the size is untrusted, so callers check it before acting on it
+fn parse_chunk_size<R: BufRead>(reader: &mut R) -> Result<usize, FlaskError> {
+    let line = read_line(reader, MAX_CHUNK_LINE)?;
+    // chunk extensions after ';' carry nothing we use
+    let size_str = line.trim_end_matches("\r\n").split(';').next().unwrap_or("").trim();
+    // from_str_radix alone would also take a sign
+    let is_hex = !size_str.is_empty() && size_str.bytes().all(|byte| byte.is_ascii_hexdigit());
+    match usize::from_str_radix(size_str, 16) {
+        Ok(size) if is_hex => Ok(size),
+        _ => Err( FlaskError::BadRequest(format!("Invalid chunk size: {}", size_str)) )
+    }
+}
+
+// the trailer fields after the last chunk are read off the wire and dropped
+fn skip_trailers<R: BufRead>(reader: &mut R) -> Result<(), FlaskError> {
+    let mut trailer_size = 0;
+    loop {
+        let line = read_line(reader, MAX_TRAILER_SIZE - trailer_size)?;
+        if line == "\r\n" {
+            return Ok(());
+        }
+        trailer_size += line.len();
+        if trailer_size >= MAX_TRAILER_SIZE {
+            return Err( FlaskError::PayloadTooLarge(format!("Trailers exceed {} bytes", MAX_TRAILER_SIZE)) );
+        }
+    }
+}
+
+fn read_chunked_body<R: BufRead>(reader: &mut R) -> Result<Vec<u8>, FlaskError> {
+    let mut body: Vec<u8> = Vec::new();
+    loop {
+        let size = parse_chunk_size(reader)?;
+        if size == 0 {
+            break;
+        }
+
+        // the size is untrusted: check it before reading, and let the body grow with the data actually sent
+        let total = match body.len().checked_add(size) {
+            Some(total) if total <= MAX_BODY_SIZE => total,
+            _ => return Err( body_too_large() )
+        };
+        if let Err(io_err) = reader.take(size as u64).read_to_end(&mut body) {
+            let msg = format!("Error reading chunk: {}", io_err);
+            return Err( FlaskError::BadRequest(msg) );
+        }
+        if body.len() != total {
+            return Err( FlaskError::BadRequest("Unexpected end of stream inside a chunk".to_string()) );
+        }
+        if read_line(reader, MAX_CHUNK_LINE)? != "\r\n" {
+            return Err( FlaskError::BadRequest("Malformed chunk: no terminating CRLF".to_string()) );
+        }
+    }
+
+    skip_trailers(reader)?;
+    Ok(body)
+}
+
+/// Once a chunked body is decoded into memory the message is no longer
+/// chunked: describe it by its length instead, so writing it out again frames
+/// the plain body correctly.
+pub(crate) fn unchunk_headers(headers: &mut HeaderMap, body_len: usize) {
+    headers.remove(header::TRANSFER_ENCODING);
+    headers.remove(header::TRAILER);
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(body_len));
+}
+
+pub(crate) fn read_body<R: BufRead>(reader: &mut R, framing: BodyFraming) -> Result<Vec<u8>, FlaskError> {
+    match framing {
+        BodyFraming::Empty => Ok(Vec::new()),
+        BodyFraming::Length(content_length) => {
+            if content_length > MAX_BODY_SIZE {
+                return Err( body_too_large() );
+            }
+            let mut body = Vec::new();
+            match reader.take(content_length as u64).read_to_end(&mut body) {
+                Ok(num_bytes) if num_bytes == content_length => Ok(body),
+                Ok(_) => Err( FlaskError::BadRequest("Body ended before Content-Length bytes".to_string()) ),
+                Err(io_err) => Err( FlaskError::BadRequest(io_err.to_string()) )
+            }
+        },
+        BodyFraming::Chunked => read_chunked_body(reader),
+        BodyFraming::UntilClose => {
+            let mut body = Vec::new();
+            match reader.take(MAX_BODY_SIZE as u64 + 1).read_to_end(&mut body) {
+                Ok(num_bytes) if num_bytes > MAX_BODY_SIZE => Err( body_too_large() ),
+                Ok(_) => Ok(body),
+                Err(io_err) => Err( FlaskError::BadRequest(io_err.to_string()) )
+            }
+        }
+    }
+}
+
+enum BodyState {
+    Length(usize),
+    ChunkSize,
+    ChunkData(usize),
+    UntilClose,
+    Done,
+}
+
+fn invalid_data(flask_err: FlaskError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, flask_err.get_msg().to_string())
+}
+
+/// A message body read from the connection as the caller consumes it, with
+/// the framing (Content-Length or chunked) taken off. Reading stops at the end
+/// of the body, so the reader is positioned at the next message afterwards.
+pub struct BodyReader<'a, R: BufRead> {
+    reader: &'a mut R,
+    state: BodyState,
+}
+
+impl<'a, R: BufRead> BodyReader<'a, R> {
+    pub(crate) fn new(reader: &'a mut R, framing: BodyFraming) -> BodyReader<'a, R> {
+        let state = match framing {
+            BodyFraming::Empty => BodyState::Done,
+            BodyFraming::Length(len) => BodyState::Length(len),
+            BodyFraming::Chunked => BodyState::ChunkSize,
+            BodyFraming::UntilClose => BodyState::UntilClose,
+        };
+        BodyReader { reader, state }
+    }
+
+    /// True once the whole body was read.
+    pub fn is_done(&self) -> bool {
+        matches!(self.state, BodyState::Done)
+    }
+
+    // read a chunk-size line, and the trailers after the last chunk. Chunk
+    // data is handed out as it arrives, so a large size allocates nothing.
+    fn next_chunk(&mut self) -> Result<(), FlaskError> {
+        let size = parse_chunk_size(self.reader)?;
+        if size > 0 {
+            self.state = BodyState::ChunkData(size);
+            return Ok(());
+        }
+        skip_trailers(self.reader)?;
+        self.state = BodyState::Done;
+        Ok(())
+    }
+}
+
+impl<'a, R: BufRead> Read for BodyReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            match self.state {
+                BodyState::Done => return Ok(0),
+                BodyState::UntilClose => {
+                    let num_bytes = self.reader.read(buf)?;
+                    if num_bytes == 0 {
+                        self.state = BodyState::Done;
+                    }
+                    return Ok(num_bytes);
+                },
+                BodyState::Length(0) => self.state = BodyState::Done,
+                BodyState::Length(remaining) => {
+                    let limit = remaining.min(buf.len());
+                    let num_bytes = self.reader.read(&mut buf[..limit])?;
+                    if num_bytes == 0 {
+                        return Err( io::Error::new(io::ErrorKind::UnexpectedEof, "Body ended before Content-Length bytes") );
+                    }
+                    self.state = BodyState::Length(remaining - num_bytes);
+                    return Ok(num_bytes);
+                },
+                BodyState::ChunkSize => self.next_chunk().map_err(invalid_data)?,
+                BodyState::ChunkData(remaining) => {
+                    let limit = remaining.min(buf.len());
+                    let num_bytes = self.reader.read(&mut buf[..limit])?;
+                    if num_bytes == 0 {
+                        return Err( io::Error::new(io::ErrorKind::UnexpectedEof, "Body ended inside a chunk") );
+                    }
+                    if num_bytes == remaining {
+                        if read_line(self.reader, MAX_CHUNK_LINE).map_err(invalid_data)? != "\r\n" {
+                            return Err( invalid_data(FlaskError::BadRequest("Malformed chunk: no terminating CRLF".to_string())) );
+                        }
+                        self.state = BodyState::ChunkSize;
+                    } else {
+                        self.state = BodyState::ChunkData(remaining - num_bytes);
+                    }
+                    return Ok(num_bytes);
+                }
+            }
+        }
+    }
+}
+
+
+//#################################################################################################################
+// test cases go below here
+//#################################################################################################################
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufReader;
+
+    #[test]
+    fn test_is_chunked() {
+        assert!(is_chunked("chunked"));
+        assert!(is_chunked("gzip, Chunked"));
+        assert!(!is_chunked("chunked, gzip"));
+        assert!(!is_chunked("identity"));
+    }
+
+    #[test]
+    fn test_read_chunked_body_with_extensions_and_trailers() {
+        let raw = "4;name=value\r\nWiki\r\n5\r\npedia\r\n0\r\nExpires: never\r\n\r\nNEXT";
+        let mut reader = BufReader::new(raw.as_bytes());
+        let body = read_body(&mut reader, BodyFraming::Chunked).unwrap();
+        assert_eq!(body, b"Wikipedia");
+
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "NEXT");
+    }
+
+    #[test]
+    fn test_read_chunked_body_bad_size() {
+        let mut reader = BufReader::new("zz\r\n".as_bytes());
+        let flask_err = read_body(&mut reader, BodyFraming::Chunked).err().unwrap();
+        assert_eq!(flask_err.get_msg(), "Invalid chunk size: zz");
+    }
+
+    #[test]
+    fn test_read_chunked_body_truncated() {
+        let mut reader = BufReader::new("5\r\nab".as_bytes());
+        assert!(read_body(&mut reader, BodyFraming::Chunked).is_err());
+    }
+
+    fn chunked_status(raw: &[u8]) -> u16 {
+        let mut reader = BufReader::new(raw);
+        read_body(&mut reader, BodyFraming::Chunked).err().map(|err| err.status_code().as_u16()).unwrap_or(200)
+    }
+
+    #[test]
+    fn test_read_chunked_body_untrusted_sizes() {
+        // would overflow the allocation, the running total, or simply be huge
+        assert_eq!(chunked_status(b"ffffffffffffffff\r\n"), 413);
+        assert_eq!(chunked_status(b"1\r\na\r\nffffffffffffffff\r\n"), 413);
+        assert_eq!(chunked_status(b"10000000000\r\nab"), 413);
+        assert_eq!(chunked_status(b"fffffffffffffffff\r\n"), 400);
+        // a size that fits the limit but is not sent is a truncated body, not an allocation
+        assert_eq!(chunked_status(b"3fffff\r\nab"), 400);
+    }
+
+    #[test]
+    fn test_read_chunked_body_line_and_trailer_limits() {
+        let long_extension = format!("1;{}\r\na\r\n0\r\n\r\n", "x".repeat(MAX_CHUNK_LINE));
+        assert_eq!(chunked_status(long_extension.as_bytes()), 413);
+
+        let trailers = format!("0\r\n{}\r\n", "X-Pad: padding\r\n".repeat(MAX_TRAILER_SIZE / 16 + 1));
+        assert_eq!(chunked_status(trailers.as_bytes()), 413);
+        assert_eq!(chunked_status(b"0\r\nX-Pad: padding\r\n\r\n"), 200);
+    }
+
+    #[test]
+    fn test_body_reader_applies_chunk_limits() {
+        let read_all = |raw: &[u8]| {
+            let mut reader = BufReader::new(raw);
+            let mut body = Vec::new();
+            BodyReader::new(&mut reader, BodyFraming::Chunked).read_to_end(&mut body).map(|_| body)
+        };
+        assert_eq!(read_all(b"+4\r\nWiki\r\n0\r\n\r\n").unwrap_err().to_string(), "Invalid chunk size: +4");
+        assert_eq!(chunked_status(b"+4\r\nWiki\r\n0\r\n\r\n"), 400);
+
+        let long_size_line = format!("{}\r\n", "0".repeat(MAX_CHUNK_LINE));
+        assert!(read_all(long_size_line.as_bytes()).unwrap_err().to_string().contains("exceeds"));
+
+        let trailers = format!("0\r\n{}\r\n", "X-Pad: padding\r\n".repeat(MAX_TRAILER_SIZE / 16 + 1));
+        assert_eq!(read_all(trailers.as_bytes()).unwrap_err().to_string(), format!("Trailers exceed {} bytes", MAX_TRAILER_SIZE));
+        assert_eq!(read_all(b"ffffffffffffffff\r\nab").unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_read_body_content_length_limit() {
+        let mut reader = BufReader::new("abc".as_bytes());
+        let flask_err = read_body(&mut reader, BodyFraming::Length(MAX_BODY_SIZE + 1)).err().unwrap();
+        assert_eq!(flask_err.status_code().as_u16(), 413);
+
+        let mut reader = BufReader::new("abc".as_bytes());
+        assert!(read_body(&mut reader, BodyFraming::Length(5)).is_err());
+    }
+
+    #[test]
+    fn test_body_reader_chunked_in_small_reads() {
+        let raw = "4;name=value\r\nWiki\r\n5\r\npedia\r\n0\r\nExpires: never\r\n\r\nNEXT";
+        let mut reader = BufReader::new(raw.as_bytes());
+        let mut body_reader = BodyReader::new(&mut reader, BodyFraming::Chunked);
+        let mut body = Vec::new();
+        let mut buf = [0u8; 3];
+        loop {
+            match body_reader.read(&mut buf).unwrap() {
+                0 => break,
+                num_bytes => body.extend_from_slice(&buf[..num_bytes])
+            }
+        }
+        assert_eq!(body, b"Wikipedia");
+        assert!(body_reader.is_done());
+
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "NEXT");
+    }
+
+    #[test]
+    fn test_body_reader_length_and_errors() {
+        let mut reader = BufReader::new("hello world".as_bytes());
+        let mut body = String::new();
+        BodyReader::new(&mut reader, BodyFraming::Length(5)).read_to_string(&mut body).unwrap();
+        assert_eq!(body, "hello");
+
+        let mut reader = BufReader::new("abc".as_bytes());
+        let err = BodyReader::new(&mut reader, BodyFraming::Length(5)).read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut reader = BufReader::new("zz\r\n".as_bytes());
+        let err = BodyReader::new(&mut reader, BodyFraming::Chunked).read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid chunk size: zz");
+    }
+
+    #[test]
+    fn test_read_body_until_close() {
+        let mut reader = BufReader::new("everything left".as_bytes());
+        assert_eq!(read_body(&mut reader, BodyFraming::UntilClose).unwrap(), b"everything left");
+    }
+}
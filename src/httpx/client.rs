@@ -0,0 +1,709 @@
+use super::{
+    body::is_chunked,
+    cookie_jar::CookieJar,
+    errors::FlaskError,
+    header_has_token,
+    pipeline::exchange_pipelined,
+    pool::ConnectionPool,
+    response::{read_http_response_for, response_framing},
+    write_http_request
+};
+
+use http::{header, HeaderValue, Method, Request, Response, StatusCode, Uri, Version};
+use std::io::{BufRead, BufReader, ErrorKind};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+const DEFAULT_MAX_IDLE_PER_HOST: usize = 8;
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Connections are only shared between requests for the same scheme, host and port.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub(crate) struct ConnKey {
+    pub(crate) scheme: String,
+    pub(crate) host: String,
+    pub(crate) port: u16,
+}
+
+impl ConnKey {
+    pub(crate) fn from_request<B>(req: &Request<B>) -> Result<ConnKey, FlaskError> {
+        ConnKey::from_uri(req.uri())
+    }
+
+    pub(crate) fn from_uri(uri: &Uri) -> Result<ConnKey, FlaskError> {
+        let scheme = match uri.scheme_str() {
+            Some("http") => "http",
+            Some("https") => return Err( FlaskError::NotImplemented("HTTPS is not supported by the client".to_string()) ),
+            Some(other) => {
+                let msg = format!("Unsupported URI scheme: {}", other);
+                return Err( FlaskError::BadRequest(msg) );
+            },
+            None => {
+                let msg = format!("Client requests need an absolute URI, got {}", uri);
+                return Err( FlaskError::BadRequest(msg) );
+            }
+        };
+        let host = match uri.host() {
+            Some(host) => host.trim_start_matches('[').trim_end_matches(']'),
+            None => {
+                let msg = format!("URI has no host: {}", uri);
+                return Err( FlaskError::BadRequest(msg) );
+            }
+        };
+        Ok( ConnKey { scheme: scheme.to_string(), host: host.to_string(), port: uri.port_u16().unwrap_or(80) } )
+    }
+}
+
+pub(crate) fn connect(key: &ConnKey, timeout: Duration) -> Result<TcpStream, FlaskError> {
+    let addrs = match (key.host.as_str(), key.port).to_socket_addrs() {
+        Ok(addrs) => addrs,
+        Err(io_err) => {
+            let msg = format!("Error resolving {}: {}", key.host, io_err);
+            return Err( FlaskError::BadGateway(msg) );
+        }
+    };
+    let mut last_err = format!("No addresses found for {}", key.host);
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(io_err) => last_err = format!("Error connecting to {}:{}: {}", key.host, key.port, io_err)
+        }
+    }
+    Err( FlaskError::BadGateway(last_err) )
+}
+
+/// Read the final response to a request, skipping interim 1xx responses
+/// (other than 101, which ends HTTP on the connection).
+pub(crate) fn read_final_response(reader: &mut BufReader<TcpStream>, method: &Method) -> Result<Response<Vec<u8>>, FlaskError> {
+    loop {
+        let resp = read_http_response_for(reader, method)?;
+        if !resp.status().is_informational() || resp.status() == StatusCode::SWITCHING_PROTOCOLS {
+            return Ok(resp);
+        }
+    }
+}
+
+/// Whether the connection that carried `req` and `resp` may carry another exchange.
+pub(crate) fn is_reusable<B>(req: &Request<B>, resp: &Response<Vec<u8>>) -> bool {
+    if resp.version() != Version::HTTP_11 || resp.status() == StatusCode::SWITCHING_PROTOCOLS {
+        return false;
+    }
+    if header_has_token(req.headers(), header::CONNECTION, "close") || header_has_token(resp.headers(), header::CONNECTION, "close") {
+        return false;
+    }
+    let headers = resp.headers();
+    let chunked = headers.get_all(header::TRANSFER_ENCODING).iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(is_chunked);
+    let content_length = headers.get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    response_framing(resp.status(), Some(req.method()), chunked, content_length).is_delimited()
+}
+
+// safe to resend when a reused connection turns out to be dead (RFC 9110 section 9.2.2)
+// Wait for the response to start on a pooled connection. A server that closed the
+// connection while it sat idle shows up as EOF or a reset before any byte arrives.
+fn server_closed(reader: &mut BufReader<TcpStream>) -> Result<bool, FlaskError> {
+    match reader.fill_buf() {
+        Ok(buf) => Ok(buf.is_empty()),
+        Err(io_err) => match io_err.kind() {
+            ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe => Ok(true),
+            _ => {
+                let msg = format!("Error reading response: {}", io_err);
+                Err( FlaskError::BadGateway(msg) )
+            }
+        }
+    }
+}
+
+fn is_idempotent(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS | Method::TRACE)
+}
+
+// ***************************************************************************
+// redirects
+// ***************************************************************************
+
+fn origin_of(uri: &Uri) -> (Option<&str>, Option<&str>, Option<u16>) {
+    let default_port = match uri.scheme_str() {
+        Some("https") => 443,
+        _ => 80
+    };
+    (uri.scheme_str(), uri.host(), Some(uri.port_u16().unwrap_or(default_port)))
+}
+
+/// Resolve a `Location` value against the URI of the request that received it.
+pub(crate) fn resolve_location(base: &Uri, location: &str) -> Result<Uri, FlaskError> {
+    let scheme = base.scheme_str().unwrap_or("http");
+    let authority = base.authority().map(|authority| authority.as_str()).unwrap_or("");
+    let resolved = if location.contains("://") {
+        location.to_string()
+    } else if let Some(rest) = location.strip_prefix("//") {
+        format!("{}://{}", scheme, rest)
+    } else if location.starts_with('/') {
+        format!("{}://{}{}", scheme, authority, location)
+    } else {
+        let base_path = base.path();
+        let dir = match base_path.rfind('/') {
+            Some(idx) => &base_path[..=idx],
+            None => "/"
+        };
+        format!("{}://{}{}{}", scheme, authority, dir, location)
+    };
+    // the fragment is for the user agent only
+    let resolved = resolved.split('#').next().unwrap_or("");
+    match resolved.parse::<Uri>() {
+        Ok(uri) => Ok(uri),
+        Err(_) => {
+            let msg = format!("Invalid redirect location: {}", location);
+            Err( FlaskError::BadGateway(msg) )
+        }
+    }
+}
+
+/// The request to send next when `resp` redirects `req`, following the method
+/// rewriting rules of RFC 9110 section 15.4. `None` when `resp` is not a
+/// redirect we can follow.
+pub(crate) fn redirect_request(req: &Request<Vec<u8>>, resp: &Response<Vec<u8>>) -> Result<Option<Request<Vec<u8>>>, FlaskError> {
+    let status = resp.status().as_u16();
+    if !matches!(status, 301 | 302 | 303 | 307 | 308) {
+        return Ok(None);
+    }
+    let location = match resp.headers().get(header::LOCATION).and_then(|value| value.to_str().ok()) {
+        Some(location) => location,
+        None => return Ok(None)
+    };
+    let uri = resolve_location(req.uri(), location)?;
+
+    let rewrite_to_get = match status {
+        301 | 302 => req.method() == Method::POST,
+        303 => req.method() != Method::HEAD,
+        _ => false
+    };
+    let mut headers = req.headers().clone();
+    let (method, body) = if rewrite_to_get {
+        headers.remove(header::CONTENT_LENGTH);
+        headers.remove(header::CONTENT_TYPE);
+        headers.remove(header::TRANSFER_ENCODING);
+        (Method::GET, Vec::new())
+    } else {
+        (req.method().clone(), req.body().clone())
+    };
+
+    headers.remove(header::HOST);
+    if origin_of(&uri) != origin_of(req.uri()) {
+        // credentials are only meant for the origin they were sent to
+        headers.remove(header::AUTHORIZATION);
+        headers.remove(header::COOKIE);
+    }
+
+    let mut next = Request::new(body);
+    *next.method_mut() = method;
+    *next.uri_mut() = uri;
+    *next.version_mut() = req.version();
+    *next.headers_mut() = headers;
+    Ok( Some(next) )
+}
+
+// ***************************************************************************
+// the client
+// ***************************************************************************
+
+/// A small blocking HTTP/1.1 client for plain `http://` URIs.
+pub struct Client {
+    connect_timeout: Duration,
+    read_timeout: Option<Duration>,
+    pool: ConnectionPool,
+    max_redirects: usize,
+    cookie_jar: Option<Arc<CookieJar>>,
+}
+
+impl Default for Client {
+    fn default() -> Client {
+        Client::new()
+    }
+}
+
+impl Client {
+    pub fn new() -> Client {
+        Client {
+            connect_timeout: Duration::from_secs(10),
+            read_timeout: None,
+            pool: ConnectionPool::new(DEFAULT_MAX_IDLE_PER_HOST, DEFAULT_IDLE_TIMEOUT),
+            max_redirects: 0,
+            cookie_jar: None,
+        }
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Client {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    pub fn read_timeout(mut self, timeout: Duration) -> Client {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Keep at most `max_idle` idle connections per scheme, host and port. Zero disables reuse.
+    pub fn max_idle_per_host(mut self, max_idle: usize) -> Client {
+        self.pool.set_max_idle_per_host(max_idle);
+        self
+    }
+
+    /// Close connections that have sat unused in the pool for longer than `timeout`.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Client {
+        self.pool.set_idle_timeout(timeout);
+        self
+    }
+
+    /// Follow up to `max_hops` redirects. Zero, the default, returns redirects as they are.
+    pub fn follow_redirects(mut self, max_hops: usize) -> Client {
+        self.max_redirects = max_hops;
+        self
+    }
+
+    /// Send cookies from `jar` and store the cookies servers set in it.
+    pub fn cookie_jar(mut self, jar: Arc<CookieJar>) -> Client {
+        self.cookie_jar = Some(jar);
+        self
+    }
+
+    /// Number of connections currently waiting in the pool.
+    pub fn idle_connections(&self) -> usize {
+        self.pool.evict_expired();
+        self.pool.idle_count()
+    }
+
+    fn open(&self, key: &ConnKey) -> Result<BufReader<TcpStream>, FlaskError> {
+        let stream = connect(key, self.connect_timeout)?;
+        // a failure to set the timeout only means we may block longer than asked
+        let _ = stream.set_read_timeout(self.read_timeout);
+        Ok( BufReader::new(stream) )
+    }
+
+    fn exchange(&self, key: ConnKey, mut reader: BufReader<TcpStream>, req: &Request<Vec<u8>>) -> Result<Response<Vec<u8>>, FlaskError> {
+        write_http_request(reader.get_mut(), req)?;
+        self.finish_exchange(key, reader, req)
+    }
+
+    fn finish_exchange(&self, key: ConnKey, mut reader: BufReader<TcpStream>, req: &Request<Vec<u8>>) -> Result<Response<Vec<u8>>, FlaskError> {
+        let resp = read_final_response(&mut reader, req.method())?;
+        if is_reusable(req, &resp) {
+            self.pool.checkin(key, reader);
+        }
+        Ok(resp)
+    }
+
+    /// Send all of `reqs` back-to-back on one connection and return the responses
+    /// in request order. Every request must be for the same scheme, host and port.
+    /// Requests are not retried, so pipeline non-idempotent requests with care.
+    pub fn send_pipelined(&self, reqs: &[Request<Vec<u8>>]) -> Result<Vec<Response<Vec<u8>>>, FlaskError> {
+        let key = match reqs.first() {
+            Some(first) => ConnKey::from_request(first)?,
+            None => return Ok(Vec::new())
+        };
+        for req in reqs.iter() {
+            if ConnKey::from_request(req)? != key {
+                let msg = format!("Pipelined requests must share one origin, got {}", req.uri());
+                return Err( FlaskError::BadRequest(msg) );
+            }
+        }
+
+        let mut reader = match self.pool.checkout(&key) {
+            Some(reader) => reader,
+            None => self.open(&key)?
+        };
+        let responses = exchange_pipelined(&mut reader, reqs)?;
+        if reqs.iter().zip(responses.iter()).all(|(req, resp)| is_reusable(req, resp)) {
+            self.pool.checkin(key, reader);
+        }
+        Ok(responses)
+    }
+
+    fn send_once(&self, req: &Request<Vec<u8>>) -> Result<Response<Vec<u8>>, FlaskError> {
+        let key = ConnKey::from_request(req)?;
+        match self.pool.checkout(&key) {
+            Some(mut reader) if is_idempotent(req.method()) => {
+                let closed = match write_http_request(reader.get_mut(), req) {
+                    Ok(_) => server_closed(&mut reader)?,
+                    Err(_) => true
+                };
+                if closed {
+                    let reader = self.open(&key)?;
+                    return self.exchange(key, reader, req);
+                }
+                self.finish_exchange(key, reader, req)
+            },
+            Some(reader) => self.exchange(key, reader, req),
+            None => {
+                let reader = self.open(&key)?;
+                self.exchange(key, reader, req)
+            }
+        }
+    }
+
+    /// Send `req` to the server named by its absolute URI and read the response.
+    /// The connection goes back to the pool when both sides allow it. If a pooled
+    /// connection turns out to have been closed by the server, idempotent requests
+    /// are retried once on a fresh connection. Only a failed write, or an EOF or
+    /// reset before the first byte of the response, counts as closed; a timeout
+    /// is not retried.
+    pub fn send(&self, mut req: Request<Vec<u8>>) -> Result<Response<Vec<u8>>, FlaskError> {
+        // cookies set by the caller are merged with the jar's on every same-origin hop
+        let mut user_cookie = req.headers_mut().remove(header::COOKIE);
+        let mut hops = 0;
+        loop {
+            let jar_cookie = self.cookie_jar.as_ref().and_then(|jar| jar.cookie_header(req.uri()));
+            let cookie = match (&user_cookie, jar_cookie) {
+                (Some(user), Some(jar)) => {
+                    let joined = format!("{}; {}", user.to_str().unwrap_or(""), jar.to_str().unwrap_or(""));
+                    HeaderValue::from_str(&joined).ok()
+                },
+                (Some(user), None) => Some(user.clone()),
+                (None, jar) => jar
+            };
+            if let Some(cookie) = cookie {
+                req.headers_mut().insert(header::COOKIE, cookie);
+            }
+
+            let resp = self.send_once(&req)?;
+            if let Some(jar) = &self.cookie_jar {
+                jar.store_response_cookies(req.uri(), resp.headers());
+            }
+
+            if self.max_redirects == 0 {
+                return Ok(resp);
+            }
+            match redirect_request(&req, &resp)? {
+                Some(mut next) => {
+                    hops += 1;
+                    if hops > self.max_redirects {
+                        let msg = format!("Too many redirects (limit {})", self.max_redirects);
+                        return Err( FlaskError::BadGateway(msg) );
+                    }
+                    if origin_of(next.uri()) != origin_of(req.uri()) {
+                        user_cookie = None;
+                    }
+                    next.headers_mut().remove(header::COOKIE);
+                    req = next;
+                },
+                None => return Ok(resp)
+            }
+        }
+    }
+}
+
+
+//#################################################################################################################
+// test cases go below here
+//#################################################################################################################
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::httpx::{read_http_request_from, write_http_response};
+    use mockito::Matcher;
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    fn get(uri: &str) -> Request<Vec<u8>> {
+        Request::get(uri).body(Vec::new()).unwrap()
+    }
+
+    #[test]
+    fn test_send_get() {
+        let mut s = mockito::Server::new();
+        let _mock = s.mock("GET", "/hello")
+            .match_header("host", s.host_with_port().as_str())
+            .with_body("Hello World!")
+            .create();
+
+        let resp = Client::new().send(get(&format!("{}/hello", s.url()))).unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.body(), b"Hello World!");
+
+        _mock.assert();
+    }
+
+    #[test]
+    fn test_send_post_with_body() {
+        let mut s = mockito::Server::new();
+        let _mock = s.mock("POST", "/echo")
+            .match_body(Matcher::Exact("ping".to_string()))
+            .with_status(201)
+            .with_body("pong")
+            .create();
+
+        let req = Request::post(format!("{}/echo", s.url())).body(b"ping".to_vec()).unwrap();
+        let resp = Client::new().send(req).unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert_eq!(resp.body(), b"pong");
+
+        _mock.assert();
+    }
+
+    #[test]
+    fn test_send_chunked_and_head() {
+        let mut s = mockito::Server::new();
+        let _chunked = s.mock("GET", "/stream")
+            .with_chunked_body(|w| { w.write_all(b"a")?; w.write_all(b"bc") })
+            .create();
+        let _head = s.mock("HEAD", "/stream").with_header("content-length", "3").create();
+
+        let client = Client::new();
+        let resp = client.send(get(&format!("{}/stream", s.url()))).unwrap();
+        assert_eq!(resp.body(), b"abc");
+
+        let head = Request::head(format!("{}/stream", s.url())).body(Vec::new()).unwrap();
+        let resp = client.send(head).unwrap();
+        assert!(resp.body().is_empty());
+    }
+
+    #[test]
+    fn test_reuses_keep_alive_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let accepted_count = accepted.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                accepted_count.fetch_add(1, Ordering::SeqCst);
+                let mut reader = BufReader::new(stream.unwrap());
+                while let Ok(req) = read_http_request_from(&mut reader) {
+                    let resp = Response::new(req.uri().path().as_bytes().to_vec());
+                    write_http_response(reader.get_mut(), &resp).unwrap();
+                }
+            }
+        });
+
+        let client = Client::new();
+        for path in ["/one", "/two", "/three"] {
+            let resp = client.send(get(&format!("http://{}{}", addr, path))).unwrap();
+            assert_eq!(resp.body(), path.as_bytes());
+        }
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    }
+
+    // answers the first request on each connection, then drops the connection
+    // when the next one arrives, like a server whose keep-alive timer just fired
+    fn spawn_one_shot_server() -> (std::net::SocketAddr, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let accepted_count = accepted.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                accepted_count.fetch_add(1, Ordering::SeqCst);
+                let mut reader = BufReader::new(stream.unwrap());
+                if read_http_request_from(&mut reader).is_ok() {
+                    write_http_response(reader.get_mut(), &Response::new(b"ok".to_vec())).unwrap();
+                }
+                let _ = read_http_request_from(&mut reader);
+            }
+        });
+        (addr, accepted)
+    }
+
+    #[test]
+    fn test_stale_connection_retried_for_idempotent_request() {
+        let (addr, accepted) = spawn_one_shot_server();
+        let client = Client::new();
+        for _ in 0..2 {
+            let resp = client.send(get(&format!("http://{}/", addr))).unwrap();
+            assert_eq!(resp.body(), b"ok");
+        }
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_stale_connection_not_retried_for_post() {
+        let (addr, _accepted) = spawn_one_shot_server();
+        let client = Client::new();
+        let post = || Request::post(format!("http://{}/", addr)).body(b"data".to_vec()).unwrap();
+        assert!(client.send(post()).is_ok());
+        assert!(client.send(post()).is_err());
+    }
+
+    #[test]
+    fn test_read_timeout_on_pooled_connection_not_retried() {
+        // answers the first request, then sits on the second one
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let seen = Arc::new(AtomicUsize::new(0));
+        let seen_count = seen.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut reader = BufReader::new(stream.unwrap());
+                while read_http_request_from(&mut reader).is_ok() {
+                    if seen_count.fetch_add(1, Ordering::SeqCst) == 0 {
+                        write_http_response(reader.get_mut(), &Response::new(b"ok".to_vec())).unwrap();
+                    }
+                }
+            }
+        });
+
+        let client = Client::new().read_timeout(Duration::from_millis(200));
+        assert_eq!(client.send(get(&format!("http://{}/", addr))).unwrap().body(), b"ok");
+        assert!(client.send(get(&format!("http://{}/", addr))).is_err());
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(seen.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_idle_connection_limits() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.unwrap());
+                    while let Ok(_req) = read_http_request_from(&mut reader) {
+                        write_http_response(reader.get_mut(), &Response::new(Vec::new())).unwrap();
+                    }
+                });
+            }
+        });
+
+        let client = Client::new().idle_timeout(Duration::from_millis(50));
+        client.send(get(&format!("http://{}/", addr))).unwrap();
+        assert_eq!(client.idle_connections(), 1);
+        thread::sleep(Duration::from_millis(80));
+        assert_eq!(client.idle_connections(), 0);
+
+        let no_reuse = Client::new().max_idle_per_host(0);
+        no_reuse.send(get(&format!("http://{}/", addr))).unwrap();
+        assert_eq!(no_reuse.idle_connections(), 0);
+    }
+
+    fn redirect_response(status: u16, location: &str) -> Response<Vec<u8>> {
+        Response::builder().status(status).header("location", location).body(Vec::new()).unwrap()
+    }
+
+    #[test]
+    fn test_resolve_location() {
+        let base: Uri = "http://example.com/a/b?q=1".parse().unwrap();
+        assert_eq!(resolve_location(&base, "http://other.com/x").unwrap(), "http://other.com/x");
+        assert_eq!(resolve_location(&base, "//cdn.example.com/y").unwrap(), "http://cdn.example.com/y");
+        assert_eq!(resolve_location(&base, "/root#frag").unwrap(), "http://example.com/root");
+        assert_eq!(resolve_location(&base, "c?d=2").unwrap(), "http://example.com/a/c?d=2");
+    }
+
+    #[test]
+    fn test_redirect_method_rewriting() {
+        let post = Request::post("http://example.com/form")
+            .header("content-type", "text/plain")
+            .body(b"data".to_vec())
+            .unwrap();
+
+        for status in [301, 302, 303] {
+            let next = redirect_request(&post, &redirect_response(status, "/done")).unwrap().unwrap();
+            assert_eq!(next.method(), Method::GET);
+            assert!(next.body().is_empty());
+            assert!(!next.headers().contains_key("content-type"));
+        }
+        for status in [307, 308] {
+            let next = redirect_request(&post, &redirect_response(status, "/done")).unwrap().unwrap();
+            assert_eq!(next.method(), Method::POST);
+            assert_eq!(next.body(), b"data");
+        }
+
+        let put = Request::put("http://example.com/").body(b"x".to_vec()).unwrap();
+        let next = redirect_request(&put, &redirect_response(302, "/")).unwrap().unwrap();
+        assert_eq!(next.method(), Method::PUT);
+        let next = redirect_request(&put, &redirect_response(303, "/")).unwrap().unwrap();
+        assert_eq!(next.method(), Method::GET);
+
+        let head = Request::head("http://example.com/").body(Vec::new()).unwrap();
+        let next = redirect_request(&head, &redirect_response(303, "/")).unwrap().unwrap();
+        assert_eq!(next.method(), Method::HEAD);
+    }
+
+    #[test]
+    fn test_redirect_strips_authorization_cross_origin() {
+        let req = Request::get("http://example.com/")
+            .header("authorization", "Bearer secret")
+            .body(Vec::new())
+            .unwrap();
+
+        let same = redirect_request(&req, &redirect_response(302, "/next")).unwrap().unwrap();
+        assert_eq!(same.headers()["authorization"], "Bearer secret");
+
+        let cross = redirect_request(&req, &redirect_response(302, "http://example.com:8080/")).unwrap().unwrap();
+        assert!(!cross.headers().contains_key("authorization"));
+    }
+
+    #[test]
+    fn test_follow_redirects_with_cookies() {
+        let mut s = mockito::Server::new();
+        let _login = s.mock("POST", "/login")
+            .with_status(303)
+            .with_header("location", "/home")
+            .with_header("set-cookie", "session=abc; Path=/")
+            .create();
+        let _home = s.mock("GET", "/home")
+            .match_header("cookie", "session=abc")
+            .with_body("welcome")
+            .create();
+
+        let jar = Arc::new(CookieJar::new());
+        let client = Client::new().follow_redirects(5).cookie_jar(jar.clone());
+        let req = Request::post(format!("{}/login", s.url())).body(b"user=alice".to_vec()).unwrap();
+        let resp = client.send(req).unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.body(), b"welcome");
+        assert_eq!(jar.get("session"), Some("abc".to_string()));
+
+        _login.assert();
+        _home.assert();
+    }
+
+    #[test]
+    fn test_too_many_redirects() {
+        let mut s = mockito::Server::new();
+        let _loop = s.mock("GET", "/loop")
+            .with_status(302)
+            .with_header("location", "/loop")
+            .expect(3)
+            .create();
+
+        let client = Client::new().follow_redirects(2);
+        let flask_err = client.send(get(&format!("{}/loop", s.url()))).err().unwrap();
+        assert_eq!(flask_err.get_msg(), "Too many redirects (limit 2)");
+
+        _loop.assert();
+    }
+
+    #[test]
+    fn test_redirects_not_followed_by_default() {
+        let mut s = mockito::Server::new();
+        let _moved = s.mock("GET", "/old").with_status(301).with_header("location", "/new").create();
+
+        let resp = Client::new().send(get(&format!("{}/old", s.url()))).unwrap();
+        assert_eq!(resp.status(), StatusCode::MOVED_PERMANENTLY);
+    }
+
+    #[test]
+    fn test_connection_close_is_not_reused() {
+        let req = get("http://example.com/");
+        let resp = Response::builder().header("connection", "close").header("content-length", "0").body(Vec::new()).unwrap();
+        assert!(!is_reusable(&req, &resp));
+
+        let until_close = Response::new(Vec::new());
+        assert!(!is_reusable(&req, &until_close));
+    }
+
+    #[test]
+    fn test_rejects_unsupported_uris() {
+        let client = Client::new();
+        let flask_err = client.send(get("https://example.com/")).err().unwrap();
+        assert_eq!(flask_err.get_msg(), "HTTPS is not supported by the client");
+
+        let flask_err = client.send(get("/relative")).err().unwrap();
+        assert_eq!(flask_err.get_msg(), "Client requests need an absolute URI, got /relative");
+    }
+}
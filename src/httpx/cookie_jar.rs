@@ -0,0 +1,303 @@
+use super::date::parse_cookie_date;
+
+use http::{header, HeaderMap, HeaderValue, Uri};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// A cookie as stored by the jar, after the Set-Cookie attributes were applied.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StoredCookie {
+    pub name: String,
+    pub value: String,
+    pub domain: String,
+    pub host_only: bool,
+    pub path: String,
+    pub expires: Option<SystemTime>,
+    pub secure: bool,
+    pub http_only: bool,
+    created: SystemTime,
+}
+
+impl StoredCookie {
+    fn is_expired(&self, now: SystemTime) -> bool {
+        match self.expires {
+            Some(expires) => expires <= now,
+            None => false
+        }
+    }
+}
+
+fn uri_host(uri: &Uri) -> Option<String> {
+    uri.host().map(|host| host.trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase())
+}
+
+fn is_secure(uri: &Uri) -> bool {
+    uri.scheme_str() == Some("https")
+}
+
+// RFC 6265 section 5.1.3
+fn domain_matches(host: &str, domain: &str) -> bool {
+    if host == domain {
+        return true;
+    }
+    let is_ip = host.parse::<std::net::IpAddr>().is_ok();
+    !is_ip && host.ends_with(domain) && host[..host.len() - domain.len()].ends_with('.')
+}
+
+// RFC 6265 section 5.1.4
+fn default_path(uri: &Uri) -> String {
+    let path = uri.path();
+    if !path.starts_with('/') {
+        return "/".to_string();
+    }
+    match path.rfind('/') {
+        Some(0) | None => "/".to_string(),
+        Some(idx) => path[..idx].to_string()
+    }
+}
+
+fn path_matches(request_path: &str, cookie_path: &str) -> bool {
+    if request_path == cookie_path {
+        return true;
+    }
+    request_path.starts_with(cookie_path)
+        && (cookie_path.ends_with('/') || request_path[cookie_path.len()..].starts_with('/'))
+}
+
+/// Apply a single `Set-Cookie` value received from `uri` (RFC 6265 section 5.2
+/// and 5.3). Returns `None` when the cookie must be ignored.
+fn parse_set_cookie(value: &str, uri: &Uri, now: SystemTime) -> Option<StoredCookie> {
+    let host = uri_host(uri)?;
+    let mut parts = value.split(';');
+    let (name, cookie_value) = parts.next()?.split_once('=')?;
+    let name = name.trim();
+    if name.is_empty() {
+        return None;
+    }
+
+    let mut cookie = StoredCookie {
+        name: name.to_string(),
+        value: cookie_value.trim().trim_matches('"').to_string(),
+        domain: host.clone(),
+        host_only: true,
+        path: default_path(uri),
+        expires: None,
+        secure: false,
+        http_only: false,
+        created: now,
+    };
+    let mut max_age: Option<i64> = None;
+
+    for attribute in parts {
+        let (attr_name, attr_value) = match attribute.split_once('=') {
+            Some((attr_name, attr_value)) => (attr_name.trim(), attr_value.trim()),
+            None => (attribute.trim(), "")
+        };
+        match attr_name.to_ascii_lowercase().as_str() {
+            "expires" => {
+                if let Some(expires) = parse_cookie_date(attr_value) {
+                    cookie.expires = Some(expires);
+                }
+            },
+            "max-age" => {
+                if let Ok(seconds) = attr_value.parse::<i64>() {
+                    max_age = Some(seconds);
+                }
+            },
+            "domain" => {
+                let domain = attr_value.trim_start_matches('.').to_ascii_lowercase();
+                if !domain.is_empty() {
+                    if !domain_matches(&host, &domain) {
+                        return None;
+                    }
+                    cookie.domain = domain;
+                    cookie.host_only = false;
+                }
+            },
+            "path" if attr_value.starts_with('/') => cookie.path = attr_value.to_string(),
+            "secure" => cookie.secure = true,
+            "httponly" => cookie.http_only = true,
+            _ => {}
+        }
+    }
+
+    // Max-Age wins over Expires; zero or less means expire now
+    if let Some(seconds) = max_age {
+        cookie.expires = match seconds {
+            s if s <= 0 => Some(SystemTime::UNIX_EPOCH),
+            s => now.checked_add(Duration::from_secs(s as u64))
+        };
+    }
+    // RFC 6265bis: only secure origins may set Secure cookies
+    if cookie.secure && !is_secure(uri) {
+        return None;
+    }
+    Some(cookie)
+}
+
+/// An in-memory cookie store honoring Domain, Path, Expires/Max-Age and Secure.
+/// There is no public suffix list, so a server can set a cookie for any parent
+/// domain of its host.
+#[derive(Debug, Default)]
+pub struct CookieJar {
+    cookies: Mutex<Vec<StoredCookie>>,
+}
+
+impl CookieJar {
+    pub fn new() -> CookieJar {
+        CookieJar::default()
+    }
+
+    /// Store the cookies from every `Set-Cookie` header of a response to `uri`.
+    pub fn store_response_cookies(&self, uri: &Uri, headers: &HeaderMap) {
+        let now = SystemTime::now();
+        let mut cookies = self.cookies.lock().unwrap();
+        for value in headers.get_all(header::SET_COOKIE).iter() {
+            let value = match value.to_str() {
+                Ok(value) => value,
+                Err(_) => continue
+            };
+            let mut cookie = match parse_set_cookie(value, uri, now) {
+                Some(cookie) => cookie,
+                None => continue
+            };
+            let existing = cookies.iter().position(|stored| {
+                stored.name == cookie.name && stored.domain == cookie.domain && stored.path == cookie.path
+            });
+            if let Some(idx) = existing {
+                cookie.created = cookies[idx].created;
+                cookies.remove(idx);
+            }
+            if !cookie.is_expired(now) {
+                cookies.push(cookie);
+            }
+        }
+    }
+
+    /// Cookies to send with a request to `uri`, longest path first.
+    pub fn matching(&self, uri: &Uri) -> Vec<StoredCookie> {
+        let host = match uri_host(uri) {
+            Some(host) => host,
+            None => return Vec::new()
+        };
+        let path = match uri.path() {
+            "" => "/",
+            path => path
+        };
+        let now = SystemTime::now();
+        let mut cookies = self.cookies.lock().unwrap();
+        cookies.retain(|cookie| !cookie.is_expired(now));
+
+        let mut matching: Vec<StoredCookie> = cookies.iter()
+            .filter(|cookie| match cookie.host_only {
+                true => cookie.domain == host,
+                false => domain_matches(&host, &cookie.domain)
+            })
+            .filter(|cookie| path_matches(path, &cookie.path))
+            .filter(|cookie| !cookie.secure || is_secure(uri))
+            .cloned()
+            .collect();
+        matching.sort_by(|a, b| b.path.len().cmp(&a.path.len()).then(a.created.cmp(&b.created)));
+        matching
+    }
+
+    /// The `Cookie` header value for a request to `uri`, if any cookies apply.
+    pub fn cookie_header(&self, uri: &Uri) -> Option<HeaderValue> {
+        let pairs: Vec<String> = self.matching(uri).iter()
+            .map(|cookie| format!("{}={}", cookie.name, cookie.value))
+            .collect();
+        if pairs.is_empty() {
+            return None;
+        }
+        HeaderValue::from_str(&pairs.join("; ")).ok()
+    }
+
+    pub fn get(&self, name: &str) -> Option<String> {
+        let cookies = self.cookies.lock().unwrap();
+        cookies.iter().find(|cookie| cookie.name == name).map(|cookie| cookie.value.clone())
+    }
+
+    pub fn clear(&self) {
+        self.cookies.lock().unwrap().clear();
+    }
+}
+
+
+//#################################################################################################################
+// test cases go below here
+//#################################################################################################################
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(jar: &CookieJar, uri: &str, set_cookies: &[&str]) {
+        let mut headers = HeaderMap::new();
+        for value in set_cookies {
+            headers.append(header::SET_COOKIE, HeaderValue::from_str(value).unwrap());
+        }
+        jar.store_response_cookies(&uri.parse().unwrap(), &headers);
+    }
+
+    fn header_for(jar: &CookieJar, uri: &str) -> Option<String> {
+        jar.cookie_header(&uri.parse().unwrap()).map(|value| value.to_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn test_host_only_and_domain_cookies() {
+        let jar = CookieJar::new();
+        store(&jar, "http://www.example.com/", &["host=1", "wide=2; Domain=.example.com"]);
+
+        assert_eq!(header_for(&jar, "http://www.example.com/"), Some("host=1; wide=2".to_string()));
+        assert_eq!(header_for(&jar, "http://api.example.com/"), Some("wide=2".to_string()));
+        assert_eq!(header_for(&jar, "http://example.org/"), None);
+    }
+
+    #[test]
+    fn test_foreign_domain_is_rejected() {
+        let jar = CookieJar::new();
+        store(&jar, "http://www.example.com/", &["evil=1; Domain=other.com"]);
+        assert_eq!(jar.get("evil"), None);
+    }
+
+    #[test]
+    fn test_path_matching_and_order() {
+        let jar = CookieJar::new();
+        store(&jar, "http://example.com/app/login", &["root=r; Path=/", "app=a; Path=/app", "default=d"]);
+
+        assert_eq!(header_for(&jar, "http://example.com/app/x"), Some("app=a; default=d; root=r".to_string()));
+        assert_eq!(header_for(&jar, "http://example.com/application"), Some("root=r".to_string()));
+    }
+
+    #[test]
+    fn test_expires_and_max_age() {
+        let jar = CookieJar::new();
+        store(&jar, "http://example.com/", &[
+            "old=1; Expires=Wed, 21 Oct 2015 07:28:00 GMT",
+            "fresh=2; Expires=Wed, 21 Oct 2099 07:28:00 GMT",
+            "short=3; Max-Age=0; Expires=Wed, 21 Oct 2099 07:28:00 GMT",
+        ]);
+        assert_eq!(header_for(&jar, "http://example.com/"), Some("fresh=2".to_string()));
+
+        store(&jar, "http://example.com/", &["fresh=gone; Max-Age=-1"]);
+        assert_eq!(header_for(&jar, "http://example.com/"), None);
+    }
+
+    #[test]
+    fn test_secure_cookies() {
+        let jar = CookieJar::new();
+        store(&jar, "http://example.com/", &["plain=1; Secure"]);
+        assert_eq!(jar.get("plain"), None);
+
+        store(&jar, "https://example.com/", &["token=abc; Secure; HttpOnly"]);
+        assert_eq!(header_for(&jar, "http://example.com/"), None);
+        assert_eq!(header_for(&jar, "https://example.com/"), Some("token=abc".to_string()));
+    }
+
+    #[test]
+    fn test_replacing_a_cookie() {
+        let jar = CookieJar::new();
+        store(&jar, "http://example.com/", &["id=1"]);
+        store(&jar, "http://example.com/", &["id=2"]);
+        assert_eq!(header_for(&jar, "http://example.com/"), Some("id=2".to_string()));
+    }
+}
@@ -0,0 +1,369 @@
+use super::{
+    date::format_http_date,
+    errors::FlaskError
+};
+
+use http::{header, HeaderMap, HeaderValue};
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// ***************************************************************************
+// Cookie request headers
+// ***************************************************************************
+
+/// The cookies of a request, from all of its `Cookie` headers, in the order
+/// they were sent. Browsers send every cookie that applies, so one name can
+/// occur more than once (a cookie set for `/` and another for `/app`); the
+/// one with the longest path comes first, which is what `get` returns.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Cookies {
+    pairs: Vec<(String, String)>,
+}
+
+impl Cookies {
+    /// Parse the `Cookie` headers in `headers`. Pairs without `=` are ignored
+    /// and values in double quotes are unquoted.
+    pub fn from_headers(headers: &HeaderMap) -> Cookies {
+        let pairs = headers.get_all(header::COOKIE).iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(name, value)| (name.trim(), value.trim()))
+            .filter(|(name, _)| !name.is_empty())
+            .map(|(name, value)| {
+                let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+                (name.to_string(), value.to_string())
+            })
+            .collect();
+        Cookies { pairs }
+    }
+
+    /// The first cookie called `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.pairs.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+
+    /// Every value sent for `name`, in order.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.pairs.iter().filter(move |(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.pairs.iter().any(|(key, _)| key == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.pairs.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.pairs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+}
+
+// ***************************************************************************
+// Set-Cookie response headers
+// ***************************************************************************
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl fmt::Display for SameSite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        })
+    }
+}
+
+// RFC 9110 token characters
+fn is_token(value: &str) -> bool {
+    !value.is_empty() && value.bytes().all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte))
+}
+
+// RFC 6265 cookie-octet, optionally the whole value in double quotes
+fn is_cookie_value(value: &str) -> bool {
+    let inner = match value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        true => &value[1..value.len() - 1],
+        false => value
+    };
+    inner.bytes().all(|byte| matches!(byte, 0x21 | 0x23..=0x2B | 0x2D..=0x3A | 0x3C..=0x5B | 0x5D..=0x7E))
+}
+
+// attribute values end at ';' and may not contain control characters
+fn is_attribute_value(value: &str) -> bool {
+    !value.is_empty() && value.bytes().all(|byte| byte != b';' && !byte.is_ascii_control())
+}
+
+/// Builds a `Set-Cookie` header value. `build` checks the cookie against the
+/// rules of RFC 6265bis before producing it:
+///
+/// ```ignore
+/// let cookie = SetCookie::new("sid", "31d4d96e407aad42")
+///     .path("/")
+///     .max_age(Duration::from_secs(3600))
+///     .secure(true)
+///     .http_only(true)
+///     .same_site(SameSite::Lax);
+/// cookie.append_to(resp.headers_mut())?;
+/// ```
+///
+/// Invalid cookies are a server bug, so `build` reports them as a 500.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SetCookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    expires: Option<SystemTime>,
+    max_age: Option<u64>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+    partitioned: bool,
+}
+
+impl SetCookie {
+    pub fn new(name: &str, value: &str) -> SetCookie {
+        SetCookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            path: None,
+            domain: None,
+            expires: None,
+            max_age: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+            partitioned: false,
+        }
+    }
+
+    /// A cookie that deletes `name` from the browser: empty, with `Max-Age=0`
+    /// and an `Expires` in the past. Its path and domain must be those the
+    /// cookie was set with, or the browser keeps the original.
+    pub fn removal(name: &str) -> SetCookie {
+        SetCookie::new(name, "").max_age(Duration::ZERO).expires(UNIX_EPOCH)
+    }
+
+    pub fn path(mut self, path: &str) -> SetCookie {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    pub fn domain(mut self, domain: &str) -> SetCookie {
+        self.domain = Some(domain.to_string());
+        self
+    }
+
+    pub fn expires(mut self, expires: SystemTime) -> SetCookie {
+        self.expires = Some(expires);
+        self
+    }
+
+    /// Lifetime in whole seconds; browsers prefer it over `Expires`.
+    pub fn max_age(mut self, max_age: Duration) -> SetCookie {
+        self.max_age = Some(max_age.as_secs());
+        self
+    }
+
+    pub fn secure(mut self, secure: bool) -> SetCookie {
+        self.secure = secure;
+        self
+    }
+
+    pub fn http_only(mut self, http_only: bool) -> SetCookie {
+        self.http_only = http_only;
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> SetCookie {
+        self.same_site = Some(same_site);
+        self
+    }
+
+    /// Ask for partitioned storage (CHIPS); requires `Secure`.
+    pub fn partitioned(mut self, partitioned: bool) -> SetCookie {
+        self.partitioned = partitioned;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if !is_token(&self.name) {
+            return Err( format!("Invalid cookie name {:?}", self.name) );
+        }
+        if !is_cookie_value(&self.value) {
+            return Err( format!("Invalid value for cookie {}", self.name) );
+        }
+        if let Some(path) = &self.path {
+            if !is_attribute_value(path) || !path.starts_with('/') {
+                return Err( format!("Invalid Path {:?} for cookie {}", path, self.name) );
+            }
+        }
+        if let Some(domain) = &self.domain {
+            let valid = is_attribute_value(domain) && domain.bytes().all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'.');
+            if !valid {
+                return Err( format!("Invalid Domain {:?} for cookie {}", domain, self.name) );
+            }
+        }
+        if self.same_site == Some(SameSite::None) && !self.secure {
+            return Err( format!("Cookie {} has SameSite=None without Secure", self.name) );
+        }
+        if self.partitioned && !self.secure {
+            return Err( format!("Cookie {} is Partitioned without Secure", self.name) );
+        }
+        // cookie name prefixes, RFC 6265bis section 4.1.3
+        if self.name.starts_with("__Secure-") && !self.secure {
+            return Err( format!("Cookie {} needs Secure", self.name) );
+        }
+        if self.name.starts_with("__Host-") && (!self.secure || self.domain.is_some() || self.path.as_deref() != Some("/")) {
+            return Err( format!("Cookie {} needs Secure, Path=/ and no Domain", self.name) );
+        }
+        Ok(())
+    }
+
+    /// The header value, or a `FlaskError::InternalServerError` naming the
+    /// first rule the cookie breaks.
+    pub fn build(&self) -> Result<HeaderValue, FlaskError> {
+        if let Err(msg) = self.validate() {
+            return Err( FlaskError::InternalServerError(msg) );
+        }
+        match HeaderValue::from_str(&self.to_string()) {
+            Ok(value) => Ok(value),
+            Err(_) => Err( FlaskError::InternalServerError(format!("Invalid Set-Cookie value for cookie {}", self.name)) )
+        }
+    }
+
+    /// Add the cookie as another `Set-Cookie` header.
+    pub fn append_to(&self, headers: &mut HeaderMap) -> Result<(), FlaskError> {
+        headers.append(header::SET_COOKIE, self.build()?);
+        Ok(())
+    }
+}
+
+/// The `Set-Cookie` value, without validation.
+impl fmt::Display for SetCookie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", path)?;
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={}", domain)?;
+        }
+        if let Some(expires) = self.expires {
+            write!(f, "; Expires={}", format_http_date(expires))?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age)?;
+        }
+        if self.secure {
+            f.write_str("; Secure")?;
+        }
+        if self.http_only {
+            f.write_str("; HttpOnly")?;
+        }
+        if let Some(same_site) = self.same_site {
+            write!(f, "; SameSite={}", same_site)?;
+        }
+        if self.partitioned {
+            f.write_str("; Partitioned")?;
+        }
+        Ok(())
+    }
+}
+
+
+//#################################################################################################################
+// test cases go below here
+//#################################################################################################################
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cookie_headers() {
+        let mut headers = HeaderMap::new();
+        headers.append(header::COOKIE, HeaderValue::from_static("sid=app; theme=\"dark\"; junk; =nameless"));
+        headers.append(header::COOKIE, HeaderValue::from_static("sid=root"));
+        let cookies = Cookies::from_headers(&headers);
+
+        assert_eq!(cookies.len(), 3);
+        assert_eq!(cookies.get("sid"), Some("app"));
+        assert_eq!(cookies.get_all("sid").collect::<Vec<&str>>(), vec!["app", "root"]);
+        assert_eq!(cookies.get("theme"), Some("dark"));
+        assert!(!cookies.contains("junk"));
+        assert!(Cookies::from_headers(&HeaderMap::new()).is_empty());
+    }
+
+    #[test]
+    fn test_set_cookie_attributes() {
+        let cookie = SetCookie::new("sid", "31d4d96e407aad42")
+            .path("/")
+            .domain("example.com")
+            .expires(UNIX_EPOCH + Duration::from_secs(784111777))
+            .max_age(Duration::from_secs(3600))
+            .secure(true)
+            .http_only(true)
+            .same_site(SameSite::None)
+            .partitioned(true);
+        assert_eq!(
+            cookie.build().unwrap(),
+            "sid=31d4d96e407aad42; Path=/; Domain=example.com; Expires=Sun, 06 Nov 1994 08:49:37 GMT; Max-Age=3600; Secure; HttpOnly; SameSite=None; Partitioned"
+        );
+        assert_eq!(SetCookie::new("lang", "\"en-US\"").same_site(SameSite::Lax).build().unwrap(), "lang=\"en-US\"; SameSite=Lax");
+    }
+
+    #[test]
+    fn test_removal_cookie() {
+        let mut headers = HeaderMap::new();
+        SetCookie::new("theme", "dark").append_to(&mut headers).unwrap();
+        SetCookie::removal("sid").path("/app").append_to(&mut headers).unwrap();
+        let values: Vec<&str> = headers.get_all(header::SET_COOKIE).iter().map(|value| value.to_str().unwrap()).collect();
+        assert_eq!(values, vec!["theme=dark", "sid=; Path=/app; Expires=Thu, 01 Jan 1970 00:00:00 GMT; Max-Age=0"]);
+    }
+
+    #[test]
+    fn test_validation() {
+        let invalid = [
+            SetCookie::new("", "x"),
+            SetCookie::new("my cookie", "x"),
+            SetCookie::new("sid", "a b"),
+            SetCookie::new("sid", "a;b"),
+            SetCookie::new("sid", "x").path("/a;b"),
+            SetCookie::new("sid", "x").path("relative"),
+            SetCookie::new("sid", "x").domain("exa mple.com"),
+            SetCookie::new("sid", "x").same_site(SameSite::None),
+            SetCookie::new("sid", "x").partitioned(true),
+            SetCookie::new("__Secure-sid", "x"),
+            SetCookie::new("__Host-sid", "x").secure(true).path("/").domain("example.com"),
+            SetCookie::new("__Host-sid", "x").secure(true).path("/app"),
+        ];
+        for cookie in invalid.iter() {
+            match cookie.build() {
+                Err( FlaskError::InternalServerError(_) ) => {},
+                other => panic!("{} built as {:?}", cookie, other)
+            }
+        }
+        assert!(SetCookie::new("__Host-sid", "x").secure(true).path("/").build().is_ok());
+        assert!(SetCookie::new("__Secure-sid", "x").secure(true).build().is_ok());
+    }
+}
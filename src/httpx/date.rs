@@ -0,0 +1,160 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAY_NAMES: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTH_NAMES: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+// days since 1970-01-01 for a proleptic Gregorian date (Howard Hinnant's algorithm)
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_index = (month as i64 + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Format a time as an IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub(crate) fn format_http_date(time: SystemTime) -> String {
+    let secs = match time.duration_since(UNIX_EPOCH) {
+        Ok(since_epoch) => since_epoch.as_secs() as i64,
+        Err(before_epoch) => -(before_epoch.duration().as_secs() as i64)
+    };
+    let days = secs.div_euclid(86400);
+    let secs_of_day = secs.rem_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+        DAY_NAMES[days.rem_euclid(7) as usize],
+        day,
+        MONTH_NAMES[(month - 1) as usize],
+        year,
+        secs_of_day / 3600,
+        (secs_of_day % 3600) / 60,
+        secs_of_day % 60
+    )
+}
+
+fn leading_digits(token: &str, min: usize, max: usize) -> Option<u32> {
+    let digits: String = token.chars().take_while(|ch| ch.is_ascii_digit()).collect();
+    if digits.len() < min || digits.len() > max {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+fn parse_time(token: &str) -> Option<(u32, u32, u32)> {
+    let mut parts = token.splitn(3, ':');
+    let hour = leading_digits(parts.next()?, 1, 2)?;
+    let minute = leading_digits(parts.next()?, 1, 2)?;
+    let second = leading_digits(parts.next()?, 1, 2)?;
+    Some((hour, minute, second))
+}
+
+/// Parse a cookie date with the lenient algorithm of RFC 6265 section 5.1.1,
+/// which accepts IMF-fixdate, RFC 850 and asctime dates alike.
+pub(crate) fn parse_cookie_date(value: &str) -> Option<SystemTime> {
+    let is_delimiter = |ch: char| {
+        ch == '\t' || (' '..='/').contains(&ch) || (';'..='@').contains(&ch) || ('['..='`').contains(&ch) || ('{'..='~').contains(&ch)
+    };
+
+    let mut time = None;
+    let mut day = None;
+    let mut month = None;
+    let mut year = None;
+    for token in value.split(is_delimiter).filter(|token| !token.is_empty()) {
+        if time.is_none() {
+            if let Some(parsed) = parse_time(token) {
+                time = Some(parsed);
+                continue;
+            }
+        }
+        if day.is_none() {
+            if let Some(parsed) = leading_digits(token, 1, 2) {
+                day = Some(parsed);
+                continue;
+            }
+        }
+        if month.is_none() && token.len() >= 3 {
+            let prefix = token[..3].to_ascii_lowercase();
+            if let Some(idx) = MONTH_NAMES.iter().position(|name| name.to_ascii_lowercase() == prefix) {
+                month = Some(idx as u32 + 1);
+                continue;
+            }
+        }
+        if year.is_none() {
+            if let Some(parsed) = leading_digits(token, 2, 4) {
+                year = Some(parsed);
+                continue;
+            }
+        }
+    }
+
+    let (hour, minute, second) = time?;
+    let (day, month, year) = (day?, month?, year?);
+    let year = match year {
+        70..=99 => year + 1900,
+        0..=69 => year + 2000,
+        _ => year
+    };
+    if !(1..=31).contains(&day) || year < 1601 || hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+
+    let secs = days_from_civil(year as i64, month, day) * 86400 + (hour * 3600 + minute * 60 + second) as i64;
+    if secs >= 0 {
+        Some(UNIX_EPOCH + Duration::from_secs(secs as u64))
+    } else {
+        UNIX_EPOCH.checked_sub(Duration::from_secs(secs.unsigned_abs()))
+    }
+}
+
+
+//#################################################################################################################
+// test cases go below here
+//#################################################################################################################
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_http_date() {
+        let time = UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(format_http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(format_http_date(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+    }
+
+    #[test]
+    fn test_parse_cookie_date_formats() {
+        let expected = Some(UNIX_EPOCH + Duration::from_secs(784111777));
+        assert_eq!(parse_cookie_date("Sun, 06 Nov 1994 08:49:37 GMT"), expected);
+        assert_eq!(parse_cookie_date("Sunday, 06-Nov-94 08:49:37 GMT"), expected);
+        assert_eq!(parse_cookie_date("Sun Nov  6 08:49:37 1994"), expected);
+    }
+
+    #[test]
+    fn test_parse_cookie_date_round_trip() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(parse_cookie_date(&format_http_date(time)), Some(time));
+    }
+
+    #[test]
+    fn test_parse_cookie_date_rejects_garbage() {
+        assert_eq!(parse_cookie_date("tomorrow"), None);
+        assert_eq!(parse_cookie_date("Sun, 32 Nov 1994 08:49:37 GMT"), None);
+        assert_eq!(parse_cookie_date("Sun, 06 Nov 1994 25:49:37 GMT"), None);
+    }
+}
@@ -0,0 +1,203 @@
+use super::{
+    errors::FlaskError,
+    server::Handler
+};
+
+use http::{Request, Response, StatusCode};
+use std::collections::HashMap;
+use std::mem::{self, Discriminant};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+
+type ErrorHook = Box<dyn Fn(Response<Vec<u8>>) -> Response<Vec<u8>> + Send + Sync>;
+
+// marks a response an error handler already produced, so an outer set of
+// handlers (the app around a blueprint) leaves it alone
+struct Handled;
+
+/// Error handlers keyed by `FlaskError` variant and by status code. Shared by
+/// `ErrorHandlers` and blueprints.
+#[derive(Default)]
+pub(crate) struct ErrorRegistry {
+    by_variant: Vec<(Discriminant<FlaskError>, ErrorHook)>,
+    by_status: HashMap<StatusCode, ErrorHook>,
+}
+
+impl ErrorRegistry {
+    pub(crate) fn on_error(&mut self, variant: fn(String) -> FlaskError, hook: ErrorHook) {
+        let key = mem::discriminant(&variant(String::new()));
+        self.by_variant.retain(|(existing, _)| *existing != key);
+        self.by_variant.push((key, hook));
+    }
+
+    pub(crate) fn on_status(&mut self, status: StatusCode, hook: ErrorHook) {
+        self.by_status.insert(status, hook);
+    }
+
+    /// Call `handler`, turning a panic into a 500, and pass the response through
+    /// the matching error handler if there is one.
+    pub(crate) fn handle(&self, handler: &dyn Handler, req: Request<Vec<u8>>) -> Response<Vec<u8>> {
+        let resp = match panic::catch_unwind(AssertUnwindSafe(|| handler.handle(req))) {
+            Ok(resp) => resp,
+            Err(_) => FlaskError::InternalServerError("Request handler panicked".to_string()).to_response()
+        };
+        self.apply(resp)
+    }
+
+    // a handler for the FlaskError behind the response wins over one for its status
+    fn apply(&self, resp: Response<Vec<u8>>) -> Response<Vec<u8>> {
+        if resp.extensions().get::<Handled>().is_some() {
+            return resp;
+        }
+        let by_variant = resp.extensions().get::<FlaskError>().and_then(|flask_err| {
+            let key = mem::discriminant(flask_err);
+            self.by_variant.iter().find(|(existing, _)| *existing == key).map(|(_, hook)| hook)
+        });
+        let hook = match by_variant.or_else(|| self.by_status.get(&resp.status())) {
+            Some(hook) => hook,
+            None => return resp
+        };
+
+        // an error handler that panics gets no second chance
+        let mut resp = match panic::catch_unwind(AssertUnwindSafe(|| hook(resp))) {
+            Ok(resp) => resp,
+            Err(_) => FlaskError::InternalServerError("Error handler panicked".to_string()).to_response()
+        };
+        resp.extensions_mut().insert(Handled);
+        resp
+    }
+}
+
+/// Wraps a handler and replaces its error responses, Flask's `errorhandler`.
+/// Handlers are registered per `FlaskError` variant (matching responses made
+/// with `FlaskError::to_response`) or per status code, and get the original
+/// response to rewrite or replace.
+///
+/// A panic in the wrapped handler becomes a `FlaskError::InternalServerError`
+/// response, so it reaches the handlers for that variant or for status 500.
+pub struct ErrorHandlers {
+    handler: Arc<dyn Handler>,
+    registry: ErrorRegistry,
+}
+
+impl ErrorHandlers {
+    pub fn new<H: Handler>(handler: H) -> ErrorHandlers {
+        ErrorHandlers { handler: Arc::new(handler), registry: ErrorRegistry::default() }
+    }
+
+    /// Handle responses with status `status`, e.g. a custom 404 page.
+    pub fn error_handler<F>(mut self, status: StatusCode, handler: F) -> ErrorHandlers
+    where
+        F: Fn(Response<Vec<u8>>) -> Response<Vec<u8>> + Send + Sync + 'static
+    {
+        self.registry.on_status(status, Box::new(handler));
+        self
+    }
+
+    /// Handle responses made from one `FlaskError` variant, named by its
+    /// constructor: `.on_error(FlaskError::BadRequest, ...)`.
+    pub fn on_error<F>(mut self, variant: fn(String) -> FlaskError, handler: F) -> ErrorHandlers
+    where
+        F: Fn(Response<Vec<u8>>) -> Response<Vec<u8>> + Send + Sync + 'static
+    {
+        self.registry.on_error(variant, Box::new(handler));
+        self
+    }
+}
+
+impl Handler for ErrorHandlers {
+    fn handle(&self, req: Request<Vec<u8>>) -> Response<Vec<u8>> {
+        self.registry.handle(&*self.handler, req)
+    }
+}
+
+
+//#################################################################################################################
+// test cases go below here
+//#################################################################################################################
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::httpx::blueprint::Blueprint;
+    use crate::httpx::router::Router;
+
+    fn get(path: &str) -> Request<Vec<u8>> {
+        Request::get(path).body(Vec::new()).unwrap()
+    }
+
+    fn replace_body(body: &'static str) -> impl Fn(Response<Vec<u8>>) -> Response<Vec<u8>> + Send + Sync + 'static {
+        move |mut resp: Response<Vec<u8>>| {
+            *resp.body_mut() = body.as_bytes().to_vec();
+            resp
+        }
+    }
+
+    fn app() -> Router {
+        Router::new()
+            .get("/bad", |_req: Request<Vec<u8>>| FlaskError::BadRequest("missing field".to_string()).into())
+            .get("/gateway", |_req: Request<Vec<u8>>| FlaskError::BadGateway("upstream down".to_string()).into())
+            .get("/panic", |_req: Request<Vec<u8>>| -> Response<Vec<u8>> { panic!("view bug") })
+    }
+
+    #[test]
+    fn test_status_and_variant_handlers() {
+        let handlers = ErrorHandlers::new(app())
+            .error_handler(StatusCode::NOT_FOUND, replace_body("custom 404"))
+            .error_handler(StatusCode::BAD_REQUEST, replace_body("status 400"))
+            .on_error(FlaskError::BadRequest, replace_body("variant BadRequest"));
+
+        let resp = handlers.handle(get("/nowhere"));
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(resp.body(), b"custom 404");
+        assert_eq!(handlers.handle(get("/bad")).body(), b"variant BadRequest");
+        // no handler registered, so the FlaskError message comes through
+        assert_eq!(handlers.handle(get("/gateway")).body(), b"upstream down");
+    }
+
+    #[test]
+    fn test_handler_sees_the_flask_error() {
+        let handlers = ErrorHandlers::new(app()).on_error(FlaskError::BadGateway, |resp: Response<Vec<u8>>| {
+            let msg = resp.extensions().get::<FlaskError>().unwrap().get_msg().to_string();
+            Response::builder().status(resp.status()).body(format!("{{\"error\": \"{}\"}}", msg).into_bytes()).unwrap()
+        });
+        let resp = handlers.handle(get("/gateway"));
+        assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(resp.body(), b"{\"error\": \"upstream down\"}");
+    }
+
+    #[test]
+    fn test_panics_become_500_through_handlers() {
+        let plain = ErrorHandlers::new(app());
+        let resp = plain.handle(get("/panic"));
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(resp.body(), b"Request handler panicked");
+
+        let custom = ErrorHandlers::new(app()).error_handler(StatusCode::INTERNAL_SERVER_ERROR, replace_body("sorry"));
+        assert_eq!(custom.handle(get("/panic")).body(), b"sorry");
+    }
+
+    #[test]
+    fn test_panicking_error_handler() {
+        let handlers = ErrorHandlers::new(app()).error_handler(StatusCode::NOT_FOUND, |_resp: Response<Vec<u8>>| -> Response<Vec<u8>> {
+            panic!("handler bug")
+        });
+        let resp = handlers.handle(get("/nowhere"));
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(resp.body(), b"Error handler panicked");
+    }
+
+    #[test]
+    fn test_blueprint_handlers_take_precedence() {
+        let api = Blueprint::new("api", "/api")
+            .on_error(FlaskError::InternalServerError, replace_body("{\"error\": \"internal\"}"))
+            .get("/panic", |_req: Request<Vec<u8>>| -> Response<Vec<u8>> { panic!("api bug") })
+            .get("/teapot", |_req: Request<Vec<u8>>| Response::builder().status(StatusCode::IM_A_TEAPOT).body(Vec::new()).unwrap());
+        let handlers = ErrorHandlers::new(app().blueprint(api))
+            .error_handler(StatusCode::INTERNAL_SERVER_ERROR, replace_body("html 500"))
+            .error_handler(StatusCode::IM_A_TEAPOT, replace_body("app teapot"));
+
+        assert_eq!(handlers.handle(get("/api/panic")).body(), b"{\"error\": \"internal\"}");
+        assert_eq!(handlers.handle(get("/panic")).body(), b"html 500");
+        assert_eq!(handlers.handle(get("/api/teapot")).body(), b"app teapot");
+    }
+}
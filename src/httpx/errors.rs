@@ -1,16 +1,23 @@
+use http::{header, Response, StatusCode};
 use std::fmt;
 
+#[derive(Clone)]
 pub enum FlaskError {
     BadRequest(String),             // 400
+    NotFound(String),               // 404
+    MethodNotAllowed(String),       // 405
     ClientClosedRequest(String),    // 499
     InternalServerError(String),    // 500
     BadGateway(String),             // 502
-    NotImplemented(String),
+    NotImplemented(String),         // 501
+    PayloadTooLarge(String),        // 413
+    UnsupportedMediaType(String),   // 415
+    UnprocessableEntity(String),    // 422
 }
 
-impl ToString for FlaskError {
-    fn to_string(&self) -> String {
-        self.get_msg().to_string()
+impl fmt::Display for FlaskError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.get_msg())
     }
 }
 
@@ -26,10 +33,48 @@ impl FlaskError {
     pub fn get_msg(&self) -> &str {
         match self {
             FlaskError::BadRequest(s) => s,
+            FlaskError::NotFound(s) => s,
+            FlaskError::MethodNotAllowed(s) => s,
             FlaskError::ClientClosedRequest(s) => s,
             FlaskError::InternalServerError(s) => s,
             FlaskError::BadGateway(s) => s,
             FlaskError::NotImplemented(s) => s,
+            FlaskError::PayloadTooLarge(s) => s,
+            FlaskError::UnsupportedMediaType(s) => s,
+            FlaskError::UnprocessableEntity(s) => s,
+        }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            FlaskError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            FlaskError::NotFound(_) => StatusCode::NOT_FOUND,
+            FlaskError::MethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+            // nginx's non-standard code, always in range for StatusCode
+            FlaskError::ClientClosedRequest(_) => StatusCode::from_u16(499).unwrap_or(StatusCode::BAD_REQUEST),
+            FlaskError::InternalServerError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            FlaskError::BadGateway(_) => StatusCode::BAD_GATEWAY,
+            FlaskError::NotImplemented(_) => StatusCode::NOT_IMPLEMENTED,
+            FlaskError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            FlaskError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            FlaskError::UnprocessableEntity(_) => StatusCode::UNPROCESSABLE_ENTITY,
         }
     }
+
+    /// A plain-text response reporting this error with its status code. The
+    /// error itself is kept in the response extensions, so registered error
+    /// handlers can tell which variant produced the response.
+    pub fn to_response(&self) -> Response<Vec<u8>> {
+        let mut resp = Response::new(self.get_msg().as_bytes().to_vec());
+        *resp.status_mut() = self.status_code();
+        resp.headers_mut().insert(header::CONTENT_TYPE, header::HeaderValue::from_static("text/plain; charset=utf-8"));
+        resp.extensions_mut().insert(self.clone());
+        resp
+    }
+}
+
+impl From<FlaskError> for Response<Vec<u8>> {
+    fn from(flask_err: FlaskError) -> Response<Vec<u8>> {
+        flask_err.to_response()
+    }
 }
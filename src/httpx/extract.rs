@@ -0,0 +1,518 @@
+use super::{
+    cookies::Cookies,
+    errors::FlaskError,
+    forward::basic_credentials,
+    router::{PathParams, UrlMap},
+    server::Handler,
+    session::Session
+};
+
+use http::{header, HeaderMap, HeaderName, HeaderValue, Method, Request, Response, Uri};
+use std::marker::PhantomData;
+
+#[cfg(feature = "json")]
+use super::json::{json_response, RequestJsonExt};
+#[cfg(feature = "serde")]
+use super::{form::FormDecoder, query::QueryParams};
+
+// ***************************************************************************
+// extractor traits and the view adapter
+// ***************************************************************************
+
+/// A value a view takes from the request. When extraction fails the view is
+/// not called and the request is answered with the error's response, so the
+/// error handlers registered for its variant or status apply.
+pub trait FromRequest: Sized {
+    fn from_request(req: &Request<Vec<u8>>) -> Result<Self, FlaskError>;
+}
+
+/// What a view built with `view` may return.
+pub trait IntoResponse {
+    fn into_response(self) -> Response<Vec<u8>>;
+}
+
+impl IntoResponse for Response<Vec<u8>> {
+    fn into_response(self) -> Response<Vec<u8>> {
+        self
+    }
+}
+
+impl IntoResponse for FlaskError {
+    fn into_response(self) -> Response<Vec<u8>> {
+        self.to_response()
+    }
+}
+
+impl IntoResponse for String {
+    fn into_response(self) -> Response<Vec<u8>> {
+        let mut resp = Response::new(self.into_bytes());
+        resp.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain; charset=utf-8"));
+        resp
+    }
+}
+
+impl IntoResponse for &'static str {
+    fn into_response(self) -> Response<Vec<u8>> {
+        self.to_string().into_response()
+    }
+}
+
+impl<T: IntoResponse, E: IntoResponse> IntoResponse for Result<T, E> {
+    fn into_response(self) -> Response<Vec<u8>> {
+        match self {
+            Ok(value) => value.into_response(),
+            Err(err) => err.into_response()
+        }
+    }
+}
+
+/// A function whose arguments are all extractors. Implemented for functions
+/// and closures of up to six arguments; `Args` is the tuple of argument types.
+pub trait ViewFn<Args>: Send + Sync + 'static {
+    fn call(&self, req: &Request<Vec<u8>>) -> Response<Vec<u8>>;
+}
+
+macro_rules! impl_view_fn {
+    ( $($arg:ident),* ) => {
+        impl<F, R, $($arg,)*> ViewFn<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> R + Send + Sync + 'static,
+            R: IntoResponse,
+            $($arg: FromRequest,)*
+        {
+            #[allow(non_snake_case, unused_variables)]
+            fn call(&self, req: &Request<Vec<u8>>) -> Response<Vec<u8>> {
+                $(
+                    let $arg = match $arg::from_request(req) {
+                        Ok(value) => value,
+                        Err(flask_err) => return flask_err.to_response()
+                    };
+                )*
+                self($($arg),*).into_response()
+            }
+        }
+    };
+}
+
+impl_view_fn!();
+impl_view_fn!(A);
+impl_view_fn!(A, B);
+impl_view_fn!(A, B, C);
+impl_view_fn!(A, B, C, D);
+impl_view_fn!(A, B, C, D, E);
+impl_view_fn!(A, B, C, D, E, G);
+
+/// A `Handler` calling a function of extractors, made with `view`.
+pub struct View<F, Args> {
+    func: F,
+    args: PhantomData<fn() -> Args>,
+}
+
+/// Turn a function of extractors into a handler. Arguments are extracted in
+/// order and the first failure answers the request:
+///
+/// ```ignore
+/// router.get("/users/<int:id>/<name>", view(|Path((id, name)): Path<(u32, String)>, cookies: Cookies| {
+///     format!("{} {} {:?}", id, name, cookies.get("theme"))
+/// }))
+/// ```
+pub fn view<F: ViewFn<Args>, Args>(func: F) -> View<F, Args> {
+    View { func, args: PhantomData }
+}
+
+impl<F: ViewFn<Args>, Args: 'static> Handler for View<F, Args> {
+    fn handle(&self, req: Request<Vec<u8>>) -> Response<Vec<u8>> {
+        self.func.call(&req)
+    }
+}
+
+impl<T: FromRequest> FromRequest for Option<T> {
+    fn from_request(req: &Request<Vec<u8>>) -> Result<Self, FlaskError> {
+        Ok( T::from_request(req).ok() )
+    }
+}
+
+impl FromRequest for Method {
+    fn from_request(req: &Request<Vec<u8>>) -> Result<Self, FlaskError> {
+        Ok( req.method().clone() )
+    }
+}
+
+impl FromRequest for Uri {
+    fn from_request(req: &Request<Vec<u8>>) -> Result<Self, FlaskError> {
+        Ok( req.uri().clone() )
+    }
+}
+
+impl FromRequest for HeaderMap {
+    fn from_request(req: &Request<Vec<u8>>) -> Result<Self, FlaskError> {
+        Ok( req.headers().clone() )
+    }
+}
+
+fn router_extension<T: Clone + Send + Sync + 'static>(req: &Request<Vec<u8>>) -> Result<T, FlaskError> {
+    match req.extensions().get::<T>() {
+        Some(value) => Ok( value.clone() ),
+        None => Err( FlaskError::InternalServerError("View was not dispatched by a Router".to_string()) )
+    }
+}
+
+impl FromRequest for PathParams {
+    fn from_request(req: &Request<Vec<u8>>) -> Result<Self, FlaskError> {
+        router_extension(req)
+    }
+}
+
+impl FromRequest for UrlMap {
+    fn from_request(req: &Request<Vec<u8>>) -> Result<Self, FlaskError> {
+        router_extension(req)
+    }
+}
+
+// ***************************************************************************
+// path parameters
+// ***************************************************************************
+
+/// A single path parameter value.
+pub trait PathValue: Sized {
+    fn parse_param(value: &str) -> Option<Self>;
+}
+
+macro_rules! impl_path_value {
+    ( $($ty:ty),* ) => {
+        $(
+            impl PathValue for $ty {
+                fn parse_param(value: &str) -> Option<Self> {
+                    value.parse().ok()
+                }
+            }
+        )*
+    };
+}
+
+impl_path_value!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64, bool, char, String);
+
+/// What `Path<T>` can hold: one value for a route with one parameter, or a
+/// tuple with one value per parameter, in the order they appear in the pattern.
+pub trait FromPathParams: Sized {
+    fn from_path_params(params: &PathParams) -> Result<Self, FlaskError>;
+}
+
+fn path_value<T: PathValue>(params: &PathParams, index: usize, arity: usize) -> Result<T, FlaskError> {
+    if params.len() != arity {
+        let msg = format!("Path extractor expects {} parameters, the route has {}", arity, params.len());
+        return Err( FlaskError::InternalServerError(msg) );
+    }
+    // the arity check above keeps the index in range
+    let (name, value) = params.iter().nth(index).unwrap_or(("", ""));
+    match T::parse_param(value) {
+        Some(parsed) => Ok(parsed),
+        None => Err( FlaskError::BadRequest(format!("Invalid path parameter {}: {:?}", name, value)) )
+    }
+}
+
+impl<T: PathValue> FromPathParams for T {
+    fn from_path_params(params: &PathParams) -> Result<Self, FlaskError> {
+        path_value(params, 0, 1)
+    }
+}
+
+macro_rules! impl_from_path_params {
+    ( $arity:expr; $($ty:ident $index:expr),* ) => {
+        impl<$($ty: PathValue),*> FromPathParams for ($($ty,)*) {
+            fn from_path_params(params: &PathParams) -> Result<Self, FlaskError> {
+                Ok( ($(path_value::<$ty>(params, $index, $arity)?,)*) )
+            }
+        }
+    };
+}
+
+impl_from_path_params!(1; A 0);
+impl_from_path_params!(2; A 0, B 1);
+impl_from_path_params!(3; A 0, B 1, C 2);
+impl_from_path_params!(4; A 0, B 1, C 2, D 3);
+
+/// The path parameters captured by the router. An unparseable value is a 400;
+/// a `T` that does not fit the route's parameters is a 500.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Path<T>(pub T);
+
+impl<T: FromPathParams> FromRequest for Path<T> {
+    fn from_request(req: &Request<Vec<u8>>) -> Result<Self, FlaskError> {
+        let params: PathParams = router_extension(req)?;
+        Ok( Path(T::from_path_params(&params)?) )
+    }
+}
+
+// ***************************************************************************
+// query string, form and JSON bodies
+// ***************************************************************************
+
+/// The query string deserialized into `T`; a 400 when it is malformed or
+/// does not fit.
+#[cfg(feature = "serde")]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Query<T>(pub T);
+
+#[cfg(feature = "serde")]
+impl<T: serde::de::DeserializeOwned> FromRequest for Query<T> {
+    fn from_request(req: &Request<Vec<u8>>) -> Result<Self, FlaskError> {
+        Ok( Query(QueryParams::from_request(req)?.deserialize()?) )
+    }
+}
+
+/// An `application/x-www-form-urlencoded` body deserialized into `T` with the
+/// default `FormDecoder` limits; a 415 for any other content type and a 422
+/// when the fields do not fit.
+#[cfg(feature = "serde")]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Form<T>(pub T);
+
+#[cfg(feature = "serde")]
+impl<T: serde::de::DeserializeOwned> FromRequest for Form<T> {
+    fn from_request(req: &Request<Vec<u8>>) -> Result<Self, FlaskError> {
+        Ok( Form(FormDecoder::new().deserialize(req)?) )
+    }
+}
+
+/// A JSON body deserialized into `T` with `RequestJsonExt::json`. Any other
+/// content type is a 415, malformed JSON a 400 and well-formed JSON that does
+/// not fit `T` a 422.
+#[cfg(feature = "json")]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Json<T>(pub T);
+
+#[cfg(feature = "json")]
+impl<T: serde::de::DeserializeOwned> FromRequest for Json<T> {
+    fn from_request(req: &Request<Vec<u8>>) -> Result<Self, FlaskError> {
+        Ok( Json(req.json()?) )
+    }
+}
+
+/// A view returning `Json(value)` answers 200 with `value` serialized.
+#[cfg(feature = "json")]
+impl<T: serde::Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response<Vec<u8>> {
+        match json_response(http::StatusCode::OK, &self.0) {
+            Ok(resp) => resp,
+            Err(flask_err) => flask_err.to_response()
+        }
+    }
+}
+
+// ***************************************************************************
+// headers and cookies
+// ***************************************************************************
+
+/// A header with a typed value, for the `Header<H>` extractor.
+pub trait TypedHeader: Sized {
+    fn name() -> HeaderName;
+
+    /// `None` when the value is malformed.
+    fn decode(value: &HeaderValue) -> Option<Self>;
+}
+
+/// Credentials from the `Authorization` header. Schemes other than `Basic`
+/// and `Bearer` are rejected as malformed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Authorization {
+    Basic { username: String, password: String },
+    Bearer(String),
+}
+
+impl TypedHeader for Authorization {
+    fn name() -> HeaderName {
+        header::AUTHORIZATION
+    }
+
+    fn decode(value: &HeaderValue) -> Option<Self> {
+        if let Some((username, password)) = basic_credentials(value) {
+            return Some( Authorization::Basic { username, password } );
+        }
+        let (scheme, token) = value.to_str().ok()?.trim().split_once(' ')?;
+        let token = token.trim();
+        match scheme.eq_ignore_ascii_case("bearer") && !token.is_empty() {
+            true => Some( Authorization::Bearer(token.to_string()) ),
+            false => None
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UserAgent(pub String);
+
+impl TypedHeader for UserAgent {
+    fn name() -> HeaderName {
+        header::USER_AGENT
+    }
+
+    fn decode(value: &HeaderValue) -> Option<Self> {
+        Some( UserAgent(value.to_str().ok()?.to_string()) )
+    }
+}
+
+/// A required typed header; missing or malformed is a 400. Wrap it in an
+/// `Option` for a header that may be absent.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Header<H>(pub H);
+
+impl<H: TypedHeader> FromRequest for Header<H> {
+    fn from_request(req: &Request<Vec<u8>>) -> Result<Self, FlaskError> {
+        let name = H::name();
+        let value = match req.headers().get(&name) {
+            Some(value) => value,
+            None => return Err( FlaskError::BadRequest(format!("Missing header {}", name)) )
+        };
+        match H::decode(value) {
+            Some(decoded) => Ok( Header(decoded) ),
+            None => Err( FlaskError::BadRequest(format!("Invalid header {}", name)) )
+        }
+    }
+}
+
+/// The request's cookies, see `Cookies::from_headers`. Never rejects; a
+/// request without a `Cookie` header has no cookies.
+impl FromRequest for Cookies {
+    fn from_request(req: &Request<Vec<u8>>) -> Result<Self, FlaskError> {
+        Ok( Cookies::from_headers(req.headers()) )
+    }
+}
+
+/// The session `SessionMiddleware` or `StoredSessionMiddleware` loaded; a 500
+/// when the view is not wrapped in either.
+impl FromRequest for Session {
+    fn from_request(req: &Request<Vec<u8>>) -> Result<Self, FlaskError> {
+        match req.extensions().get::<Session>() {
+            Some(session) => Ok( session.clone() ),
+            None => Err( FlaskError::InternalServerError("No session: the view is not wrapped in a session middleware".to_string()) )
+        }
+    }
+}
+
+//#################################################################################################################
+// test cases go below here
+//#################################################################################################################
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::httpx::router::Router;
+    use http::StatusCode;
+
+    fn get(path: &str) -> Request<Vec<u8>> {
+        Request::get(path).body(Vec::new()).unwrap()
+    }
+
+    #[cfg(feature = "serde")]
+    fn post(path: &str, content_type: &str, body: &str) -> Request<Vec<u8>> {
+        Request::post(path).header(header::CONTENT_TYPE, content_type).body(body.as_bytes().to_vec()).unwrap()
+    }
+
+    fn body(resp: &Response<Vec<u8>>) -> &str {
+        std::str::from_utf8(resp.body()).unwrap()
+    }
+
+    #[test]
+    fn test_path_tuple_and_single_value() {
+        let router = Router::new()
+            .get("/users/<int:id>/<name>", view(|Path((id, name)): Path<(u32, String)>| format!("{}:{}", id, name)))
+            .get("/items/<id>", view(|Path(id): Path<u8>| format!("item {}", id)))
+            .get("/pair/<a>/<b>", view(|Path(a): Path<String>| a));
+
+        assert_eq!(body(&router.handle(get("/users/7/ann"))), "7:ann");
+        assert_eq!(body(&router.handle(get("/items/42"))), "item 42");
+
+        let resp = router.handle(get("/items/300"));
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body(&resp), "Invalid path parameter id: \"300\"");
+
+        // a view that does not fit its route is a programming error
+        assert_eq!(router.handle(get("/pair/x/y")).status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn test_header_extractor() {
+        let handler = view(|Header(auth): Header<Authorization>, agent: Option<Header<UserAgent>>| {
+            let agent = agent.map(|Header(UserAgent(agent))| agent).unwrap_or_default();
+            match auth {
+                Authorization::Basic { username, password } => format!("basic {} {} {}", username, password, agent),
+                Authorization::Bearer(token) => format!("bearer {} {}", token, agent)
+            }
+        });
+
+        let req = Request::get("/").header(header::AUTHORIZATION, "Bearer abc").header(header::USER_AGENT, "curl").body(Vec::new()).unwrap();
+        assert_eq!(body(&handler.handle(req)), "bearer abc curl");
+        // "ann:secret"
+        let req = Request::get("/").header(header::AUTHORIZATION, "Basic YW5uOnNlY3JldA==").body(Vec::new()).unwrap();
+        assert_eq!(body(&handler.handle(req)), "basic ann secret ");
+
+        let resp = handler.handle(get("/"));
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body(&resp), "Missing header authorization");
+        let req = Request::get("/").header(header::AUTHORIZATION, "Digest x").body(Vec::new()).unwrap();
+        assert_eq!(body(&handler.handle(req)), "Invalid header authorization");
+    }
+
+    #[test]
+    fn test_cookies_and_plain_extractors() {
+        let handler = view(|method: Method, uri: Uri, cookies: Cookies| {
+            let names: Vec<&str> = cookies.iter().map(|(name, _)| name).collect();
+            format!("{} {} {:?} {}", method, uri.path(), cookies.get("theme"), names.join(","))
+        });
+        let req = Request::get("/page")
+            .header(header::COOKIE, "theme=\"dark\"; sid=abc")
+            .header(header::COOKIE, "lang=en")
+            .body(Vec::new()).unwrap();
+        assert_eq!(body(&handler.handle(req)), "GET /page Some(\"dark\") theme,sid,lang");
+        assert_eq!(body(&handler.handle(get("/"))), "GET / None ");
+    }
+
+    #[test]
+    fn test_rejections_reach_error_handlers() {
+        use crate::httpx::error_handlers::ErrorHandlers;
+
+        let router = Router::new().get("/<int:id>", view(|Path(id): Path<i8>| -> Result<String, FlaskError> {
+            match id {
+                0 => Err( FlaskError::UnprocessableEntity("zero".to_string()) ),
+                _ => Ok( id.to_string() )
+            }
+        }));
+        let handlers = ErrorHandlers::new(router)
+            .on_error(FlaskError::BadRequest, |_resp: Response<Vec<u8>>| Response::new(b"bad id".to_vec()));
+
+        assert_eq!(body(&handlers.handle(get("/5"))), "5");
+        assert_eq!(body(&handlers.handle(get("/999"))), "bad id");
+        assert_eq!(handlers.handle(get("/0")).status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[cfg(feature = "serde")]
+    #[derive(serde::Deserialize)]
+    struct Paging {
+        page: u32,
+        per_page: Option<u32>,
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_query_and_form() {
+        let handler = view(|Query(paging): Query<Paging>| format!("{} {:?}", paging.page, paging.per_page));
+        assert_eq!(body(&handler.handle(get("/?page=2&per_page=10"))), "2 Some(10)");
+        assert_eq!(handler.handle(get("/?page=two")).status(), StatusCode::BAD_REQUEST);
+        assert_eq!(handler.handle(get("/?page=1&per_page=%zz")).status(), StatusCode::BAD_REQUEST);
+
+        let handler = view(|Form(paging): Form<Paging>| paging.page.to_string());
+        assert_eq!(body(&handler.handle(post("/", "application/x-www-form-urlencoded", "page=3"))), "3");
+        assert_eq!(handler.handle(post("/", "text/plain", "page=3")).status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(handler.handle(post("/", "application/x-www-form-urlencoded", "per_page=3")).status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_json() {
+        let handler = view(|Json(paging): Json<Paging>| Json(vec![paging.page]));
+        assert_eq!(body(&handler.handle(post("/", "application/json; charset=utf-8", "{\"page\": 4}"))), "[4]");
+        assert_eq!(body(&handler.handle(post("/", "application/merge-patch+json", "{\"page\": 5}"))), "[5]");
+        assert_eq!(handler.handle(post("/", "text/plain", "{\"page\": 4}")).status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(handler.handle(post("/", "application/json", "{\"page\": ")).status(), StatusCode::BAD_REQUEST);
+        assert_eq!(handler.handle(post("/", "application/json", "{\"page\": \"x\"}")).status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+}
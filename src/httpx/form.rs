@@ -0,0 +1,211 @@
+use super::{
+    errors::FlaskError,
+    media_type,
+    media_type_param,
+    query::{decode_component, QueryBuilder, QueryParams}
+};
+
+use http::{header, request, HeaderValue, Request};
+
+pub const FORM_URLENCODED: &str = "application/x-www-form-urlencoded";
+
+const DEFAULT_MAX_FIELDS: usize = 1000;
+const DEFAULT_MAX_FIELD_SIZE: usize = 64 * 1024;
+
+/// Decodes `application/x-www-form-urlencoded` request bodies into the same
+/// ordered multimap as query strings.
+///
+/// The request must declare that content type; a `charset` parameter other
+/// than UTF-8 (or its ASCII subset) is a 415. A body with more than
+/// `max_fields` fields, or a field longer than `max_field_size` encoded bytes,
+/// is a 413, and malformed percent-encoding a 400.
+#[derive(Clone, Debug)]
+pub struct FormDecoder {
+    max_fields: usize,
+    max_field_size: usize,
+}
+
+impl Default for FormDecoder {
+    fn default() -> FormDecoder {
+        FormDecoder { max_fields: DEFAULT_MAX_FIELDS, max_field_size: DEFAULT_MAX_FIELD_SIZE }
+    }
+}
+
+impl FormDecoder {
+    pub fn new() -> FormDecoder {
+        FormDecoder::default()
+    }
+
+    pub fn max_fields(mut self, max_fields: usize) -> FormDecoder {
+        self.max_fields = max_fields;
+        self
+    }
+
+    /// The limit for one `name=value` field, counted before decoding.
+    pub fn max_field_size(mut self, max_field_size: usize) -> FormDecoder {
+        self.max_field_size = max_field_size;
+        self
+    }
+
+    /// Check the request's content type and decode its body.
+    pub fn decode<B: AsRef<[u8]>>(&self, req: &Request<B>) -> Result<QueryParams, FlaskError> {
+        if media_type(req.headers()).as_deref() != Some(FORM_URLENCODED) {
+            let msg = format!("Expected Content-Type {}", FORM_URLENCODED);
+            return Err( FlaskError::UnsupportedMediaType(msg) );
+        }
+        if let Some(charset) = media_type_param(req.headers(), "charset") {
+            if !["utf-8", "utf8", "us-ascii"].contains(&charset.to_ascii_lowercase().as_str()) {
+                return Err( FlaskError::UnsupportedMediaType(format!("Unsupported form charset {}", charset)) );
+            }
+        }
+        self.decode_body(req.body().as_ref())
+    }
+
+    /// Decode a body without looking at any headers.
+    pub fn decode_body(&self, body: &[u8]) -> Result<QueryParams, FlaskError> {
+        let body = match std::str::from_utf8(body) {
+            Ok(body) => body,
+            Err(_) => return Err( FlaskError::BadRequest("Form body is not valid UTF-8".to_string()) )
+        };
+
+        let mut pairs = Vec::new();
+        for field in body.split('&').filter(|field| !field.is_empty()) {
+            if pairs.len() == self.max_fields {
+                return Err( FlaskError::PayloadTooLarge(format!("Form has more than {} fields", self.max_fields)) );
+            }
+            let (name, value) = field.split_once('=').unwrap_or((field, ""));
+            if field.len() > self.max_field_size {
+                let msg = format!("Form field {:?} exceeds {} bytes", decode_component(name).unwrap_or_default(), self.max_field_size);
+                return Err( FlaskError::PayloadTooLarge(msg) );
+            }
+            match (decode_component(name), decode_component(value)) {
+                (Some(name), Some(value)) => pairs.push((name, value)),
+                _ => return Err( FlaskError::BadRequest(format!("Invalid percent-encoding in form field {:?}", name)) )
+            }
+        }
+        Ok( QueryParams::from_pairs(pairs) )
+    }
+
+    /// Decode the body into `T`; fields that do not fit `T` are a 422.
+    #[cfg(feature = "serde")]
+    pub fn deserialize<T: serde::de::DeserializeOwned, B: AsRef<[u8]>>(&self, req: &Request<B>) -> Result<T, FlaskError> {
+        let fields = self.decode(req)?;
+        match serde_urlencoded::from_str(&fields.to_string()) {
+            Ok(value) => Ok(value),
+            Err(err) => Err( FlaskError::UnprocessableEntity(format!("Invalid form data: {}", err)) )
+        }
+    }
+}
+
+/// Builds `application/x-www-form-urlencoded` bodies, mainly for requests in tests:
+///
+/// ```ignore
+/// let req = FormEncoder::new().field("name", "Ann Lee").field("tag", "a&b").request(Request::post("/signup"))?;
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct FormEncoder {
+    fields: QueryBuilder,
+}
+
+impl FormEncoder {
+    pub fn new() -> FormEncoder {
+        FormEncoder::default()
+    }
+
+    /// Add a field after the existing ones, also when the name is taken.
+    pub fn field(mut self, name: &str, value: &str) -> FormEncoder {
+        self.fields = self.fields.append(name, value);
+        self
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        self.fields.build().into_bytes()
+    }
+
+    /// Finish `builder` with the encoded body and matching `Content-Type` and
+    /// `Content-Length` headers.
+    pub fn request(&self, builder: request::Builder) -> Result<Request<Vec<u8>>, FlaskError> {
+        let body = self.encode();
+        let result = builder
+            .header(header::CONTENT_TYPE, HeaderValue::from_static(FORM_URLENCODED))
+            .header(header::CONTENT_LENGTH, body.len())
+            .body(body);
+        match result {
+            Ok(req) => Ok(req),
+            Err(err) => Err( FlaskError::InternalServerError(format!("Invalid form request: {}", err)) )
+        }
+    }
+}
+
+
+//#################################################################################################################
+// test cases go below here
+//#################################################################################################################
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn form_request(content_type: &str, body: &str) -> Request<Vec<u8>> {
+        Request::post("/").header(header::CONTENT_TYPE, content_type).body(body.as_bytes().to_vec()).unwrap()
+    }
+
+    fn status(result: Result<QueryParams, FlaskError>) -> u16 {
+        result.err().map(|err| err.status_code().as_u16()).unwrap_or(200)
+    }
+
+    #[test]
+    fn test_encoder_round_trip() {
+        let req = FormEncoder::new()
+            .field("name", "Ann Lee")
+            .field("tag", "a&b")
+            .field("tag", "100%")
+            .request(Request::post("/signup"))
+            .unwrap();
+        assert_eq!(req.headers()[header::CONTENT_TYPE], FORM_URLENCODED);
+        assert_eq!(req.headers()[header::CONTENT_LENGTH], req.body().len().to_string().as_str());
+        assert_eq!(req.body(), b"name=Ann+Lee&tag=a%26b&tag=100%25");
+
+        let fields = FormDecoder::new().decode(&req).unwrap();
+        assert_eq!(fields.get("name"), Some("Ann Lee"));
+        assert_eq!(fields.get_all("tag").collect::<Vec<&str>>(), vec!["a&b", "100%"]);
+    }
+
+    #[test]
+    fn test_content_type_and_charset() {
+        let decoder = FormDecoder::new();
+        assert_eq!(status(decoder.decode(&form_request("application/x-www-form-urlencoded; charset=UTF-8", "a=1"))), 200);
+        assert_eq!(status(decoder.decode(&form_request("Application/X-WWW-Form-Urlencoded", "a=1"))), 200);
+        assert_eq!(status(decoder.decode(&form_request("application/x-www-form-urlencoded; charset=\"iso-8859-1\"", "a=1"))), 415);
+        assert_eq!(status(decoder.decode(&form_request("application/json", "a=1"))), 415);
+        let req = Request::post("/").body(b"a=1".to_vec()).unwrap();
+        assert_eq!(status(decoder.decode(&req)), 415);
+    }
+
+    #[test]
+    fn test_limits_and_malformed_bodies() {
+        let decoder = FormDecoder::new().max_fields(2).max_field_size(8);
+        assert_eq!(decoder.decode_body(b"a=1&b=2").unwrap().len(), 2);
+        assert_eq!(status(decoder.decode_body(b"a=1&b=2&c=3")), 413);
+        assert_eq!(status(decoder.decode_body(b"a=123456789")), 413);
+        assert_eq!(status(decoder.decode_body(b"a=%zz")), 400);
+        assert_eq!(status(decoder.decode_body(b"a=\xff")), 400);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_deserialize() {
+        #[derive(serde::Deserialize, Debug, PartialEq)]
+        struct Signup {
+            name: String,
+            age: u8,
+        }
+
+        let req = FormEncoder::new().field("name", "Ann").field("age", "31").request(Request::post("/")).unwrap();
+        let signup: Signup = FormDecoder::new().deserialize(&req).unwrap();
+        assert_eq!(signup, Signup { name: "Ann".to_string(), age: 31 });
+
+        let req = FormEncoder::new().field("name", "Ann").field("age", "old").request(Request::post("/")).unwrap();
+        let err = FormDecoder::new().deserialize::<Signup, _>(&req).unwrap_err();
+        assert_eq!(err.status_code().as_u16(), 422);
+    }
+}
@@ -0,0 +1,384 @@
+use super::{
+    errors::FlaskError,
+    header_has_token,
+    read_http_response_for,
+    write_http_request
+};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use http::{header, HeaderMap, HeaderValue, Method, Request, Response, StatusCode};
+use http::uri::{Authority, Uri};
+use std::io::BufReader;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+// RFC 9110 section 7.6.1, plus the non-standard Proxy-Connection
+const HOP_BY_HOP_HEADERS: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Copy `headers` without the hop-by-hop headers, including any listed in `Connection`.
+pub fn remove_hop_by_hop_headers(headers: &HeaderMap) -> HeaderMap {
+    let mut cleaned = headers.clone();
+    let listed: Vec<String> = headers.get_all(header::CONNECTION).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+    for name in HOP_BY_HOP_HEADERS.iter().map(|name| name.to_string()).chain(listed) {
+        cleaned.remove(name.as_str());
+    }
+    cleaned
+}
+
+/// The origin server a proxied request is for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Origin {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Origin {
+    fn from_authority(authority: &Authority) -> Origin {
+        let host = authority.host().trim_start_matches('[').trim_end_matches(']');
+        Origin { host: host.to_string(), port: authority.port_u16().unwrap_or(80) }
+    }
+}
+
+/// Rewrite an absolute-form request (`GET http://example.com/path`) to origin-form
+/// (`GET /path`) with a matching `Host`. The authority in the target wins over any
+/// `Host` the client sent. Origin-form requests are resolved through their `Host`.
+pub fn to_origin_form(mut req: Request<Vec<u8>>) -> Result<(Request<Vec<u8>>, Origin), FlaskError> {
+    let uri = req.uri().clone();
+    let authority = match (uri.scheme_str(), uri.authority()) {
+        (Some("http"), Some(authority)) => authority.clone(),
+        (Some(scheme), _) => {
+            let msg = format!("Unsupported scheme in proxy request: {}", scheme);
+            return Err( FlaskError::BadRequest(msg) );
+        },
+        (None, _) => {
+            let host = match req.headers().get(header::HOST).and_then(|val| val.to_str().ok()) {
+                Some(host) => host,
+                None => return Err( FlaskError::BadRequest("Proxy request has no absolute target or Host header".to_string()) )
+            };
+            match host.parse::<Authority>() {
+                Ok(authority) => authority,
+                Err(_) => {
+                    let msg = format!("Invalid Host header: {}", host);
+                    return Err( FlaskError::BadRequest(msg) );
+                }
+            }
+        }
+    };
+
+    let path = match uri.path_and_query() {
+        Some(pq) if !pq.as_str().is_empty() => pq.as_str().to_string(),
+        _ => "/".to_string()
+    };
+    *req.uri_mut() = match path.parse::<Uri>() {
+        Ok(origin_uri) => origin_uri,
+        Err(_) => {
+            let msg = format!("Invalid request target: {}", path);
+            return Err( FlaskError::BadRequest(msg) );
+        }
+    };
+
+    // an authority parsed from a Uri or header is always a valid header value
+    if let Ok(host) = HeaderValue::from_str(authority.as_str()) {
+        req.headers_mut().insert(header::HOST, host);
+    }
+    Ok( (req, Origin::from_authority(&authority)) )
+}
+
+/// Decode `Basic` credentials from a `Proxy-Authorization` or `Authorization` value.
+pub fn basic_credentials(value: &HeaderValue) -> Option<(String, String)> {
+    let value = value.to_str().ok()?;
+    let (scheme, encoded) = value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(BASE64.decode(encoded.trim()).ok()?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some( (user.to_string(), password.to_string()) )
+}
+
+type CredentialCheck = Box<dyn Fn(&str, &str) -> bool + Send + Sync>;
+
+/// A plain-HTTP forward proxy: takes requests in absolute-form, optionally
+/// demands `Basic` proxy credentials and relays them to the named origin.
+pub struct ForwardProxy {
+    realm: String,
+    credentials: Option<CredentialCheck>,
+    connect_timeout: Duration,
+    io_timeout: Duration,
+}
+
+impl Default for ForwardProxy {
+    fn default() -> ForwardProxy {
+        ForwardProxy::new()
+    }
+}
+
+impl ForwardProxy {
+    pub fn new() -> ForwardProxy {
+        ForwardProxy {
+            realm: String::from("proxy"),
+            credentials: None,
+            connect_timeout: Duration::from_secs(10),
+            io_timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// Require `Proxy-Authorization: Basic ...` credentials accepted by `check`.
+    pub fn basic_auth<F>(mut self, realm: &str, check: F) -> ForwardProxy
+        where F: Fn(&str, &str) -> bool + Send + Sync + 'static {
+        self.realm = realm.to_string();
+        self.credentials = Some(Box::new(check));
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> ForwardProxy {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// How long a read from or write to an origin may block before the
+    /// request fails.
+    pub fn io_timeout(mut self, timeout: Duration) -> ForwardProxy {
+        self.io_timeout = timeout;
+        self
+    }
+
+    /// The `407 Proxy Authentication Required` to send back when `req` lacks
+    /// acceptable credentials, `None` when it may be forwarded.
+    pub fn challenge<B>(&self, req: &Request<B>) -> Option<Response<Vec<u8>>> {
+        let check = self.credentials.as_ref()?;
+        let authorized = req.headers().get(header::PROXY_AUTHORIZATION)
+            .and_then(basic_credentials)
+            .map(|(user, password)| check(&user, &password))
+            .unwrap_or(false);
+        if authorized {
+            return None;
+        }
+        let challenge = format!("Basic realm=\"{}\"", self.realm);
+        let resp = Response::builder()
+            .status(StatusCode::PROXY_AUTHENTICATION_REQUIRED)
+            .header(header::PROXY_AUTHENTICATE, challenge)
+            .body(Vec::new())
+            .unwrap_or_default();
+        Some(resp)
+    }
+
+    /// Relay `req` to its origin and return the origin's response, both with
+    /// their hop-by-hop headers stripped. Does not check credentials.
+    pub fn forward(&self, req: Request<Vec<u8>>) -> Result<Response<Vec<u8>>, FlaskError> {
+        if req.method() == Method::CONNECT {
+            return Err( FlaskError::BadRequest("CONNECT requests must be tunnelled".to_string()) );
+        }
+        let (mut req, origin) = to_origin_form(req)?;
+        let close_requested = header_has_token(req.headers(), header::CONNECTION, "close");
+        *req.headers_mut() = remove_hop_by_hop_headers(req.headers());
+        // we read one response per connection, so ask the origin to close it
+        req.headers_mut().insert(header::CONNECTION, HeaderValue::from_static("close"));
+
+        let mut stream = self.connect(&origin)?;
+        // a failure to set timeouts only means we may block longer than asked
+        let _ = stream.set_read_timeout(Some(self.io_timeout));
+        let _ = stream.set_write_timeout(Some(self.io_timeout));
+        write_http_request(&mut stream, &req)?;
+        let mut resp = read_http_response_for(&mut BufReader::new(stream), req.method())?;
+        *resp.headers_mut() = remove_hop_by_hop_headers(resp.headers());
+        if close_requested {
+            resp.headers_mut().insert(header::CONNECTION, HeaderValue::from_static("close"));
+        }
+        Ok(resp)
+    }
+
+    /// Authorize and forward `req`, turning failures into error responses.
+    pub fn handle(&self, req: Request<Vec<u8>>) -> Response<Vec<u8>> {
+        if let Some(challenge) = self.challenge(&req) {
+            return challenge;
+        }
+        match self.forward(req) {
+            Ok(resp) => resp,
+            Err(flask_err) => flask_err.to_response()
+        }
+    }
+
+    fn connect(&self, origin: &Origin) -> Result<TcpStream, FlaskError> {
+        let addrs = match (origin.host.as_str(), origin.port).to_socket_addrs() {
+            Ok(addrs) => addrs,
+            Err(io_err) => {
+                let msg = format!("Error resolving origin {}: {}", origin.host, io_err);
+                return Err( FlaskError::BadGateway(msg) );
+            }
+        };
+        let mut last_err = format!("No addresses found for origin {}", origin.host);
+        for addr in addrs {
+            match TcpStream::connect_timeout(&addr, self.connect_timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(io_err) => last_err = format!("Error connecting to origin {}: {}", origin.host, io_err)
+            }
+        }
+        Err( FlaskError::BadGateway(last_err) )
+    }
+}
+
+
+//#################################################################################################################
+// test cases go below here
+//#################################################################################################################
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::httpx::read_http_request_from;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::mpsc;
+    use std::thread;
+
+    // an origin that records the request it saw and answers with a fixed body
+    fn spawn_origin() -> (SocketAddr, mpsc::Receiver<Request<Vec<u8>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let req = read_http_request_from(&mut reader).unwrap();
+            sender.send(req).unwrap();
+            let payload = "HTTP/1.1 200 OK\r\ncontent-length: 6\r\nkeep-alive: timeout=5\r\n\r\norigin";
+            reader.get_mut().write_all(payload.as_bytes()).unwrap();
+        });
+        (addr, receiver)
+    }
+
+    #[test]
+    fn test_to_origin_form_absolute_target_wins_over_host() {
+        let req = Request::get("http://example.com:8080/a/b?c=d")
+            .header("Host", "elsewhere.com")
+            .body(Vec::new())
+            .unwrap();
+        let (req, origin) = to_origin_form(req).unwrap();
+        assert_eq!(req.uri(), "/a/b?c=d");
+        assert_eq!(req.headers()["host"], "example.com:8080");
+        assert_eq!(origin, Origin { host: "example.com".to_string(), port: 8080 });
+    }
+
+    #[test]
+    fn test_to_origin_form_uses_host_for_origin_form() {
+        let req = Request::get("/index.html").header("Host", "example.com").body(Vec::new()).unwrap();
+        let (req, origin) = to_origin_form(req).unwrap();
+        assert_eq!(req.uri(), "/index.html");
+        assert_eq!(origin.port, 80);
+    }
+
+    #[test]
+    fn test_to_origin_form_rejects_https() {
+        let req = Request::get("https://example.com/").body(Vec::new()).unwrap();
+        let flask_err = to_origin_form(req).err().unwrap();
+        assert_eq!(flask_err.get_msg(), "Unsupported scheme in proxy request: https");
+    }
+
+    #[test]
+    fn test_remove_hop_by_hop_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("connection", HeaderValue::from_static("keep-alive, x-private"));
+        headers.insert("proxy-connection", HeaderValue::from_static("keep-alive"));
+        headers.insert("proxy-authorization", HeaderValue::from_static("Basic Zm9vOmJhcg=="));
+        headers.insert("x-private", HeaderValue::from_static("secret"));
+        headers.insert("accept", HeaderValue::from_static("*/*"));
+
+        let cleaned = remove_hop_by_hop_headers(&headers);
+        assert_eq!(cleaned.len(), 1);
+        assert_eq!(cleaned["accept"], "*/*");
+    }
+
+    #[test]
+    fn test_forward_relays_to_origin() {
+        let (addr, seen) = spawn_origin();
+        let proxy = ForwardProxy::new();
+        let req = Request::get(format!("http://{}/path?q=1", addr))
+            .header("Proxy-Connection", "keep-alive")
+            .header("X-Custom", "kept")
+            .body(Vec::new())
+            .unwrap();
+
+        let resp = proxy.handle(req);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.body(), b"origin");
+        assert!(!resp.headers().contains_key("keep-alive"));
+
+        let origin_req = seen.recv().unwrap();
+        assert_eq!(origin_req.uri(), "/path?q=1");
+        assert_eq!(origin_req.headers()["host"], addr.to_string().as_str());
+        assert_eq!(origin_req.headers()["x-custom"], "kept");
+        assert!(!origin_req.headers().contains_key("proxy-connection"));
+    }
+
+    #[test]
+    fn test_proxy_authentication() {
+        let (addr, seen) = spawn_origin();
+        let proxy = ForwardProxy::new().basic_auth("internal", |user, password| user == "alice" && password == "s3cret");
+
+        let anonymous = Request::get(format!("http://{}/", addr)).body(Vec::new()).unwrap();
+        let resp = proxy.handle(anonymous);
+        assert_eq!(resp.status(), StatusCode::PROXY_AUTHENTICATION_REQUIRED);
+        assert_eq!(resp.headers()["proxy-authenticate"], "Basic realm=\"internal\"");
+
+        let credentials = BASE64.encode("alice:s3cret");
+        let authorized = Request::get(format!("http://{}/", addr))
+            .header("Proxy-Authorization", format!("Basic {}", credentials))
+            .body(Vec::new())
+            .unwrap();
+        let resp = proxy.handle(authorized);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!seen.recv().unwrap().headers().contains_key("proxy-authorization"));
+    }
+
+    #[test]
+    fn test_forward_unreachable_origin_is_bad_gateway() {
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let req = Request::get(format!("http://{}/", closed)).body(Vec::new()).unwrap();
+        let resp = ForwardProxy::new().handle(req);
+        assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+        assert!(resp.extensions().get::<FlaskError>().is_some());
+    }
+
+    #[test]
+    fn test_forward_head_and_stalled_origin() {
+        // answers HEAD with the length a GET would have, then stalls on the next request
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            read_http_request_from(&mut reader).unwrap();
+            reader.get_mut().write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 6\r\n\r\n").unwrap();
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            read_http_request_from(&mut reader).unwrap();
+            let _ = reader.fill_buf();
+        });
+        let proxy = ForwardProxy::new().io_timeout(Duration::from_millis(200));
+
+        let head = Request::head(format!("http://{}/", addr)).body(Vec::new()).unwrap();
+        let resp = proxy.forward(head).unwrap();
+        assert_eq!(resp.headers()["content-length"], "6");
+        assert!(resp.body().is_empty());
+
+        let started = std::time::Instant::now();
+        assert!(proxy.forward(Request::get(format!("http://{}/", addr)).body(Vec::new()).unwrap()).is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
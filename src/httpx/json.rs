@@ -0,0 +1,127 @@
+use super::{
+    errors::FlaskError,
+    media_type
+};
+
+use http::{header, HeaderValue, Request, Response, StatusCode};
+use serde::{de::DeserializeOwned, Serialize};
+
+fn is_json(media: &str) -> bool {
+    media == "application/json" || media.ends_with("+json")
+}
+
+// serde_json appends " at line L column C" to its messages; report the location up front instead
+fn describe(err: &serde_json::Error) -> String {
+    let msg = err.to_string();
+    let suffix = format!(" at line {} column {}", err.line(), err.column());
+    let msg = msg.strip_suffix(&suffix).unwrap_or(&msg);
+    format!("line {}, column {}: {}", err.line(), err.column(), msg)
+}
+
+/// Parse `body` as JSON into `T`. Malformed or truncated JSON is a 400 and
+/// JSON of the wrong shape for `T` a 422, both saying where the problem is.
+pub fn from_json_slice<T: DeserializeOwned>(body: &[u8]) -> Result<T, FlaskError> {
+    match serde_json::from_slice(body) {
+        Ok(value) => Ok(value),
+        Err(err) if err.is_data() => Err( FlaskError::UnprocessableEntity(format!("Invalid JSON body at {}", describe(&err))) ),
+        Err(err) => Err( FlaskError::BadRequest(format!("Malformed JSON body at {}", describe(&err))) )
+    }
+}
+
+/// A JSON response with `Content-Type: application/json` and a matching
+/// `Content-Length`. A value that cannot be serialized is a 500.
+pub fn json_response<T: Serialize + ?Sized>(status: StatusCode, value: &T) -> Result<Response<Vec<u8>>, FlaskError> {
+    let body = match serde_json::to_vec(value) {
+        Ok(body) => body,
+        Err(err) => return Err( FlaskError::InternalServerError(format!("Cannot serialize JSON response: {}", err)) )
+    };
+    let mut resp = Response::new(Vec::new());
+    *resp.status_mut() = status;
+    resp.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    resp.headers_mut().insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+    *resp.body_mut() = body;
+    Ok(resp)
+}
+
+/// JSON bodies of requests.
+pub trait RequestJsonExt {
+    /// The body parsed as JSON into `T`. The `Content-Type` must be
+    /// `application/json` or a `+json` type, otherwise the result is a 415.
+    fn json<T: DeserializeOwned>(&self) -> Result<T, FlaskError>;
+}
+
+impl<B: AsRef<[u8]>> RequestJsonExt for Request<B> {
+    fn json<T: DeserializeOwned>(&self) -> Result<T, FlaskError> {
+        match media_type(self.headers()) {
+            Some(media) if is_json(&media) => from_json_slice(self.body().as_ref()),
+            _ => Err( FlaskError::UnsupportedMediaType("Expected Content-Type application/json".to_string()) )
+        }
+    }
+}
+
+/// JSON bodies of responses, e.g. from an upstream or in tests. Unlike
+/// requests the content type is not checked.
+pub trait ResponseJsonExt {
+    fn json<T: DeserializeOwned>(&self) -> Result<T, FlaskError>;
+}
+
+impl<B: AsRef<[u8]>> ResponseJsonExt for Response<B> {
+    fn json<T: DeserializeOwned>(&self) -> Result<T, FlaskError> {
+        from_json_slice(self.body().as_ref())
+    }
+}
+
+
+//#################################################################################################################
+// test cases go below here
+//#################################################################################################################
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Deserialize, Serialize, Debug, PartialEq)]
+    struct Item {
+        name: String,
+        count: u32,
+    }
+
+    fn json_request(content_type: &str, body: &str) -> Request<Vec<u8>> {
+        Request::post("/items").header(header::CONTENT_TYPE, content_type).body(body.as_bytes().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_request_json() {
+        let req = json_request("application/json; charset=utf-8", "{\"name\": \"pen\", \"count\": 2}");
+        assert_eq!(req.json::<Item>().unwrap(), Item { name: "pen".to_string(), count: 2 });
+
+        let req = json_request("text/plain", "{\"name\": \"pen\", \"count\": 2}");
+        assert_eq!(req.json::<Item>().unwrap_err().status_code(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[test]
+    fn test_errors_report_the_location() {
+        let err = json_request("application/json", "{\n  \"name\": \"pen\",\n  \"count\": }").json::<Item>().unwrap_err();
+        assert_eq!(err.status_code(), StatusCode::BAD_REQUEST);
+        assert_eq!(err.get_msg(), "Malformed JSON body at line 3, column 12: expected value");
+
+        let err = json_request("application/json", "{\"name\": \"pen\"").json::<Item>().unwrap_err();
+        assert_eq!(err.status_code(), StatusCode::BAD_REQUEST);
+        assert_eq!(err.get_msg(), "Malformed JSON body at line 1, column 14: EOF while parsing an object");
+
+        let err = json_request("application/json", "{\"name\": \"pen\", \"count\": -1}").json::<Item>().unwrap_err();
+        assert_eq!(err.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(err.get_msg(), "Invalid JSON body at line 1, column 27: invalid value: integer `-1`, expected u32");
+    }
+
+    #[test]
+    fn test_json_response() {
+        let item = Item { name: "pen".to_string(), count: 2 };
+        let resp = json_response(StatusCode::CREATED, &item).unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/json");
+        assert_eq!(resp.headers()[header::CONTENT_LENGTH], "24");
+        assert_eq!(resp.body(), b"{\"name\":\"pen\",\"count\":2}");
+        assert_eq!(resp.json::<Item>().unwrap(), item);
+    }
+}
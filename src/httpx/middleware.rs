@@ -0,0 +1,248 @@
+use super::server::Handler;
+
+use http::{Request, Response};
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+
+// shared with blueprints, which run the same kinds of hooks
+pub(crate) type BeforeHook = Box<dyn Fn(&mut Request<Vec<u8>>) -> Option<Response<Vec<u8>>> + Send + Sync>;
+pub(crate) type AfterHook = Box<dyn Fn(Response<Vec<u8>>) -> Response<Vec<u8>> + Send + Sync>;
+type TeardownHook = Box<dyn Fn(Option<&str>) + Send + Sync>;
+
+/// Code wrapped around a handler. A middleware gets the request and the rest
+/// of the chain; it can change the request, answer without calling `next`,
+/// or post-process what `next` returns. Keeping state across that call (a
+/// start time, a request id) is just a local variable.
+pub trait Middleware: Send + Sync + 'static {
+    fn handle(&self, req: Request<Vec<u8>>, next: Next<'_>) -> Response<Vec<u8>>;
+}
+
+impl<F> Middleware for F
+where
+    F: for<'a> Fn(Request<Vec<u8>>, Next<'a>) -> Response<Vec<u8>> + Send + Sync + 'static
+{
+    fn handle(&self, req: Request<Vec<u8>>, next: Next<'_>) -> Response<Vec<u8>> {
+        self(req, next)
+    }
+}
+
+/// The part of a chain after the current middleware.
+pub struct Next<'a> {
+    middleware: &'a [Arc<dyn Middleware>],
+    chain: &'a Chain,
+}
+
+impl<'a> Next<'a> {
+    pub fn run(self, req: Request<Vec<u8>>) -> Response<Vec<u8>> {
+        match self.middleware.split_first() {
+            Some((first, rest)) => first.handle(req, Next { middleware: rest, chain: self.chain }),
+            None => self.chain.dispatch(req)
+        }
+    }
+}
+
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg
+    } else {
+        "handler panicked"
+    }
+}
+
+/// A handler wrapped in middleware and Flask-style request hooks. A `Chain` is
+/// itself a `Handler`, so it can wrap a `Router`, a single view, or another chain.
+///
+/// For every request the middleware run first, outermost first, in the order
+/// they were added. Inside them the `before_request` hooks run in order until
+/// one answers; then the handler (unless a hook answered); then the
+/// `after_request` hooks, last registered first, on whichever response came
+/// out. The `teardown_request` hooks run at the very end, also when something
+/// panicked, after which the panic continues to the caller.
+pub struct Chain {
+    handler: Arc<dyn Handler>,
+    middleware: Vec<Arc<dyn Middleware>>,
+    before: Vec<BeforeHook>,
+    after: Vec<AfterHook>,
+    teardown: Vec<TeardownHook>,
+}
+
+impl Chain {
+    pub fn new<H: Handler>(handler: H) -> Chain {
+        Chain {
+            handler: Arc::new(handler),
+            middleware: Vec::new(),
+            before: Vec::new(),
+            after: Vec::new(),
+            teardown: Vec::new(),
+        }
+    }
+
+    /// Add a middleware inside the ones added before it.
+    pub fn wrap<M: Middleware>(mut self, middleware: M) -> Chain {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// Run `hook` before the handler. It may modify the request, or return a
+    /// response to skip the handler.
+    pub fn before_request<F>(mut self, hook: F) -> Chain
+    where
+        F: Fn(&mut Request<Vec<u8>>) -> Option<Response<Vec<u8>>> + Send + Sync + 'static
+    {
+        self.before.push(Box::new(hook));
+        self
+    }
+
+    /// Run `hook` on every response, including those from before hooks.
+    pub fn after_request<F>(mut self, hook: F) -> Chain
+    where
+        F: Fn(Response<Vec<u8>>) -> Response<Vec<u8>> + Send + Sync + 'static
+    {
+        self.after.push(Box::new(hook));
+        self
+    }
+
+    /// Run `hook` once the request is done. It gets the panic message when the
+    /// request failed with a panic, and `None` otherwise.
+    pub fn teardown_request<F>(mut self, hook: F) -> Chain
+    where
+        F: Fn(Option<&str>) + Send + Sync + 'static
+    {
+        self.teardown.push(Box::new(hook));
+        self
+    }
+
+    fn dispatch(&self, mut req: Request<Vec<u8>>) -> Response<Vec<u8>> {
+        let short_circuit = self.before.iter().find_map(|hook| hook(&mut req));
+        let mut resp = match short_circuit {
+            Some(resp) => resp,
+            None => self.handler.handle(req)
+        };
+        for hook in self.after.iter().rev() {
+            resp = hook(resp);
+        }
+        resp
+    }
+}
+
+impl Handler for Chain {
+    fn handle(&self, req: Request<Vec<u8>>) -> Response<Vec<u8>> {
+        let next = Next { middleware: &self.middleware, chain: self };
+        let result = panic::catch_unwind(AssertUnwindSafe(|| next.run(req)));
+        let panic_msg = result.as_ref().err().map(|payload| panic_message(payload.as_ref()));
+        for hook in self.teardown.iter().rev() {
+            hook(panic_msg);
+        }
+        match result {
+            Ok(resp) => resp,
+            Err(payload) => panic::resume_unwind(payload)
+        }
+    }
+}
+
+
+//#################################################################################################################
+// test cases go below here
+//#################################################################################################################
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::{header, HeaderValue, StatusCode};
+    use std::sync::Mutex;
+
+    // appends `step` to the x-trace header
+    fn trace(mut resp: Response<Vec<u8>>, step: &str) -> Response<Vec<u8>> {
+        let value = match resp.headers().get("x-trace") {
+            Some(existing) => format!("{},{}", existing.to_str().unwrap(), step),
+            None => step.to_string()
+        };
+        resp.headers_mut().insert("x-trace", HeaderValue::from_str(&value).unwrap());
+        resp
+    }
+
+    fn ok(_req: Request<Vec<u8>>) -> Response<Vec<u8>> {
+        Response::new(b"ok".to_vec())
+    }
+
+    fn get(path: &str) -> Request<Vec<u8>> {
+        Request::get(path).body(Vec::new()).unwrap()
+    }
+
+    #[test]
+    fn test_order_of_middleware_and_hooks() {
+        let chain = Chain::new(ok)
+            .wrap(|req: Request<Vec<u8>>, next: Next<'_>| trace(next.run(req), "outer"))
+            .wrap(|req: Request<Vec<u8>>, next: Next<'_>| trace(next.run(req), "inner"))
+            .after_request(|resp: Response<Vec<u8>>| trace(resp, "after1"))
+            .after_request(|resp: Response<Vec<u8>>| trace(resp, "after2"));
+
+        let resp = chain.handle(get("/"));
+        assert_eq!(resp.headers()["x-trace"], "after2,after1,inner,outer");
+    }
+
+    #[test]
+    fn test_before_hook_mutates_and_short_circuits() {
+        let chain = Chain::new(|req: Request<Vec<u8>>| Response::new(req.headers()["x-user"].as_bytes().to_vec()))
+            .before_request(|req: &mut Request<Vec<u8>>| {
+                match req.headers().get(header::AUTHORIZATION).cloned() {
+                    Some(token) => {
+                        req.headers_mut().insert("x-user", token);
+                        None
+                    },
+                    None => Some(Response::builder().status(StatusCode::UNAUTHORIZED).body(Vec::new()).unwrap())
+                }
+            })
+            .after_request(|resp: Response<Vec<u8>>| trace(resp, "after"));
+
+        let resp = chain.handle(get("/"));
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(resp.headers()["x-trace"], "after");
+
+        let req = Request::get("/").header(header::AUTHORIZATION, "alice").body(Vec::new()).unwrap();
+        assert_eq!(chain.handle(req).body(), b"alice");
+    }
+
+    #[test]
+    fn test_middleware_short_circuit_skips_handler() {
+        let calls = Arc::new(Mutex::new(0));
+        let counted = calls.clone();
+        let chain = Chain::new(move |req: Request<Vec<u8>>| {
+                *counted.lock().unwrap() += 1;
+                ok(req)
+            })
+            .wrap(|req: Request<Vec<u8>>, next: Next<'_>| {
+                match req.uri().path() {
+                    "/blocked" => Response::builder().status(StatusCode::FORBIDDEN).body(Vec::new()).unwrap(),
+                    _ => next.run(req)
+                }
+            });
+
+        assert_eq!(chain.handle(get("/blocked")).status(), StatusCode::FORBIDDEN);
+        assert_eq!(*calls.lock().unwrap(), 0);
+        assert_eq!(chain.handle(get("/open")).status(), StatusCode::OK);
+        assert_eq!(*calls.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_teardown_runs_on_success_and_panic() {
+        let seen: Arc<Mutex<Vec<Option<String>>>> = Arc::new(Mutex::new(Vec::new()));
+        let recorder = seen.clone();
+        let chain = Chain::new(|req: Request<Vec<u8>>| {
+                if req.uri().path() == "/boom" {
+                    panic!("view exploded");
+                }
+                ok(req)
+            })
+            .teardown_request(move |panic_msg: Option<&str>| {
+                recorder.lock().unwrap().push(panic_msg.map(|msg| msg.to_string()));
+            });
+
+        assert_eq!(chain.handle(get("/")).status(), StatusCode::OK);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| chain.handle(get("/boom"))));
+        assert!(result.is_err());
+        assert_eq!(*seen.lock().unwrap(), vec![None, Some("view exploded".to_string())]);
+    }
+}
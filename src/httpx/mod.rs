@@ -1,19 +1,41 @@
+pub mod blueprint;
+mod body;
+pub mod client;
+pub mod cookie_jar;
+pub mod cookies;
+mod date;
+pub mod error_handlers;
 mod errors;
+pub mod extract;
+pub mod form;
+pub mod forward;
+#[cfg(feature = "json")]
+pub mod json;
+pub mod middleware;
+pub mod multipart;
+pub(crate) mod percent;
+pub mod pipeline;
+mod pool;
+pub mod query;
 mod request;
 mod response;
+pub mod router;
+pub mod server;
+pub mod session;
+pub mod session_store;
+pub mod tunnel;
+pub mod upgrade;
+pub mod upstream;
 
 pub use errors::FlaskError;
-pub use request::read_http_request;
-pub use response::read_http_response;
+pub use body::BodyReader;
+pub use request::{read_http_request, read_http_request_from, read_http_request_streaming, write_http_request};
+pub use response::{read_http_response, read_http_response_for, read_http_response_from, write_http_response};
 
 use crate::combinators::*;
 
-use http::Version;
-use std::net::TcpStream;
-use std::io::{
-    BufReader,
-    prelude::*
-};
+use http::{HeaderMap, Version};
+use std::io::prelude::*;
 
 struct Header<'b> {
     key: &'b str,
@@ -21,31 +43,30 @@ struct Header<'b> {
 }
 
 const CONTENT_LENGTH_HEADER: &str = "content-length";
+const TRANSFER_ENCODING_HEADER: &str = "transfer-encoding";
 
 fn get_http_version(ver_str: &str) -> Result<Version, FlaskError> {
     match ver_str {
         "1.1" => Ok( Version::HTTP_11 ),
         ver @ "0.9" | ver @ "1.0" | ver @ "2.0" | ver @ "3.0" => {
-            let fmt_msg = format!("Unsupported HTTP version {}", ver);
-            let msg = String::from(fmt_msg);
+            let msg = format!("Unsupported HTTP version {}", ver);
             let err = FlaskError::NotImplemented(msg);
             Err(err)
         },
-        ver @ _ => {
-            let fmt_msg = format!("Unknown HTTP version {}", ver);
-            let msg = String::from(fmt_msg);
+        ver => {
+            let msg = format!("Unknown HTTP version {}", ver);
             let err = FlaskError::BadRequest(msg);
             Err(err)
         }
     }
 }
 
-fn read_buffered_line(reader: &mut BufReader<TcpStream>) -> Result<String, FlaskError> {
+fn read_buffered_line<R: BufRead>(reader: &mut R) -> Result<String, FlaskError> {
     let mut line: String = String::from("");
     match reader.read_line(&mut line) {
         Ok(num_bytes) => {
             if num_bytes != line.len() {
-                let msg = format!("Error in request line byte count");
+                let msg = "Error in request line byte count".to_string();
                 let flask_err = FlaskError::InternalServerError(msg);
                 Err(flask_err)
             } else {
@@ -60,13 +81,58 @@ fn read_buffered_line(reader: &mut BufReader<TcpStream>) -> Result<String, Flask
     }
 }
 
-fn read_header(line: &str) -> Result<Header, FlaskError> {
-    let (line, key) = http_header_name(line).unwrap();
-    let (line, _) = colon(line).unwrap();
-    let (line, _) = space(line).unwrap();
-    let (line, value) = header_value(line).unwrap();
+fn read_header(line: &str) -> Result<Header<'_>, FlaskError> {
+    let malformed = |reason: &str| FlaskError::BadRequest(format!("Malformed Header: {}", reason));
+    let (line, key) = match http_header_name(line) {
+      Ok((rest, key)) if !key.is_empty() => (rest, key),
+      _ => return Err( malformed("invalid field name") )
+    };
+    let line = match colon(line) {
+      Ok((rest, _)) => rest,
+      Err(_) => return Err( malformed("missing colon after field name") )
+    };
+    // optional whitespace around the value (RFC 9112 section 5)
+    let line = match ows(line) {
+      Ok((rest, _)) => rest,
+      Err(_) => line
+    };
+    let (line, value) = match header_value(line) {
+      Ok(parsed) => parsed,
+      Err(_) => return Err( malformed("invalid field value") )
+    };
     match crlf(line) {
-      Ok(_) => Ok( Header {key: key, value: value} ),
-      Err(_) => Err( FlaskError::BadRequest("Malformed Header: no terminating CRLF".to_string()) )
+      Ok(_) => Ok( Header {key, value: value.trim_end_matches([' ', '\t'])} ),
+      Err(_) => Err( malformed("no terminating CRLF") )
     }
   }
+
+// true if any comma separated element of the named header matches `token`, ignoring case
+pub(crate) fn header_has_token(headers: &HeaderMap, name: http::header::HeaderName, token: &str) -> bool {
+    headers.get_all(name).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|elem| elem.trim().eq_ignore_ascii_case(token))
+}
+
+/// The media type of the `Content-Type` header, lowercased and without parameters.
+pub(crate) fn media_type(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(http::header::CONTENT_TYPE)?.to_str().ok()?;
+    let essence = value.split(';').next().unwrap_or("").trim();
+    match essence.is_empty() {
+        true => None,
+        false => Some(essence.to_ascii_lowercase())
+    }
+}
+
+/// A parameter of the `Content-Type` header such as `charset` or `boundary`,
+/// with surrounding quotes removed.
+pub(crate) fn media_type_param(headers: &HeaderMap, name: &str) -> Option<String> {
+    let value = headers.get(http::header::CONTENT_TYPE)?.to_str().ok()?;
+    value.split(';').skip(1)
+        .filter_map(|param| param.split_once('='))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| {
+            let value = value.trim();
+            value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value).to_string()
+        })
+}
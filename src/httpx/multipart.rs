@@ -0,0 +1,506 @@
+use super::{
+    errors::FlaskError,
+    media_type,
+    media_type_param,
+    read_header
+};
+
+use http::{header, HeaderMap, HeaderName, HeaderValue, Request};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+const READ_SIZE: usize = 8 * 1024;
+const MAX_HEADER_SIZE: usize = 8 * 1024;
+const DEFAULT_MAX_PART_SIZE: u64 = 16 * 1024 * 1024;
+const DEFAULT_MAX_TOTAL_SIZE: u64 = 64 * 1024 * 1024;
+const DEFAULT_MAX_PARTS: usize = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Preamble,
+    Headers,
+    Body,
+    Done,
+}
+
+/// A `multipart/form-data` body (RFC 7578) read part by part from any reader,
+/// typically the `BodyReader` of `read_http_request_streaming`. Only a few
+/// kilobytes are buffered at a time, so part bodies can go straight to disk:
+///
+/// ```ignore
+/// let mut form = Multipart::from_request(req)?.max_part_size(100 << 20);
+/// while let Some(mut part) = form.next_part()? {
+///     match part.filename() {
+///         Some(_) => { part.save_to(upload_dir.join(part.name()))?; },
+///         None => println!("{} = {}", part.name(), part.text()?)
+///     }
+/// }
+/// ```
+///
+/// A part larger than `max_part_size`, a body larger than `max_total_size` or
+/// more than `max_parts` parts is a 413; a malformed body is a 400. Parts not
+/// read to the end are skipped by the next call to `next_part`. Once the
+/// closing boundary is found the epilogue is read and discarded, so the
+/// reader ends up exhausted: after a `BodyReader`, the connection is ready for
+/// the next request.
+pub struct Multipart<R: Read> {
+    reader: R,
+    delimiter: Vec<u8>,
+    buf: Vec<u8>,
+    pos: usize,
+    eof: bool,
+    state: State,
+    total_read: u64,
+    part_read: u64,
+    parts: usize,
+    max_part_size: u64,
+    max_total_size: u64,
+    max_parts: usize,
+}
+
+impl<R: Read> Multipart<R> {
+    /// Parse the body in `reader`, delimited by `boundary`.
+    pub fn new(reader: R, boundary: &str) -> Result<Multipart<R>, FlaskError> {
+        // RFC 2046 section 5.1.1
+        if boundary.is_empty() || boundary.len() > 70 || boundary.bytes().any(|byte| byte.is_ascii_control()) {
+            return Err( FlaskError::BadRequest(format!("Invalid multipart boundary {:?}", boundary)) );
+        }
+        Ok( Multipart {
+            reader,
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            // the first delimiter has no CRLF in front of it; pretend it has
+            buf: b"\r\n".to_vec(),
+            pos: 0,
+            eof: false,
+            state: State::Preamble,
+            total_read: 0,
+            part_read: 0,
+            parts: 0,
+            max_part_size: DEFAULT_MAX_PART_SIZE,
+            max_total_size: DEFAULT_MAX_TOTAL_SIZE,
+            max_parts: DEFAULT_MAX_PARTS,
+        } )
+    }
+
+    /// Parse the body of a request; its `Content-Type` must be
+    /// `multipart/form-data` with a boundary, or the result is a 415 or 400.
+    pub fn from_request(req: Request<R>) -> Result<Multipart<R>, FlaskError> {
+        let (head, body) = req.into_parts();
+        if media_type(&head.headers).as_deref() != Some("multipart/form-data") {
+            return Err( FlaskError::UnsupportedMediaType("Expected Content-Type multipart/form-data".to_string()) );
+        }
+        match media_type_param(&head.headers, "boundary") {
+            Some(boundary) => Multipart::new(body, &boundary),
+            None => Err( FlaskError::BadRequest("Multipart Content-Type without a boundary".to_string()) )
+        }
+    }
+
+    pub fn max_part_size(mut self, max_part_size: u64) -> Multipart<R> {
+        self.max_part_size = max_part_size;
+        self
+    }
+
+    pub fn max_total_size(mut self, max_total_size: u64) -> Multipart<R> {
+        self.max_total_size = max_total_size;
+        self
+    }
+
+    pub fn max_parts(mut self, max_parts: usize) -> Multipart<R> {
+        self.max_parts = max_parts;
+        self
+    }
+
+    /// The next part, or `None` after the closing boundary.
+    pub fn next_part(&mut self) -> Result<Option<Part<'_, R>>, FlaskError> {
+        // skip the preamble or whatever is left of the previous part
+        let mut scratch = [0u8; READ_SIZE];
+        while matches!(self.state, State::Preamble | State::Body) {
+            self.read_body(&mut scratch)?;
+        }
+        if self.state == State::Done {
+            return Ok(None);
+        }
+
+        self.parts += 1;
+        if self.parts > self.max_parts {
+            return Err( FlaskError::PayloadTooLarge(format!("Multipart body has more than {} parts", self.max_parts)) );
+        }
+        let headers = self.read_headers()?;
+        let disposition = headers.get(header::CONTENT_DISPOSITION).and_then(|value| value.to_str().ok()).unwrap_or("");
+        let (kind, params) = parse_disposition(disposition);
+        let name = match params.iter().find(|(key, _)| key == "name") {
+            Some((_, name)) if kind == "form-data" => name.clone(),
+            _ => return Err( FlaskError::BadRequest("Multipart part without a form-data name".to_string()) )
+        };
+        let filename = params.into_iter().find(|(key, _)| key == "filename").map(|(_, filename)| filename);
+
+        self.state = State::Body;
+        self.part_read = 0;
+        Ok( Some(Part { multipart: self, headers, name, filename }) )
+    }
+
+    // read until at least `want` bytes are buffered or the reader is exhausted
+    fn fill(&mut self, want: usize) -> Result<(), FlaskError> {
+        if self.pos > 0 && self.buf.len() - self.pos < want {
+            self.buf.drain(..self.pos);
+            self.pos = 0;
+        }
+        let mut chunk = [0u8; READ_SIZE];
+        while !self.eof && self.buf.len() - self.pos < want {
+            let num_bytes = match self.reader.read(&mut chunk) {
+                Ok(num_bytes) => num_bytes,
+                Err(io_err) if io_err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(io_err) => return Err( FlaskError::BadRequest(format!("Error reading multipart body: {}", io_err)) )
+            };
+            self.eof = num_bytes == 0;
+            self.total_read += num_bytes as u64;
+            if self.total_read > self.max_total_size {
+                return Err( FlaskError::PayloadTooLarge(format!("Multipart body exceeds {} bytes", self.max_total_size)) );
+            }
+            self.buf.extend_from_slice(&chunk[..num_bytes]);
+        }
+        Ok(())
+    }
+
+    // body bytes up to the next delimiter; 0 once it is reached
+    fn read_body(&mut self, out: &mut [u8]) -> Result<usize, FlaskError> {
+        if self.state != State::Preamble && self.state != State::Body {
+            return Ok(0);
+        }
+        self.fill(self.delimiter.len() + 1)?;
+        let available = &self.buf[self.pos..];
+        let (num_bytes, at_delimiter) = match find(available, &self.delimiter) {
+            Some(idx) => (idx.min(out.len()), idx == 0),
+            // the tail may be the start of a delimiter split across reads
+            None if !self.eof => (available.len().saturating_sub(self.delimiter.len() - 1).min(out.len()), false),
+            None => return Err( FlaskError::BadRequest("Multipart body ended before the closing boundary".to_string()) )
+        };
+        if at_delimiter {
+            self.pos += self.delimiter.len();
+            self.finish_delimiter()?;
+            return Ok(0);
+        }
+
+        out[..num_bytes].copy_from_slice(&available[..num_bytes]);
+        self.pos += num_bytes;
+        if self.state == State::Body {
+            self.part_read += num_bytes as u64;
+            if self.part_read > self.max_part_size {
+                return Err( FlaskError::PayloadTooLarge(format!("Multipart part exceeds {} bytes", self.max_part_size)) );
+            }
+        }
+        Ok(num_bytes)
+    }
+
+    // after a delimiter: "--" closes the body, optional whitespace and CRLF start a part
+    fn finish_delimiter(&mut self) -> Result<(), FlaskError> {
+        self.fill(2)?;
+        if self.buf[self.pos..].starts_with(b"--") {
+            self.pos += 2;
+            self.state = State::Done;
+            return self.drain_epilogue();
+        }
+        loop {
+            self.fill(2)?;
+            let available = &self.buf[self.pos..];
+            if available.starts_with(b"\r\n") {
+                self.pos += 2;
+                self.state = State::Headers;
+                return Ok(());
+            }
+            match available.first() {
+                Some(b' ') | Some(b'\t') => self.pos += 1,
+                _ => return Err( FlaskError::BadRequest("Malformed multipart boundary line".to_string()) )
+            }
+        }
+    }
+
+    // the epilogue still counts towards max_total_size
+    fn drain_epilogue(&mut self) -> Result<(), FlaskError> {
+        loop {
+            self.buf.clear();
+            self.pos = 0;
+            if self.eof {
+                return Ok(());
+            }
+            self.fill(READ_SIZE)?;
+        }
+    }
+
+    fn read_headers(&mut self) -> Result<HeaderMap, FlaskError> {
+        let mut headers = HeaderMap::new();
+        let mut header_size = 0;
+        loop {
+            let line_end = loop {
+                if let Some(idx) = find(&self.buf[self.pos..], b"\r\n") {
+                    break idx + 2;
+                }
+                let buffered = self.buf.len() - self.pos;
+                if self.eof || header_size + buffered > MAX_HEADER_SIZE {
+                    return Err( FlaskError::BadRequest("Malformed multipart part headers".to_string()) );
+                }
+                self.fill(buffered + 1)?;
+            };
+            header_size += line_end;
+            if header_size > MAX_HEADER_SIZE {
+                return Err( FlaskError::BadRequest(format!("Multipart part headers exceed {} bytes", MAX_HEADER_SIZE)) );
+            }
+            let line = match std::str::from_utf8(&self.buf[self.pos..self.pos + line_end]) {
+                Ok(line) => line.to_string(),
+                Err(_) => return Err( FlaskError::BadRequest("Multipart part header is not valid UTF-8".to_string()) )
+            };
+            self.pos += line_end;
+            if line == "\r\n" {
+                return Ok(headers);
+            }
+
+            let parsed = read_header(&line)?;
+            match (HeaderName::from_bytes(parsed.key.as_bytes()), HeaderValue::from_str(parsed.value)) {
+                (Ok(name), Ok(value)) => { headers.append(name, value); },
+                _ => return Err( FlaskError::BadRequest(format!("Malformed multipart part header: {}", line.trim_end())) )
+            }
+        }
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+// "form-data; name=\"field\"; filename=\"a;b.txt\"" into the lowercased type and its parameters
+fn parse_disposition(value: &str) -> (String, Vec<(String, String)>) {
+    let (kind, mut rest) = match value.split_once(';') {
+        Some((kind, rest)) => (kind, rest),
+        None => (value, "")
+    };
+    let mut params = Vec::new();
+    while let Some((key, after_key)) = rest.split_once('=') {
+        let key = key.trim().to_ascii_lowercase();
+        let after_key = after_key.trim_start();
+        let (param_value, remainder) = match after_key.strip_prefix('"') {
+            // browsers percent-encode quotes in names (RFC 7578 section 4.2), so the next one closes
+            Some(quoted) => match quoted.split_once('"') {
+                Some((inner, after)) => (inner, after.split_once(';').map(|(_, r)| r).unwrap_or("")),
+                None => (quoted, "")
+            },
+            None => match after_key.split_once(';') {
+                Some((token, r)) => (token.trim(), r),
+                None => (after_key.trim(), "")
+            }
+        };
+        params.push((key, param_value.to_string()));
+        rest = remainder;
+    }
+    (kind.trim().to_ascii_lowercase(), params)
+}
+
+/// One part of a multipart body. It reads its body with `Read`, or with
+/// `save_to`, `copy_to` and `text`.
+pub struct Part<'a, R: Read> {
+    multipart: &'a mut Multipart<R>,
+    headers: HeaderMap,
+    name: String,
+    filename: Option<String>,
+}
+
+impl<'a, R: Read> Part<'a, R> {
+    /// The form field name from `Content-Disposition`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The file name a browser sent with a file field. It comes from the
+    /// client: never use it as a path without sanitizing it.
+    pub fn filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.headers.get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok())
+    }
+
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    /// Write the rest of the body to `writer`, returning the number of bytes.
+    pub fn copy_to<W: Write>(&mut self, writer: &mut W) -> Result<u64, FlaskError> {
+        let mut chunk = [0u8; READ_SIZE];
+        let mut written = 0;
+        loop {
+            let num_bytes = self.multipart.read_body(&mut chunk)?;
+            if num_bytes == 0 {
+                return Ok(written);
+            }
+            if let Err(io_err) = writer.write_all(&chunk[..num_bytes]) {
+                return Err( FlaskError::InternalServerError(format!("Error writing multipart part {}: {}", self.name, io_err)) );
+            }
+            written += num_bytes as u64;
+        }
+    }
+
+    /// Stream the rest of the body into a new file at `path`. The file is
+    /// removed again when the part turns out malformed or too large.
+    pub fn save_to<P: AsRef<Path>>(&mut self, path: P) -> Result<u64, FlaskError> {
+        let path = path.as_ref();
+        let mut file = match File::create(path) {
+            Ok(file) => file,
+            Err(io_err) => return Err( FlaskError::InternalServerError(format!("Cannot create {}: {}", path.display(), io_err)) )
+        };
+        let result = self.copy_to(&mut file);
+        if result.is_err() {
+            drop(file);
+            let _ = fs::remove_file(path);
+        }
+        result
+    }
+
+    /// The rest of the body as UTF-8 text, for ordinary form fields.
+    pub fn text(&mut self) -> Result<String, FlaskError> {
+        let mut body = Vec::new();
+        self.copy_to(&mut body)?;
+        match String::from_utf8(body) {
+            Ok(text) => Ok(text),
+            Err(_) => Err( FlaskError::BadRequest(format!("Multipart field {} is not valid UTF-8", self.name)) )
+        }
+    }
+}
+
+impl<'a, R: Read> Read for Part<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.multipart.read_body(buf) {
+            Ok(num_bytes) => Ok(num_bytes),
+            Err(flask_err) => Err( io::Error::new(io::ErrorKind::InvalidData, flask_err.get_msg().to_string()) )
+        }
+    }
+}
+
+
+//#################################################################################################################
+// test cases go below here
+//#################################################################################################################
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::httpx::read_http_request_streaming;
+    use std::io::BufReader;
+
+    const BODY: &str = "preamble to ignore\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"title\"\r\n\
+        \r\n\
+        Holiday; photos\r\n\
+        --XyZ \r\n\
+        Content-Disposition: form-data; name=\"file\"; filename=\"beach;1.txt\"\r\n\
+        Content-Type: text/plain\r\n\
+        \r\n\
+        sand\r\n--XyQ\r\nwater\r\n\
+        --XyZ--\r\n\
+        epilogue";
+
+    // a reader handing out at most `step` bytes per read, to split delimiters
+    struct Trickle<'a> {
+        data: &'a [u8],
+        step: usize,
+    }
+
+    impl<'a> Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let num_bytes = self.step.min(buf.len()).min(self.data.len());
+            buf[..num_bytes].copy_from_slice(&self.data[..num_bytes]);
+            self.data = &self.data[num_bytes..];
+            Ok(num_bytes)
+        }
+    }
+
+    fn status<T>(result: Result<T, FlaskError>) -> u16 {
+        result.err().map(|err| err.status_code().as_u16()).unwrap_or(200)
+    }
+
+    #[test]
+    fn test_parts_in_any_read_size() {
+        for step in [1, 3, 7, 64, 4096] {
+            let mut form = Multipart::new(Trickle { data: BODY.as_bytes(), step }, "XyZ").unwrap();
+
+            let mut part = form.next_part().unwrap().unwrap();
+            assert_eq!(part.name(), "title");
+            assert_eq!(part.filename(), None);
+            assert_eq!(part.text().unwrap(), "Holiday; photos");
+
+            let mut part = form.next_part().unwrap().unwrap();
+            assert_eq!(part.name(), "file");
+            assert_eq!(part.filename(), Some("beach;1.txt"));
+            assert_eq!(part.content_type(), Some("text/plain"));
+            let mut content = String::new();
+            part.read_to_string(&mut content).unwrap();
+            assert_eq!(content, "sand\r\n--XyQ\r\nwater");
+
+            assert!(form.next_part().unwrap().is_none());
+            assert!(form.next_part().unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_skips_unread_parts() {
+        let mut form = Multipart::new(BODY.as_bytes(), "XyZ").unwrap();
+        assert_eq!(form.next_part().unwrap().unwrap().name(), "title");
+        assert_eq!(form.next_part().unwrap().unwrap().name(), "file");
+        assert!(form.next_part().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_streams_file_to_disk_from_request() {
+        let raw = format!(
+            "POST /upload HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=\"XyZ\"\r\nContent-Length: {}\r\n\r\n{}NEXT",
+            BODY.len(), BODY
+        );
+        let mut reader = BufReader::new(raw.as_bytes());
+        let req = read_http_request_streaming(&mut reader).unwrap();
+        let mut form = Multipart::from_request(req).unwrap();
+        form.next_part().unwrap();
+
+        let path = std::env::temp_dir().join(format!("flask-multipart-{}.txt", std::process::id()));
+        let written = form.next_part().unwrap().unwrap().save_to(&path).unwrap();
+        assert_eq!(written, 18);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "sand\r\n--XyQ\r\nwater");
+        fs::remove_file(&path).unwrap();
+
+        // the epilogue is consumed with the body, leaving the next request on the connection
+        assert!(form.next_part().unwrap().is_none());
+        drop(form);
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "NEXT");
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut form = Multipart::new(BODY.as_bytes(), "XyZ").unwrap().max_part_size(15);
+        assert_eq!(form.next_part().unwrap().unwrap().text().unwrap(), "Holiday; photos");
+        assert_eq!(status(form.next_part().unwrap().unwrap().text()), 413);
+
+        let mut form = Multipart::new(BODY.as_bytes(), "XyZ").unwrap().max_total_size(64);
+        assert_eq!(status(form.next_part().and_then(|part| part.unwrap().text())), 413);
+
+        let mut form = Multipart::new(BODY.as_bytes(), "XyZ").unwrap().max_parts(1);
+        assert!(form.next_part().unwrap().is_some());
+        assert_eq!(status(form.next_part().map(|part| part.is_some())), 413);
+    }
+
+    #[test]
+    fn test_malformed_bodies() {
+        let mut form = Multipart::new("--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nno end".as_bytes(), "XyZ").unwrap();
+        assert_eq!(status(form.next_part().unwrap().unwrap().text()), 400);
+
+        let mut form = Multipart::new("--XyZ\r\nContent-Type: text/plain\r\n\r\nx\r\n--XyZ--".as_bytes(), "XyZ").unwrap();
+        assert_eq!(status(form.next_part().map(|part| part.is_some())), 400);
+
+        assert_eq!(status(Multipart::new(io::empty(), "")), 400);
+
+        let req = Request::post("/").header(header::CONTENT_TYPE, "multipart/form-data").body(io::empty()).unwrap();
+        assert_eq!(status(Multipart::from_request(req)), 400);
+        let req = Request::post("/").header(header::CONTENT_TYPE, "text/plain").body(io::empty()).unwrap();
+        assert_eq!(status(Multipart::from_request(req)), 415);
+    }
+}
@@ -0,0 +1,79 @@
+const HEX_DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+
+fn hex_value(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None
+    }
+}
+
+/// Escape every byte except RFC 3986 unreserved characters and those in `keep`.
+pub(crate) fn percent_encode(input: &str, keep: &[u8]) -> String {
+    let mut encoded = String::with_capacity(input.len());
+    for &byte in input.as_bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) || keep.contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push('%');
+            encoded.push(HEX_DIGITS[(byte >> 4) as usize] as char);
+            encoded.push(HEX_DIGITS[(byte & 0x0f) as usize] as char);
+        }
+    }
+    encoded
+}
+
+/// Decode `%XX` escapes. Returns `None` for a malformed escape or when the
+/// decoded bytes are not UTF-8.
+pub(crate) fn percent_decode(input: &str) -> Option<String> {
+    if !input.contains('%') {
+        return Some(input.to_string());
+    }
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        if bytes[idx] == b'%' {
+            let high = hex_value(*bytes.get(idx + 1)?)?;
+            let low = hex_value(*bytes.get(idx + 2)?)?;
+            decoded.push(high << 4 | low);
+            idx += 3;
+        } else {
+            decoded.push(bytes[idx]);
+            idx += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+
+//#################################################################################################################
+// test cases go below here
+//#################################################################################################################
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("plain"), Some("plain".to_string()));
+        assert_eq!(percent_decode("a%20b%2Fc"), Some("a b/c".to_string()));
+        assert_eq!(percent_decode("caf%C3%A9"), Some("café".to_string()));
+    }
+
+    #[test]
+    fn test_percent_encode() {
+        assert_eq!(percent_encode("a b/c~", b""), "a%20b%2Fc~");
+        assert_eq!(percent_encode("a b/c", b"/"), "a%20b/c");
+        assert_eq!(percent_encode("café", b""), "caf%C3%A9");
+        assert_eq!(percent_decode(&percent_encode("x=1&y=ü", b"")), Some("x=1&y=ü".to_string()));
+    }
+
+    #[test]
+    fn test_percent_decode_rejects_bad_input() {
+        assert_eq!(percent_decode("100%"), None);
+        assert_eq!(percent_decode("%zz"), None);
+        assert_eq!(percent_decode("%FF"), None);
+    }
+}
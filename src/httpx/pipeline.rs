@@ -0,0 +1,226 @@
+use super::{
+    client::{connect, read_final_response, ConnKey},
+    errors::FlaskError,
+    write_http_request
+};
+
+use http::{Method, Request, Response, Uri};
+use std::collections::VecDeque;
+use std::io::BufReader;
+use std::net::{Shutdown, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+/// A connection carrying several requests at once. Responses come back in the
+/// order the requests were sent, so the method of every request in flight is
+/// kept to frame its response (a HEAD response never has a body).
+pub struct PipelinedConnection {
+    reader: BufReader<TcpStream>,
+    in_flight: VecDeque<Method>,
+}
+
+impl PipelinedConnection {
+    pub fn new(stream: TcpStream) -> PipelinedConnection {
+        PipelinedConnection { reader: BufReader::new(stream), in_flight: VecDeque::new() }
+    }
+
+    /// Connect to the server named by the absolute `uri`.
+    pub fn connect(uri: &Uri, timeout: Duration) -> Result<PipelinedConnection, FlaskError> {
+        let key = ConnKey::from_uri(uri)?;
+        Ok( PipelinedConnection::new(connect(&key, timeout)?) )
+    }
+
+    /// Write `req` without waiting for the responses to earlier requests.
+    pub fn send(&mut self, req: &Request<Vec<u8>>) -> Result<(), FlaskError> {
+        write_http_request(self.reader.get_mut(), req)?;
+        self.in_flight.push_back(req.method().clone());
+        Ok(())
+    }
+
+    /// Read the response to the oldest request still in flight.
+    pub fn recv(&mut self) -> Result<Response<Vec<u8>>, FlaskError> {
+        let method = match self.in_flight.pop_front() {
+            Some(method) => method,
+            None => return Err( FlaskError::BadRequest("No pipelined request is waiting for a response".to_string()) )
+        };
+        read_final_response(&mut self.reader, &method)
+    }
+
+    /// Number of requests sent whose response has not been read yet.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+}
+
+/// Write all of `reqs` on the connection behind `reader` and read their responses
+/// in order. Requests are written from a second thread so a server that answers
+/// before reading everything cannot deadlock us.
+pub(crate) fn exchange_pipelined(reader: &mut BufReader<TcpStream>, reqs: &[Request<Vec<u8>>]) -> Result<Vec<Response<Vec<u8>>>, FlaskError> {
+    let mut writer = match reader.get_ref().try_clone() {
+        Ok(writer) => writer,
+        Err(io_err) => return Err( FlaskError::InternalServerError(io_err.to_string()) )
+    };
+
+    thread::scope(|scope| {
+        let write_all = scope.spawn(move || {
+            for req in reqs.iter() {
+                write_http_request(&mut writer, req)?;
+            }
+            Ok::<(), FlaskError>(())
+        });
+
+        let mut responses = Vec::with_capacity(reqs.len());
+        for req in reqs.iter() {
+            match read_final_response(reader, req.method()) {
+                Ok(resp) => responses.push(resp),
+                Err(flask_err) => {
+                    // the writer may be blocked on a server that stopped reading; unblock it so the scope can end
+                    let _ = reader.get_ref().shutdown(Shutdown::Both);
+                    let msg = format!("Pipelined response {} of {} failed: {}", responses.len() + 1, reqs.len(), flask_err);
+                    return Err( FlaskError::BadGateway(msg) );
+                }
+            }
+        }
+        match write_all.join() {
+            Ok(result) => result.map(|_| responses),
+            Err(_) => Err( FlaskError::InternalServerError("Pipeline writer thread panicked".to_string()) )
+        }
+    })
+}
+
+
+//#################################################################################################################
+// test cases go below here
+//#################################################################################################################
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::httpx::client::Client;
+    use crate::httpx::{read_http_request_from, write_http_response};
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::mpsc;
+
+    // answers each request in order with its path; HEAD gets the length of
+    // what a GET would return but no body. Waits a moment before reading so
+    // it can report how many requests arrived before the first response.
+    fn spawn_pipelining_server() -> (SocketAddr, mpsc::Receiver<usize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                thread::sleep(Duration::from_millis(50));
+                let mut reader = BufReader::new(stream.unwrap());
+                let mut first = true;
+                while let Ok(req) = read_http_request_from(&mut reader) {
+                    if first {
+                        let queued = reader.buffer().windows(4).filter(|w| *w == b"\r\n\r\n").count();
+                        let _ = sender.send(1 + queued);
+                        first = false;
+                    }
+                    let path = req.uri().path().as_bytes().to_vec();
+                    let resp = match *req.method() {
+                        Method::HEAD => Response::builder().header("content-length", path.len()).body(Vec::new()).unwrap(),
+                        _ => Response::new(path)
+                    };
+                    write_http_response(reader.get_mut(), &resp).unwrap();
+                }
+            }
+        });
+        (addr, receiver)
+    }
+
+    fn req(method: Method, addr: SocketAddr, path: &str) -> Request<Vec<u8>> {
+        Request::builder().method(method).uri(format!("http://{}{}", addr, path)).body(Vec::new()).unwrap()
+    }
+
+    #[test]
+    fn test_send_pipelined_in_order_with_head() {
+        let (addr, first_batch) = spawn_pipelining_server();
+        let reqs = vec![
+            req(Method::GET, addr, "/first"),
+            req(Method::HEAD, addr, "/second"),
+            req(Method::GET, addr, "/third"),
+        ];
+        let client = Client::new();
+        let responses = client.send_pipelined(&reqs).unwrap();
+
+        let bodies: Vec<&[u8]> = responses.iter().map(|resp| resp.body().as_slice()).collect();
+        assert_eq!(bodies, vec![b"/first".as_slice(), b"".as_slice(), b"/third".as_slice()]);
+        assert_eq!(responses[1].headers()["content-length"], "7");
+        assert_eq!(first_batch.recv().unwrap(), 3);
+        assert_eq!(client.idle_connections(), 1);
+    }
+
+    #[test]
+    fn test_pipelined_connection() {
+        let (addr, first_batch) = spawn_pipelining_server();
+        let uri: Uri = format!("http://{}/", addr).parse().unwrap();
+        let mut conn = PipelinedConnection::connect(&uri, Duration::from_secs(1)).unwrap();
+        for path in ["/a", "/b", "/c"] {
+            conn.send(&req(Method::GET, addr, path)).unwrap();
+        }
+        conn.send(&req(Method::HEAD, addr, "/d")).unwrap();
+        assert_eq!(conn.in_flight(), 4);
+        assert_eq!(first_batch.recv().unwrap(), 4);
+
+        for path in ["/a", "/b", "/c"] {
+            assert_eq!(conn.recv().unwrap().body(), path.as_bytes());
+        }
+        assert!(conn.recv().unwrap().body().is_empty());
+        assert_eq!(conn.in_flight(), 0);
+        assert!(conn.recv().is_err());
+    }
+
+    #[test]
+    fn test_send_pipelined_rejects_mixed_origins() {
+        let reqs = vec![
+            Request::get("http://a.example/").body(Vec::new()).unwrap(),
+            Request::get("http://b.example/").body(Vec::new()).unwrap(),
+        ];
+        let flask_err = Client::new().send_pipelined(&reqs).err().unwrap();
+        assert_eq!(flask_err.get_msg(), "Pipelined requests must share one origin, got http://b.example/");
+    }
+
+    #[test]
+    fn test_send_pipelined_reports_early_close() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let _ = read_http_request_from(&mut reader).unwrap();
+            let resp = Response::builder().header("connection", "close").body(b"only one".to_vec()).unwrap();
+            write_http_response(reader.get_mut(), &resp).unwrap();
+        });
+
+        let reqs = vec![req(Method::GET, addr, "/1"), req(Method::GET, addr, "/2")];
+        let flask_err = Client::new().send_pipelined(&reqs).err().unwrap();
+        assert!(flask_err.get_msg().starts_with("Pipelined response 2 of 2 failed"));
+    }
+
+    #[test]
+    fn test_send_pipelined_read_error_unblocks_writer() {
+        // answers garbage after the first request line and stops reading, holding the socket open
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut byte = [0u8; 1];
+            while std::io::Read::read(&mut stream, &mut byte).unwrap() == 1 && byte[0] != b'\n' {}
+            std::io::Write::write_all(&mut stream, b"garbage\r\n\r\n").unwrap();
+            let _ = done_rx.recv();
+        });
+
+        let reqs: Vec<Request<Vec<u8>>> = (0..8)
+            .map(|_| Request::post(format!("http://{}/", addr)).body(vec![b'x'; 4 << 20]).unwrap())
+            .collect();
+        let (result_tx, result_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = result_tx.send(Client::new().send_pipelined(&reqs).is_err());
+        });
+        assert!(result_rx.recv_timeout(Duration::from_secs(10)).unwrap());
+        done_tx.send(()).unwrap();
+    }
+}
@@ -0,0 +1,177 @@
+use super::client::ConnKey;
+
+use std::collections::HashMap;
+use std::io::{BufReader, ErrorKind};
+use std::net::TcpStream;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+struct IdleConnection {
+    reader: BufReader<TcpStream>,
+    idle_since: Instant,
+}
+
+// A connection is only worth reusing if the server has not closed it and has
+// not sent anything unprompted. Peeking without blocking tells us both.
+fn is_open(reader: &BufReader<TcpStream>) -> bool {
+    if !reader.buffer().is_empty() {
+        return false;
+    }
+    let stream = reader.get_ref();
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let mut probe = [0u8; 1];
+    let open = match stream.peek(&mut probe) {
+        Err(io_err) => io_err.kind() == ErrorKind::WouldBlock,
+        // Ok(0) is the server's FIN, Ok(_) is stray data
+        Ok(_) => false
+    };
+    stream.set_nonblocking(false).is_ok() && open
+}
+
+/// Idle keep-alive connections, keyed by scheme, host and port.
+pub(crate) struct ConnectionPool {
+    max_idle_per_host: usize,
+    idle_timeout: Duration,
+    idle: Mutex<HashMap<ConnKey, Vec<IdleConnection>>>,
+}
+
+impl ConnectionPool {
+    pub(crate) fn new(max_idle_per_host: usize, idle_timeout: Duration) -> ConnectionPool {
+        ConnectionPool {
+            max_idle_per_host,
+            idle_timeout,
+            idle: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn set_max_idle_per_host(&mut self, max_idle_per_host: usize) {
+        self.max_idle_per_host = max_idle_per_host;
+    }
+
+    pub(crate) fn set_idle_timeout(&mut self, idle_timeout: Duration) {
+        self.idle_timeout = idle_timeout;
+    }
+
+    /// The most recently used live connection for `key`, if any. Expired or
+    /// server-closed connections found along the way are dropped.
+    pub(crate) fn checkout(&self, key: &ConnKey) -> Option<BufReader<TcpStream>> {
+        let mut idle = self.idle.lock().unwrap();
+        let conns = idle.get_mut(key)?;
+        let mut found = None;
+        while let Some(conn) = conns.pop() {
+            if conn.idle_since.elapsed() < self.idle_timeout && is_open(&conn.reader) {
+                found = Some(conn.reader);
+                break;
+            }
+        }
+        if conns.is_empty() {
+            idle.remove(key);
+        }
+        found
+    }
+
+    pub(crate) fn checkin(&self, key: ConnKey, reader: BufReader<TcpStream>) {
+        if self.max_idle_per_host == 0 {
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap();
+        let conns = idle.entry(key).or_default();
+        if conns.len() >= self.max_idle_per_host {
+            // the oldest connection is the most likely to be timed out by the server
+            conns.remove(0);
+        }
+        conns.push(IdleConnection { reader, idle_since: Instant::now() });
+    }
+
+    /// Drop every connection idle for longer than the idle timeout.
+    pub(crate) fn evict_expired(&self) {
+        let mut idle = self.idle.lock().unwrap();
+        for conns in idle.values_mut() {
+            conns.retain(|conn| conn.idle_since.elapsed() < self.idle_timeout);
+        }
+        idle.retain(|_, conns| !conns.is_empty());
+    }
+
+    pub(crate) fn idle_count(&self) -> usize {
+        self.idle.lock().unwrap().values().map(|conns| conns.len()).sum()
+    }
+}
+
+
+//#################################################################################################################
+// test cases go below here
+//#################################################################################################################
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn key(port: u16) -> ConnKey {
+        ConnKey { scheme: "http".to_string(), host: "127.0.0.1".to_string(), port }
+    }
+
+    // open `count` connections to a listener that keeps them open until told otherwise
+    fn open_connections(count: usize) -> (u16, Vec<BufReader<TcpStream>>, Vec<TcpStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let clients: Vec<BufReader<TcpStream>> = (0..count)
+            .map(|_| BufReader::new(TcpStream::connect(("127.0.0.1", port)).unwrap()))
+            .collect();
+        let servers: Vec<TcpStream> = (0..count).map(|_| listener.accept().unwrap().0).collect();
+        (port, clients, servers)
+    }
+
+    #[test]
+    fn test_checkout_returns_most_recent() {
+        let (port, clients, _servers) = open_connections(2);
+        let addrs: Vec<_> = clients.iter().map(|r| r.get_ref().local_addr().unwrap()).collect();
+        let pool = ConnectionPool::new(4, Duration::from_secs(60));
+        for reader in clients {
+            pool.checkin(key(port), reader);
+        }
+        assert_eq!(pool.idle_count(), 2);
+
+        let reader = pool.checkout(&key(port)).unwrap();
+        assert_eq!(reader.get_ref().local_addr().unwrap(), addrs[1]);
+        assert_eq!(pool.idle_count(), 1);
+    }
+
+    #[test]
+    fn test_max_idle_per_host() {
+        let (port, clients, _servers) = open_connections(3);
+        let pool = ConnectionPool::new(2, Duration::from_secs(60));
+        for reader in clients {
+            pool.checkin(key(port), reader);
+        }
+        assert_eq!(pool.idle_count(), 2);
+    }
+
+    #[test]
+    fn test_idle_timeout_eviction() {
+        let (port, clients, _servers) = open_connections(1);
+        let pool = ConnectionPool::new(2, Duration::from_millis(20));
+        for reader in clients {
+            pool.checkin(key(port), reader);
+        }
+        thread::sleep(Duration::from_millis(40));
+        pool.evict_expired();
+        assert_eq!(pool.idle_count(), 0);
+        assert!(pool.checkout(&key(port)).is_none());
+    }
+
+    #[test]
+    fn test_server_closed_connection_is_discarded() {
+        let (port, clients, servers) = open_connections(1);
+        let pool = ConnectionPool::new(2, Duration::from_secs(60));
+        for reader in clients {
+            pool.checkin(key(port), reader);
+        }
+        drop(servers);
+        thread::sleep(Duration::from_millis(20));
+        assert!(pool.checkout(&key(port)).is_none());
+        assert_eq!(pool.idle_count(), 0);
+    }
+}
@@ -0,0 +1,220 @@
+use super::{
+    errors::FlaskError,
+    percent::{percent_decode, percent_encode}
+};
+
+use http::{Request, Uri};
+use std::fmt;
+
+/// Decode one `application/x-www-form-urlencoded` name or value: `+` is a
+/// space, then `%XX` escapes are decoded. `None` for a malformed escape or
+/// bytes that are not UTF-8.
+pub(crate) fn decode_component(input: &str) -> Option<String> {
+    percent_decode(&input.replace('+', " "))
+}
+
+/// The inverse of `decode_component`: spaces become `+`, everything but
+/// unreserved characters is escaped.
+pub(crate) fn encode_component(input: &str) -> String {
+    percent_encode(input, b" ").replace(' ', "+")
+}
+
+/// The parameters of a query string, in the order they appear. A name may
+/// occur more than once (`tag=a&tag=b`); `get` returns the first value and
+/// `get_all` every one.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QueryParams {
+    pairs: Vec<(String, String)>,
+}
+
+impl QueryParams {
+    /// Parse a query string without the leading `?`. Empty pairs (`a=1&&b=2`)
+    /// are skipped and a name without `=` gets an empty value.
+    pub fn parse(query: &str) -> Result<QueryParams, FlaskError> {
+        let mut pairs = Vec::new();
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            match (decode_component(name), decode_component(value)) {
+                (Some(name), Some(value)) => pairs.push((name, value)),
+                _ => {
+                    let msg = format!("Invalid percent-encoding in query parameter {:?}", pair);
+                    return Err( FlaskError::BadRequest(msg) );
+                }
+            }
+        }
+        Ok( QueryParams { pairs } )
+    }
+
+    /// The parameters of `uri`; none when it has no query string.
+    pub fn from_uri(uri: &Uri) -> Result<QueryParams, FlaskError> {
+        QueryParams::parse(uri.query().unwrap_or(""))
+    }
+
+    pub fn from_request<B>(req: &Request<B>) -> Result<QueryParams, FlaskError> {
+        QueryParams::from_uri(req.uri())
+    }
+
+    pub(crate) fn from_pairs(pairs: Vec<(String, String)>) -> QueryParams {
+        QueryParams { pairs }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.pairs.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.pairs.iter().filter(move |(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.pairs.iter().any(|(key, _)| key == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.pairs.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.pairs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+
+    /// Deserialize the parameters into `T`, a 400 when they do not fit.
+    #[cfg(feature = "serde")]
+    pub fn deserialize<T: serde::de::DeserializeOwned>(&self) -> Result<T, FlaskError> {
+        match serde_urlencoded::from_str(&self.to_string()) {
+            Ok(value) => Ok(value),
+            Err(err) => Err( FlaskError::BadRequest(format!("Invalid query string: {}", err)) )
+        }
+    }
+}
+
+/// The parameters encoded as a query string, without the leading `?`.
+impl fmt::Display for QueryParams {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (idx, (name, value)) in self.pairs.iter().enumerate() {
+            if idx > 0 {
+                f.write_str("&")?;
+            }
+            write!(f, "{}={}", encode_component(name), encode_component(value))?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> FromIterator<(&'a str, &'a str)> for QueryParams {
+    fn from_iter<I: IntoIterator<Item = (&'a str, &'a str)>>(iter: I) -> QueryParams {
+        QueryParams { pairs: iter.into_iter().map(|(name, value)| (name.to_string(), value.to_string())).collect() }
+    }
+}
+
+/// Builds an encoded query string, or a URI with one:
+///
+/// ```ignore
+/// let uri = QueryBuilder::new().append("q", "rust lang").append("tag", "a&b").uri("/search")?;
+/// assert_eq!(uri, "/search?q=rust+lang&tag=a%26b");
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct QueryBuilder {
+    params: QueryParams,
+}
+
+impl QueryBuilder {
+    pub fn new() -> QueryBuilder {
+        QueryBuilder::default()
+    }
+
+    /// Add a parameter after the existing ones, also when the name is taken.
+    pub fn append(mut self, name: &str, value: &str) -> QueryBuilder {
+        self.params.pairs.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// The encoded query string, without the leading `?`.
+    pub fn build(&self) -> String {
+        self.params.to_string()
+    }
+
+    /// `path` with the query string appended, or `path` alone when there are
+    /// no parameters.
+    pub fn uri(&self, path: &str) -> Result<Uri, FlaskError> {
+        let target = match self.params.is_empty() {
+            true => path.to_string(),
+            false => format!("{}?{}", path, self.build())
+        };
+        match target.parse::<Uri>() {
+            Ok(uri) => Ok(uri),
+            Err(err) => Err( FlaskError::BadRequest(format!("Invalid URI {}: {}", target, err)) )
+        }
+    }
+}
+
+
+//#################################################################################################################
+// test cases go below here
+//#################################################################################################################
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_decodes_and_keeps_order() {
+        let params = QueryParams::parse("q=rust+lang&tag=a%26b&tag=c&flag&&empty=&caf%C3%A9=%E2%9C%93").unwrap();
+        let pairs: Vec<(&str, &str)> = params.iter().collect();
+        assert_eq!(pairs, vec![("q", "rust lang"), ("tag", "a&b"), ("tag", "c"), ("flag", ""), ("empty", ""), ("café", "✓")]);
+        assert_eq!(params.get("tag"), Some("a&b"));
+        assert_eq!(params.get_all("tag").collect::<Vec<&str>>(), vec!["a&b", "c"]);
+        assert!(params.contains("flag"));
+        assert_eq!(params.get("missing"), None);
+        // a literal plus is escaped
+        assert_eq!(QueryParams::parse("sum=1%2B1").unwrap().get("sum"), Some("1+1"));
+    }
+
+    #[test]
+    fn test_invalid_encoding() {
+        for query in ["a=%zz", "a=%4", "%ff=1", "a=%C3%28"] {
+            match QueryParams::parse(query) {
+                Err( FlaskError::BadRequest(_) ) => {},
+                other => panic!("{} parsed as {:?}", query, other)
+            }
+        }
+    }
+
+    #[test]
+    fn test_from_request() {
+        let req = Request::get("http://example.com/search?page=2").body(()).unwrap();
+        assert_eq!(QueryParams::from_request(&req).unwrap().get("page"), Some("2"));
+        let req = Request::get("/search").body(()).unwrap();
+        assert!(QueryParams::from_request(&req).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_builder_round_trip() {
+        let builder = QueryBuilder::new().append("q", "rust lang").append("tag", "a&b").append("tag", "1+1=2");
+        assert_eq!(builder.build(), "q=rust+lang&tag=a%26b&tag=1%2B1%3D2");
+        assert_eq!(builder.uri("/search").unwrap(), "/search?q=rust+lang&tag=a%26b&tag=1%2B1%3D2");
+        assert_eq!(QueryBuilder::new().uri("/search").unwrap(), "/search");
+
+        let parsed = QueryParams::parse(&builder.build()).unwrap();
+        assert_eq!(parsed.get_all("tag").collect::<Vec<&str>>(), vec!["a&b", "1+1=2"]);
+        assert_eq!(parsed.to_string(), builder.build());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_deserialize() {
+        #[derive(serde::Deserialize, Debug, PartialEq)]
+        struct Search {
+            q: String,
+            page: Option<u32>,
+        }
+
+        let params = QueryParams::parse("q=a+%26+b&page=3").unwrap();
+        assert_eq!(params.deserialize::<Search>().unwrap(), Search { q: "a & b".to_string(), page: Some(3) });
+        let params = QueryParams::parse("page=3").unwrap();
+        assert!(params.deserialize::<Search>().is_err());
+    }
+}
@@ -1,6 +1,9 @@
 use super::{
+  body::{BodyFraming, BodyReader, is_chunked, read_body, unchunk_headers},
   errors::FlaskError,
+  tunnel::ConnectTarget,
   CONTENT_LENGTH_HEADER,
+  TRANSFER_ENCODING_HEADER,
   get_http_version,
   read_buffered_line,
   read_header
@@ -8,7 +11,7 @@ use super::{
 
 use crate::combinators::*;
 
-use http::Request;
+use http::{Method, Request, Version};
 use http::request::Builder;
 use std::io::{
   BufReader,
@@ -23,7 +26,7 @@ struct RequestLine<'a> {
     version: &'a str,
 }
 
-fn parse_request_line(line: &str) -> Result<RequestLine, FlaskError> {
+fn parse_request_line(line: &str) -> Result<RequestLine<'_>, FlaskError> {
     let (line, method): (&str, &str) = match http_method(line) {
       Ok(obj) => obj,
       Err(_) => return Err( FlaskError::BadRequest("Malformed Request Line: missing HTTP method".to_string()) )
@@ -45,86 +48,103 @@ fn parse_request_line(line: &str) -> Result<RequestLine, FlaskError> {
       Err(_) => return Err( FlaskError::BadRequest("Malformed Request Line: bad http version".to_string()) )
     };
     match crlf(line) {
-      Ok(_) => Ok(RequestLine {method: method, target: target, version: version}),
+      Ok(_) => Ok(RequestLine {method, target, version}),
       Err(_) => Err( FlaskError::BadRequest("Malformed Request Line: no terminating CRLF".to_string()) )
     }
 }
 
-fn _read_initial_request_line(reader: &mut BufReader<TcpStream>) -> Result<Builder, FlaskError> {
+fn _read_initial_request_line<R: BufRead>(reader: &mut R) -> Result<Builder, FlaskError> {
     let mut request = Request::builder();
 
     let mut line: String = String::from("");
-    match reader.read_line(&mut line) {
-        Ok(_) => {
-            let req_line: RequestLine = match parse_request_line(line.as_str()) {
-                Ok(parsed_line) => parsed_line,
-                Err(_) => {
-                    let msg = format!("Malformed first line of request");
-                    return Err( FlaskError::BadRequest(msg) );
-                }
-            };
-            let version = get_http_version(req_line.version)?;
-
-            request = request
-                .method(req_line.method)
-                .uri(req_line.target)
-                .version(version);
-        },
-        Err(_) => {}
+    if reader.read_line(&mut line).is_ok() {
+        let req_line: RequestLine = match parse_request_line(line.as_str()) {
+            Ok(parsed_line) => parsed_line,
+            Err(_) => {
+                let msg = "Malformed first line of request".to_string();
+                return Err( FlaskError::BadRequest(msg) );
+            }
+        };
+        let version = get_http_version(req_line.version)?;
+        if req_line.method == "CONNECT" {
+            ConnectTarget::parse(req_line.target)?;
+        }
+
+        request = request
+            .method(req_line.method)
+            .uri(req_line.target)
+            .version(version);
     }
     Ok(request)
 }
 
 
-fn _read_http_request(reader: &mut BufReader<TcpStream>) -> Result<Request<Vec<u8>>, FlaskError> {
+// the request line and headers, and how the body that follows is framed
+fn _read_request_head<R: BufRead>(reader: &mut R) -> Result<(Builder, BodyFraming), FlaskError> {
   let mut request = _read_initial_request_line(reader)?;
 
-  let content_length = {
-      let mut content_length_mut = 0;
-      loop {
-          let line: String = read_buffered_line(reader)?;
-          if line.as_str() == "\r\n" {
-              break;
+  let mut content_length: Option<usize> = None;
+  let mut chunked = false;
+  loop {
+      let line: String = read_buffered_line(reader)?;
+      if line.as_str() == "\r\n" {
+          break;
+      }
+
+      let header_line = match read_header(line.as_str()) {
+          Ok(hl) => hl,
+          Err(err) => {
+              return Err( err );
           }
+      };
 
-          let header_line = match read_header(line.as_str()) {
-              Ok(hl) => hl,
-              Err(err) => {
-                  return Err( err );
-              }
-          };
-
-          if header_line.key.to_lowercase() == CONTENT_LENGTH_HEADER {
-              match header_line.value.parse::<usize>() {
-                  Ok(val) => content_length_mut = val,
-                  Err(_) => {
-                      let msg = format!("Invalid Content-Length: {}", header_line.value);
-                      return Err( FlaskError::BadRequest(msg) );
-                  }
+      let key = header_line.key.to_lowercase();
+      if key == CONTENT_LENGTH_HEADER {
+          match header_line.value.parse::<usize>() {
+              Ok(val) => content_length = Some(val),
+              Err(_) => {
+                  let msg = format!("Invalid Content-Length: {}", header_line.value);
+                  return Err( FlaskError::BadRequest(msg) );
               }
           }
-          request = request.header(header_line.key, header_line.value);
+      } else if key == TRANSFER_ENCODING_HEADER {
+          chunked = is_chunked(header_line.value);
+          if !chunked {
+              let msg = format!("Unsupported Transfer-Encoding: {}", header_line.value);
+              return Err( FlaskError::NotImplemented(msg) );
+          }
       }
-      content_length_mut
+      request = request.header(header_line.key, header_line.value);
+  }
+
+  // a request with both is a request smuggling attempt (RFC 9112 section 6.1)
+  let framing = match (chunked, content_length) {
+      (true, Some(_)) => {
+          return Err( FlaskError::BadRequest("Request has both Content-Length and Transfer-Encoding".to_string()) );
+      },
+      (true, None) => BodyFraming::Chunked,
+      (false, Some(len)) => BodyFraming::Length(len),
+      (false, None) => BodyFraming::Empty,
   };
+  Ok( (request, framing) )
+}
 
-  let mut body = vec![0; content_length];
-  match reader.read_exact(&mut body) {
-      Ok(_) => {
-          match request.body(body) {
-              Ok(req) => Ok(req),
-              Err(http_err) => {
-                  eprintln!("ERROR reading request body from stream");
-                  let msg: String = http_err.to_string();
-                  let flask_err = FlaskError::ClientClosedRequest(msg);
-                  Err(flask_err)
-              }
+fn _read_http_request<R: BufRead>(reader: &mut R) -> Result<Request<Vec<u8>>, FlaskError> {
+  let (request, framing) = _read_request_head(reader)?;
+  let body = read_body(reader, framing)?;
+  match request.body(body) {
+      Ok(mut req) => {
+          if framing == BodyFraming::Chunked {
+              let body_len = req.body().len();
+              unchunk_headers(req.headers_mut(), body_len);
           }
+          Ok(req)
       },
       Err(http_err) => {
+          eprintln!("ERROR reading request body from stream");
           let msg: String = http_err.to_string();
-          let flask_err = FlaskError::BadRequest(msg);
-          Err(flask_err) 
+          let flask_err = FlaskError::ClientClosedRequest(msg);
+          Err(flask_err)
       }
   }
 }
@@ -135,6 +155,82 @@ pub fn read_http_request(stream: TcpStream) -> Result<Request<Vec<u8>>, FlaskErr
   _read_http_request(&mut reader)
 }
 
+/// Read one request from a caller owned reader. Unlike `read_http_request` the
+/// reader, and any bytes it buffered past the end of the request, stay usable.
+pub fn read_http_request_from<R: BufRead>(reader: &mut R) -> Result<Request<Vec<u8>>, FlaskError> {
+  _read_http_request(reader)
+}
+
+/// Read the request line and headers and leave the body on the wire: the
+/// returned request's body reads it as the caller consumes it, e.g. to stream
+/// an upload to disk. The body must be read to the end before `reader` can
+/// carry another request.
+pub fn read_http_request_streaming<R: BufRead>(reader: &mut R) -> Result<Request<BodyReader<'_, R>>, FlaskError> {
+  let (request, framing) = _read_request_head(reader)?;
+  match request.body(BodyReader::new(reader, framing)) {
+      Ok(req) => Ok(req),
+      Err(http_err) => Err( FlaskError::BadRequest(http_err.to_string()) )
+  }
+}
+
+// the request-target for the request line: authority-form for CONNECT, origin-form otherwise
+fn request_target(req: &Request<Vec<u8>>) -> String {
+  let uri = req.uri();
+  if req.method() == Method::CONNECT {
+    if let Some(authority) = uri.authority() {
+      return authority.to_string();
+    }
+  }
+  match uri.path_and_query() {
+    Some(pq) if !pq.as_str().is_empty() => pq.to_string(),
+    _ => "/".to_string()
+  }
+}
+
+fn version_str(version: Version) -> &'static str {
+  match version {
+    Version::HTTP_10 => "HTTP/1.0",
+    _ => "HTTP/1.1"
+  }
+}
+
+pub fn write_http_request<W: Write>(stream: &mut W, req: &Request<Vec<u8>>) -> Result<(), FlaskError> {
+  let mut head = format!("{} {} {}\r\n", req.method(), request_target(req), version_str(req.version()));
+
+  let headers = req.headers();
+  if !headers.contains_key(http::header::HOST) {
+    if let Some(authority) = req.uri().authority() {
+      head.push_str(&format!("host: {}\r\n", authority));
+    }
+  }
+  for (key, value) in headers.iter() {
+    let value = match value.to_str() {
+      Ok(val) => val,
+      Err(_) => {
+        let msg = format!("Invalid value for header {}", key);
+        return Err( FlaskError::BadRequest(msg) );
+      }
+    };
+    head.push_str(&format!("{}: {}\r\n", key, value));
+  }
+  let body = req.body();
+  let has_framing = headers.contains_key(http::header::CONTENT_LENGTH) || headers.contains_key(http::header::TRANSFER_ENCODING);
+  if !body.is_empty() && !has_framing {
+    head.push_str(&format!("{}: {}\r\n", CONTENT_LENGTH_HEADER, body.len()));
+  }
+  head.push_str("\r\n");
+
+  let mut payload = head.into_bytes();
+  payload.extend_from_slice(body);
+  match stream.write_all(&payload).and_then(|_| stream.flush()) {
+    Ok(_) => Ok(()),
+    Err(io_err) => {
+      let msg = format!("Error writing request to stream: {}", io_err);
+      Err( FlaskError::BadGateway(msg) )
+    }
+  }
+}
+
 //#################################################################################################################
 // test cases go below here
 //#################################################################################################################
@@ -194,4 +290,100 @@ mod tests {
     let flask_err = result.err().unwrap();
     assert_eq!(flask_err.get_msg(), "Malformed Request Line: no terminating CRLF"); 
   }
+
+  #[test]
+  fn test_read_header_whitespace_is_optional() {
+    let mut reader = BufReader::new("GET / HTTP/1.1\r\nHost:example.com\r\nX-Pad: \tpadded \r\n\r\n".as_bytes());
+    let req = read_http_request_from(&mut reader).unwrap();
+    assert_eq!(req.headers()["host"], "example.com");
+    assert_eq!(req.headers()["x-pad"], "padded");
+  }
+
+  #[test]
+  fn test_read_malformed_headers_is_an_error() {
+    for (raw, msg) in [
+      ("GET / HTTP/1.1\r\nno colon here\r\n\r\n", "Malformed Header: missing colon after field name"),
+      ("GET / HTTP/1.1\r\n: empty name\r\n\r\n", "Malformed Header: invalid field name"),
+      ("GET / HTTP/1.1\r\nX-Bell: \x07\r\n\r\n", "Malformed Header: no terminating CRLF"),
+    ] {
+      let mut reader = BufReader::new(raw.as_bytes());
+      let flask_err = read_http_request_from(&mut reader).err().unwrap();
+      assert_eq!(flask_err.get_msg(), msg);
+    }
+  }
+
+  #[test]
+  fn test_read_connect_request() {
+    let mut reader = BufReader::new("CONNECT example.com:443 HTTP/1.1\r\n\r\n".as_bytes());
+    let req = read_http_request_from(&mut reader).unwrap();
+    assert_eq!(req.method(), Method::CONNECT);
+    assert_eq!(req.uri().authority().unwrap().as_str(), "example.com:443");
+  }
+
+  #[test]
+  fn test_read_connect_request_requires_authority_form() {
+    let mut reader = BufReader::new("CONNECT /index.html HTTP/1.1\r\n\r\n".as_bytes());
+    let flask_err = read_http_request_from(&mut reader).err().unwrap();
+    assert_eq!(flask_err.get_msg(), "Malformed CONNECT target: /index.html");
+  }
+
+  #[test]
+  fn test_read_chunked_request() {
+    let raw = "POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n";
+    let mut reader = BufReader::new(raw.as_bytes());
+    let req = read_http_request_from(&mut reader).unwrap();
+    assert_eq!(req.body(), b"abcde");
+  }
+
+  #[test]
+  fn test_read_request_rejects_length_and_chunked() {
+    let raw = "POST / HTTP/1.1\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+    let mut reader = BufReader::new(raw.as_bytes());
+    let flask_err = read_http_request_from(&mut reader).err().unwrap();
+    assert_eq!(flask_err.get_msg(), "Request has both Content-Length and Transfer-Encoding");
+  }
+
+  #[test]
+  fn test_write_http_request_origin_form() {
+    let req = Request::builder()
+      .method("POST")
+      .uri("http://example.com:8080/submit?x=1")
+      .header("X-Test", "yes")
+      .body(b"hello".to_vec())
+      .unwrap();
+
+    let mut out: Vec<u8> = Vec::new();
+    write_http_request(&mut out, &req).unwrap();
+    let written = String::from_utf8(out).unwrap();
+    assert_eq!(written, "POST /submit?x=1 HTTP/1.1\r\nhost: example.com:8080\r\nx-test: yes\r\ncontent-length: 5\r\n\r\nhello");
+  }
+
+  #[test]
+  fn test_write_http_request_keeps_host_header() {
+    let req = Request::builder()
+      .uri("/")
+      .header("Host", "upstream.local")
+      .body(Vec::new())
+      .unwrap();
+
+    let mut out: Vec<u8> = Vec::new();
+    write_http_request(&mut out, &req).unwrap();
+    let written = String::from_utf8(out).unwrap();
+    assert_eq!(written, "GET / HTTP/1.1\r\nhost: upstream.local\r\n\r\n");
+  }
+
+  #[test]
+  fn test_chunked_request_round_trip() {
+    let raw = "POST /wiki HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\n";
+    let req = read_http_request_from(&mut BufReader::new(raw.as_bytes())).unwrap();
+    assert!(!req.headers().contains_key("transfer-encoding"));
+    assert_eq!(req.headers()["content-length"], "9");
+
+    let mut out: Vec<u8> = Vec::new();
+    write_http_request(&mut out, &req).unwrap();
+    assert_eq!(String::from_utf8(out.clone()).unwrap(), "POST /wiki HTTP/1.1\r\nhost: example.com\r\ncontent-length: 9\r\n\r\nWikipedia");
+
+    let again = read_http_request_from(&mut BufReader::new(out.as_slice())).unwrap();
+    assert_eq!(again.body(), b"Wikipedia");
+  }
 }
@@ -1,6 +1,8 @@
 use super::{
+    body::{BodyFraming, is_chunked, read_body, unchunk_headers},
     errors::FlaskError,
     CONTENT_LENGTH_HEADER,
+    TRANSFER_ENCODING_HEADER,
     get_http_version,
     read_buffered_line,
     read_header
@@ -16,7 +18,7 @@ struct ResponseLine<'a> {
     version: &'a str,
 }
 
-use http::{Response, StatusCode};
+use http::{Method, Response, StatusCode, Version};
 use http::response::Builder;
 use std::io::{
   BufReader,
@@ -26,7 +28,7 @@ use std::net::TcpStream;
 
 
 // "HTTP/1.1 200 OK\r\n";
-fn parse_response_line(line: &str) -> Result<ResponseLine, FlaskError> {
+fn parse_response_line(line: &str) -> Result<ResponseLine<'_>, FlaskError> {
   let (line, version): (&str, &str) = match http_version(line) {
     Ok(obj) => obj,
     Err(_) => return Err( FlaskError::BadRequest("Malformed Response Line: bad http version".to_string()) )
@@ -53,105 +55,171 @@ fn parse_response_line(line: &str) -> Result<ResponseLine, FlaskError> {
   };
 
   match crlf(line) {
-    Ok(_) => Ok(ResponseLine {status_code: status_code, status_msg: status_msg, version: version}),
+    Ok(_) => Ok(ResponseLine {status_code, status_msg, version}),
     Err(_) => Err( FlaskError::BadRequest("Malformed Response Line: no terminating CRLF".to_string()) )
   }
 }
 
-fn _read_initial_request_line(reader: &mut BufReader<TcpStream>) -> Result<Builder, FlaskError> {
+fn _read_initial_request_line<R: BufRead>(reader: &mut R) -> Result<Builder, FlaskError> {
   let mut response = Response::builder();
 
   let mut line: String = String::from("");
-  match reader.read_line(&mut line) {
-      Ok(_) => {
-          let resp_line: ResponseLine = match parse_response_line(line.as_str()) {
-            Ok(parsed_line) => parsed_line,
-            Err(_) => {
-                let msg = format!("Malformed first line of response");
-                return Err( FlaskError::BadRequest(msg) );
-            }
-          };
-
-          let status_code_bytes = resp_line.status_code.as_bytes();
-          let status_code = match StatusCode::from_bytes(status_code_bytes) {
-              Ok(_status_code) => _status_code,
-              Err(parse_err) => {
-                  eprintln!("ERROR in response.rs calling StatusCode::from_bytes");
-                  let msg: String = parse_err.to_string();
-                  let flask_err = FlaskError::BadRequest(msg);
-                  return Err(flask_err);
-              }
-          };
-          let version = get_http_version(resp_line.version)?;
-
-          response = response
-              .status(status_code)
-              .version(version);
-      },
-      Err(_) => {}
+  if reader.read_line(&mut line).is_ok() {
+      let resp_line: ResponseLine = match parse_response_line(line.as_str()) {
+        Ok(parsed_line) => parsed_line,
+        Err(_) => {
+            let msg = "Malformed first line of response".to_string();
+            return Err( FlaskError::BadRequest(msg) );
+        }
+      };
+
+      let status_code_bytes = resp_line.status_code.as_bytes();
+      let status_code = match StatusCode::from_bytes(status_code_bytes) {
+          Ok(_status_code) => _status_code,
+          Err(parse_err) => {
+              eprintln!("ERROR in response.rs calling StatusCode::from_bytes");
+              let msg: String = parse_err.to_string();
+              let flask_err = FlaskError::BadRequest(msg);
+              return Err(flask_err);
+          }
+      };
+      let version = get_http_version(resp_line.version)?;
+
+      response = response
+          .status(status_code)
+          .version(version);
   }
   Ok(response)
 }
 
-fn _read_http_response(reader: &mut BufReader<TcpStream>) -> Result<Response<Vec<u8>>, FlaskError> {
+fn _read_http_response<R: BufRead>(reader: &mut R, request_method: Option<&Method>) -> Result<Response<Vec<u8>>, FlaskError> {
   let mut response = _read_initial_request_line(reader)?;
 
-  let content_length = {
-      let mut content_length_mut = 0;
-      loop {
-          let line: String = read_buffered_line(reader)?;
-          if line.as_str() == "\r\n" {
-              break;
-          }
+  let mut content_length: Option<usize> = None;
+  let mut chunked = false;
+  loop {
+      let line: String = read_buffered_line(reader)?;
+      if line.as_str() == "\r\n" {
+          break;
+      }
 
-          let header_line = match read_header(line.as_str()) {
-            Ok(hl) => hl,
-            Err(err) => {
-                return Err( err );
-            }
-        };
-          // println!("{:?}", line.as_bytes());
-
-          if header_line.key.to_lowercase() == CONTENT_LENGTH_HEADER {
-              match header_line.value.parse::<usize>() {
-                  Ok(val) => content_length_mut = val,
-                  Err(_) => {
-                      let msg = format!("Invalid Content-Length: {}", header_line.value);
-                      return Err( FlaskError::BadRequest(msg) );
-                  }
+      let header_line = match read_header(line.as_str()) {
+        Ok(hl) => hl,
+        Err(err) => {
+            return Err( err );
+        }
+      };
+
+      let key = header_line.key.to_lowercase();
+      if key == CONTENT_LENGTH_HEADER {
+          match header_line.value.parse::<usize>() {
+              Ok(val) => content_length = Some(val),
+              Err(_) => {
+                  let msg = format!("Invalid Content-Length: {}", header_line.value);
+                  return Err( FlaskError::BadRequest(msg) );
               }
           }
-          // println!("Key => {}", elem.key);
-          response = response.header(header_line.key, header_line.value);
+      } else if key == TRANSFER_ENCODING_HEADER {
+          chunked = is_chunked(header_line.value);
       }
-      content_length_mut
-  };
-
+      response = response.header(header_line.key, header_line.value);
+  }
 
-  let mut body = vec![0; content_length];
-  match reader.read_exact(&mut body) {
-      Ok(_) => {
-          match response.body(body) {
-              Ok(req) => Ok(req),
-              Err(http_err) => {
-                  eprintln!("ERROR reading response body from stream");
-                  let msg: String = http_err.to_string();
-                  let flask_err = FlaskError::ClientClosedRequest(msg);
-                  Err(flask_err)
-              }
-          }
-      },
+  let mut resp = match response.body(Vec::new()) {
+      Ok(resp) => resp,
       Err(http_err) => {
+          eprintln!("ERROR reading response body from stream");
           let msg: String = http_err.to_string();
-          let flask_err = FlaskError::BadRequest(msg);
-          Err(flask_err) 
+          let flask_err = FlaskError::ClientClosedRequest(msg);
+          return Err(flask_err);
       }
+  };
+  let framing = response_framing(resp.status(), request_method, chunked, content_length);
+  *resp.body_mut() = read_body(reader, framing)?;
+  if framing == BodyFraming::Chunked {
+      let body_len = resp.body().len();
+      unchunk_headers(resp.headers_mut(), body_len);
   }
+  Ok(resp)
+}
+
+// RFC 9112 section 6.3: Transfer-Encoding beats Content-Length, and a response
+// with neither runs until the server closes the connection
+pub(crate) fn response_framing(status: StatusCode, request_method: Option<&Method>, chunked: bool, content_length: Option<usize>) -> BodyFraming {
+    if request_method == Some(&Method::HEAD) || !status_allows_body(status) {
+        return BodyFraming::Empty;
+    }
+    if request_method == Some(&Method::CONNECT) && status.is_success() {
+        return BodyFraming::Empty;
+    }
+    match (chunked, content_length) {
+        (true, _) => BodyFraming::Chunked,
+        (false, Some(len)) => BodyFraming::Length(len),
+        (false, None) => BodyFraming::UntilClose,
+    }
 }
 
 pub fn read_http_response(stream: TcpStream) -> Result<Response<Vec<u8>>, FlaskError> {
     let mut reader: BufReader<TcpStream> = BufReader::new(stream);
-    _read_http_response(&mut reader)
+    _read_http_response(&mut reader, None)
+}
+
+// 1xx, 204 and 304 responses never carry a body, so they get neither a Content-Length nor
+// the bytes a handler may have left in the body
+fn status_allows_body(status: StatusCode) -> bool {
+    !(status.is_informational() || status == StatusCode::NO_CONTENT || status == StatusCode::NOT_MODIFIED)
+}
+
+pub fn write_http_response<W: Write>(stream: &mut W, resp: &Response<Vec<u8>>) -> Result<(), FlaskError> {
+    let version = match resp.version() {
+        Version::HTTP_10 => "HTTP/1.0",
+        _ => "HTTP/1.1"
+    };
+    let status = resp.status();
+    let reason = status.canonical_reason().unwrap_or("");
+    let mut head = format!("{} {} {}\r\n", version, status.as_str(), reason);
+
+    let headers = resp.headers();
+    for (key, value) in headers.iter() {
+        let value = match value.to_str() {
+            Ok(val) => val,
+            Err(_) => {
+                let msg = format!("Invalid value for header {}", key);
+                return Err( FlaskError::InternalServerError(msg) );
+            }
+        };
+        head.push_str(&format!("{}: {}\r\n", key, value));
+    }
+    let body = resp.body();
+    let has_framing = headers.contains_key(http::header::CONTENT_LENGTH) || headers.contains_key(http::header::TRANSFER_ENCODING);
+    if status_allows_body(status) && !has_framing {
+        head.push_str(&format!("{}: {}\r\n", CONTENT_LENGTH_HEADER, body.len()));
+    }
+    head.push_str("\r\n");
+
+    let mut payload = head.into_bytes();
+    if status_allows_body(status) {
+        payload.extend_from_slice(body);
+    }
+    match stream.write_all(&payload).and_then(|_| stream.flush()) {
+        Ok(_) => Ok(()),
+        Err(io_err) => {
+            let msg = format!("Error writing response to stream: {}", io_err);
+            Err( FlaskError::ClientClosedRequest(msg) )
+        }
+    }
+}
+
+/// Read one response from a caller owned reader, leaving the reader usable.
+pub fn read_http_response_from<R: BufRead>(reader: &mut R) -> Result<Response<Vec<u8>>, FlaskError> {
+    _read_http_response(reader, None)
+}
+
+/// Read the response to a request made with `request_method`, which decides
+/// whether a body follows (HEAD responses and 2xx answers to CONNECT have none).
+/// Use this to read several responses off one connection.
+pub fn read_http_response_for<R: BufRead>(reader: &mut R, request_method: &Method) -> Result<Response<Vec<u8>>, FlaskError> {
+    _read_http_response(reader, Some(request_method))
 }
 
 
@@ -159,6 +227,7 @@ pub fn read_http_response(stream: TcpStream) -> Result<Response<Vec<u8>>, FlaskE
 // test cases go below here
 //#################################################################################################################
 #[cfg(test)]
+#[allow(clippy::clone_on_copy, clippy::bool_assert_comparison, noop_method_call)]
 mod tests {
     // Test creating an http Response from the mockito response
     extern crate rand;
@@ -477,4 +546,103 @@ mod tests {
       // without the /r the parser doesn't know when the status message ends
       assert_eq!(flask_err.get_msg(), "Malformed Response Line: no terminating CRLF");
     }
+
+    #[test]
+    fn test_chunked_response() {
+        let mut s = mockito::Server::new();
+        let _mock = s.mock("GET", "/chunks")
+            .with_chunked_body(|w| { w.write_all(b"first ")?; w.write_all(b"second") })
+            .create();
+
+        let mut stream = TcpStream::connect(s.host_with_port()).unwrap();
+        stream.write_all("GET /chunks HTTP/1.1\r\n\r\n".as_bytes()).unwrap();
+
+        let resp = read_http_response(stream).unwrap();
+        assert!(!resp.headers().contains_key("transfer-encoding"));
+        assert_eq!(resp.headers()["content-length"], "12");
+        assert_eq!(resp.body(), b"first second");
+
+        _mock.assert();
+    }
+
+    #[test]
+    fn test_response_framing() {
+        let head = Method::HEAD;
+        assert_eq!(response_framing(StatusCode::OK, Some(&head), false, Some(10)), BodyFraming::Empty);
+        assert_eq!(response_framing(StatusCode::NO_CONTENT, None, false, None), BodyFraming::Empty);
+        assert_eq!(response_framing(StatusCode::NOT_MODIFIED, None, false, Some(10)), BodyFraming::Empty);
+        assert_eq!(response_framing(StatusCode::OK, None, true, Some(10)), BodyFraming::Chunked);
+        assert_eq!(response_framing(StatusCode::OK, None, false, Some(10)), BodyFraming::Length(10));
+        assert_eq!(response_framing(StatusCode::OK, None, false, None), BodyFraming::UntilClose);
+    }
+
+    #[test]
+    fn test_head_response_with_content_length_has_no_body() {
+        let raw = "HTTP/1.1 200 OK\r\ncontent-length: 42\r\n\r\nHTTP/1.1 204 No Content\r\n\r\n";
+        let mut reader = BufReader::new(raw.as_bytes());
+        let resp = read_http_response_for(&mut reader, &Method::HEAD).unwrap();
+        assert!(resp.body().is_empty());
+        let next = read_http_response_for(&mut reader, &Method::GET).unwrap();
+        assert_eq!(next.status(), StatusCode::NO_CONTENT);
+    }
+
+    #[test]
+    fn test_chunked_response_round_trip() {
+        let raw = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nTrailer: Expires\r\n\r\n4\r\nWiki\r\n0\r\nExpires: never\r\n\r\n";
+        let resp = read_http_response_from(&mut BufReader::new(raw.as_bytes())).unwrap();
+
+        let mut out: Vec<u8> = Vec::new();
+        write_http_response(&mut out, &resp).unwrap();
+        assert_eq!(out, b"HTTP/1.1 200 OK\r\ncontent-length: 4\r\n\r\nWiki");
+
+        let again = read_http_response_from(&mut BufReader::new(out.as_slice())).unwrap();
+        assert_eq!(again.body(), b"Wiki");
+    }
+
+    #[test]
+    fn test_write_http_response_round_trip() {
+        let resp = Response::builder()
+            .status(404)
+            .header("X-Reason", "missing")
+            .body(b"not here".to_vec())
+            .unwrap();
+
+        let mut out: Vec<u8> = Vec::new();
+        write_http_response(&mut out, &resp).unwrap();
+        assert!(out.starts_with(b"HTTP/1.1 404 Not Found\r\n"));
+
+        let mut reader = BufReader::new(out.as_slice());
+        let parsed = read_http_response_from(&mut reader).unwrap();
+        assert_eq!(parsed.status(), StatusCode::NOT_FOUND);
+        assert_eq!(parsed.headers()["x-reason"], "missing");
+        assert_eq!(parsed.body(), b"not here");
+    }
+
+    #[test]
+    fn test_write_http_response_no_content_length_on_101() {
+        let resp = Response::builder()
+            .status(101)
+            .header("Upgrade", "websocket")
+            .body(Vec::new())
+            .unwrap();
+
+        let mut out: Vec<u8> = Vec::new();
+        write_http_response(&mut out, &resp).unwrap();
+        assert_eq!(out, b"HTTP/1.1 101 Switching Protocols\r\nupgrade: websocket\r\n\r\n");
+    }
+
+    #[test]
+    fn test_write_http_response_drops_body_of_204_and_304() {
+        for status in [204, 304] {
+            let resp = Response::builder()
+                .status(status)
+                .body(b"stray".to_vec())
+                .unwrap();
+
+            let mut out: Vec<u8> = Vec::new();
+            write_http_response(&mut out, &resp).unwrap();
+            assert!(out.ends_with(b"\r\n\r\n"));
+            assert!(!out.windows(5).any(|window| window == b"stray"));
+        }
+    }
 }
@@ -0,0 +1,682 @@
+use super::{
+    blueprint::Blueprint,
+    errors::FlaskError,
+    percent::{percent_decode, percent_encode},
+    query::QueryBuilder,
+    server::Handler
+};
+
+use http::{header, HeaderValue, Method, Request, Response};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::Arc;
+
+// ***************************************************************************
+// converters
+// ***************************************************************************
+
+/// Decides which values a `<converter:name>` placeholder matches. Values are
+/// percent-decoded before they are offered to the converter.
+pub trait Converter: Send + Sync + 'static {
+    fn accepts(&self, value: &str) -> bool;
+
+    /// Whether the value may contain `/` and so span several path segments.
+    fn spans_segments(&self) -> bool {
+        false
+    }
+}
+
+impl<F> Converter for F
+where
+    F: Fn(&str) -> bool + Send + Sync + 'static
+{
+    fn accepts(&self, value: &str) -> bool {
+        self(value)
+    }
+}
+
+struct StringConverter;
+
+impl Converter for StringConverter {
+    fn accepts(&self, value: &str) -> bool {
+        !value.is_empty()
+    }
+}
+
+struct IntConverter;
+
+impl Converter for IntConverter {
+    fn accepts(&self, value: &str) -> bool {
+        !value.is_empty() && value.bytes().all(|byte| byte.is_ascii_digit()) && value.parse::<u64>().is_ok()
+    }
+}
+
+struct UuidConverter;
+
+impl Converter for UuidConverter {
+    fn accepts(&self, value: &str) -> bool {
+        let groups: Vec<&str> = value.split('-').collect();
+        let lengths = [8, 4, 4, 4, 12];
+        groups.len() == lengths.len()
+            && groups.iter().zip(lengths.iter()).all(|(group, len)| {
+                group.len() == *len && group.bytes().all(|byte| byte.is_ascii_hexdigit())
+            })
+    }
+}
+
+struct PathConverter;
+
+impl Converter for PathConverter {
+    fn accepts(&self, value: &str) -> bool {
+        !value.is_empty()
+    }
+
+    fn spans_segments(&self) -> bool {
+        true
+    }
+}
+
+// ***************************************************************************
+// route patterns
+// ***************************************************************************
+
+/// The values captured from the request path, stored in the request extensions
+/// of every request a `Router` dispatches.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PathParams {
+    params: Vec<(String, String)>,
+}
+
+impl PathParams {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.params.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+
+    /// The named value parsed as `T`, e.g. `params.parse::<u32>("id")`.
+    pub fn parse<T: FromStr>(&self, name: &str) -> Option<T> {
+        self.get(name)?.parse().ok()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.params.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.params.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.params.is_empty()
+    }
+}
+
+#[derive(Clone)]
+enum Segment {
+    Static(String),
+    Param { name: String, converter: Arc<dyn Converter> },
+}
+
+impl Segment {
+    // static segments win over placeholders, which win over multi-segment placeholders
+    fn rank(&self) -> u8 {
+        match self {
+            Segment::Static(_) => 0,
+            Segment::Param { converter, .. } if converter.spans_segments() => 2,
+            Segment::Param { .. } => 1
+        }
+    }
+}
+
+fn parse_pattern(pattern: &str, converters: &HashMap<String, Arc<dyn Converter>>) -> Result<Vec<Segment>, String> {
+    let rest = match pattern.strip_prefix('/') {
+        Some(rest) => rest,
+        None => return Err( format!("Route pattern {} must start with '/'", pattern) )
+    };
+    if rest.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut names: Vec<&str> = Vec::new();
+    let mut segments = Vec::new();
+    for part in rest.split('/') {
+        let placeholder = match part.strip_prefix('<').and_then(|part| part.strip_suffix('>')) {
+            Some(placeholder) => placeholder,
+            None if part.contains('<') || part.contains('>') => {
+                return Err( format!("Route pattern {} mixes text and a placeholder in one segment", pattern) );
+            },
+            None => {
+                segments.push(Segment::Static(part.to_string()));
+                continue;
+            }
+        };
+        let (converter_name, name) = placeholder.split_once(':').unwrap_or(("string", placeholder));
+        if name.is_empty() || names.contains(&name) {
+            return Err( format!("Route pattern {} has an empty or repeated placeholder name", pattern) );
+        }
+        let converter = match converters.get(converter_name) {
+            Some(converter) => converter.clone(),
+            None => return Err( format!("Route pattern {} uses unknown converter {}", pattern, converter_name) )
+        };
+        names.push(name);
+        segments.push(Segment::Param { name: name.to_string(), converter });
+    }
+    Ok(segments)
+}
+
+// a `%2F` decodes to `/`, which only multi-segment placeholders may hold
+fn converter_accepts(converter: &Arc<dyn Converter>, value: &str) -> bool {
+    (converter.spans_segments() || !value.contains('/')) && converter.accepts(value)
+}
+
+// match decoded path parts against the pattern, backtracking over multi-segment placeholders
+fn match_segments(segments: &[Segment], parts: &[String], params: &mut Vec<(String, String)>) -> bool {
+    let (segment, remaining) = match segments.split_first() {
+        Some(split) => split,
+        None => return parts.is_empty()
+    };
+    match segment {
+        Segment::Static(text) => {
+            !parts.is_empty() && parts[0] == *text && match_segments(remaining, &parts[1..], params)
+        },
+        Segment::Param { name, converter } => {
+            let max_parts = match converter.spans_segments() {
+                true => parts.len(),
+                false => parts.len().min(1)
+            };
+            for taken in (1..=max_parts).rev() {
+                let value = parts[..taken].join("/");
+                if !converter_accepts(converter, &value) {
+                    continue;
+                }
+                params.push((name.clone(), value));
+                if match_segments(remaining, &parts[taken..], params) {
+                    return true;
+                }
+                params.pop();
+            }
+            false
+        }
+    }
+}
+
+// ***************************************************************************
+// reverse routing
+// ***************************************************************************
+
+// characters a path segment may carry unescaped (RFC 3986 pchar), minus `/`
+const SEGMENT_SAFE: &[u8] = b"!$&'()*+,;=:@";
+
+/// The named routes of a router, for building URLs with `url_for`. Every request
+/// a `Router` dispatches carries a copy in its extensions, so handlers can build
+/// links and redirect targets without hard-coding paths.
+#[derive(Clone, Default)]
+pub struct UrlMap {
+    routes: Arc<HashMap<String, Vec<Segment>>>,
+}
+
+impl UrlMap {
+    /// Build the path of the route called `name`. Parameters not used by the
+    /// pattern are appended as a query string, in the order given.
+    ///
+    /// Fails with `InternalServerError` when the route is unknown, a parameter is
+    /// missing, or a value is rejected by the placeholder's converter.
+    pub fn url_for(&self, name: &str, params: &[(&str, &dyn Display)]) -> Result<String, FlaskError> {
+        let segments = match self.routes.get(name) {
+            Some(segments) => segments,
+            None => return Err( FlaskError::InternalServerError(format!("url_for: no route named {}", name)) )
+        };
+
+        let mut path = String::new();
+        let mut used: Vec<&str> = Vec::new();
+        for segment in segments.iter() {
+            path.push('/');
+            match segment {
+                Segment::Static(text) => path.push_str(&percent_encode(text, SEGMENT_SAFE)),
+                Segment::Param { name: param, converter } => {
+                    let value = match params.iter().find(|(key, _)| key == param) {
+                        Some((_, value)) => value.to_string(),
+                        None => {
+                            let msg = format!("url_for: route {} needs parameter {}", name, param);
+                            return Err( FlaskError::InternalServerError(msg) );
+                        }
+                    };
+                    if !converter_accepts(converter, &value) {
+                        let msg = format!("url_for: invalid value {:?} for parameter {} of route {}", value, param, name);
+                        return Err( FlaskError::InternalServerError(msg) );
+                    }
+                    let encoded = match converter.spans_segments() {
+                        true => value.split('/').map(|part| percent_encode(part, SEGMENT_SAFE)).collect::<Vec<String>>().join("/"),
+                        false => percent_encode(&value, SEGMENT_SAFE)
+                    };
+                    path.push_str(&encoded);
+                    used.push(param);
+                }
+            }
+        }
+        if path.is_empty() {
+            path.push('/');
+        }
+
+        let query = params.iter()
+            .filter(|(key, _)| !used.contains(key))
+            .fold(QueryBuilder::new(), |query, (key, value)| query.append(key, &value.to_string()))
+            .build();
+        if !query.is_empty() {
+            path.push('?');
+            path.push_str(&query);
+        }
+        Ok(path)
+    }
+}
+
+// the request's host name without port, from the URI or else the Host header
+fn request_host<B>(req: &Request<B>) -> Option<String> {
+    if let Some(host) = req.uri().host() {
+        return Some(host.to_ascii_lowercase());
+    }
+    let host = req.headers().get(header::HOST)?.to_str().ok()?;
+    let host = match host.rfind(':') {
+        Some(idx) if !host.ends_with(']') => &host[..idx],
+        _ => host
+    };
+    Some(host.to_ascii_lowercase())
+}
+
+struct Route {
+    segments: Vec<Segment>,
+    methods: Vec<Method>,
+    host: Option<String>,
+    handler: Arc<dyn Handler>,
+}
+
+impl Route {
+    fn allows(&self, method: &Method) -> bool {
+        self.methods.contains(method) || (method == Method::HEAD && self.methods.contains(&Method::GET))
+    }
+
+    fn matches_host(&self, host: Option<&str>) -> bool {
+        match &self.host {
+            Some(expected) => host == Some(expected.as_str()),
+            None => true
+        }
+    }
+
+    // host-bound routes are tried before routes for any host
+    fn rank(&self) -> Vec<u8> {
+        let host_rank = match self.host {
+            Some(_) => 0,
+            None => 1
+        };
+        std::iter::once(host_rank).chain(self.segments.iter().map(Segment::rank)).collect()
+    }
+}
+
+// ***************************************************************************
+// router
+// ***************************************************************************
+
+// HEAD comes with GET, and OPTIONS is always answered
+fn allow_header(mut methods: Vec<Method>) -> HeaderValue {
+    if methods.contains(&Method::GET) {
+        methods.push(Method::HEAD);
+    }
+    methods.push(Method::OPTIONS);
+    let mut names: Vec<&str> = Vec::new();
+    for method in methods.iter() {
+        if !names.contains(&method.as_str()) {
+            names.push(method.as_str());
+        }
+    }
+    // method names are tokens, so this cannot fail
+    HeaderValue::from_str(&names.join(", ")).unwrap_or_else(|_| HeaderValue::from_static("OPTIONS"))
+}
+
+/// Dispatches requests by path pattern and method, Flask style. Patterns look
+/// like `/users/<int:id>/posts/<slug>`; a placeholder without a converter uses
+/// `string`, which matches one non-empty segment. The built-in converters are
+/// `string`, `int`, `uuid` and `path` (any number of segments).
+///
+/// Static segments take precedence over placeholders, so `/users/me` wins over
+/// `/users/<name>` whatever the registration order.
+pub struct Router {
+    routes: Vec<Route>,
+    converters: HashMap<String, Arc<dyn Converter>>,
+    url_map: UrlMap,
+    last_added: Option<usize>,
+}
+
+impl Default for Router {
+    fn default() -> Router {
+        Router::new()
+    }
+}
+
+impl Router {
+    pub fn new() -> Router {
+        let mut converters: HashMap<String, Arc<dyn Converter>> = HashMap::new();
+        converters.insert("string".to_string(), Arc::new(StringConverter));
+        converters.insert("int".to_string(), Arc::new(IntConverter));
+        converters.insert("uuid".to_string(), Arc::new(UuidConverter));
+        converters.insert("path".to_string(), Arc::new(PathConverter));
+        Router { routes: Vec::new(), converters, url_map: UrlMap::default(), last_added: None }
+    }
+
+    /// Register a custom converter for use in patterns added after this call.
+    pub fn converter<C: Converter>(mut self, name: &str, converter: C) -> Router {
+        self.converters.insert(name.to_string(), Arc::new(converter));
+        self
+    }
+
+    /// Route requests for `pattern` with any of `methods` to `handler`.
+    ///
+    /// # Panics
+    /// When the pattern is malformed or names an unknown converter, since that
+    /// is a bug in the application rather than a runtime condition.
+    pub fn route<H: Handler>(self, methods: &[Method], pattern: &str, handler: H) -> Router {
+        self.add_route(methods, pattern, None, Arc::new(handler))
+    }
+
+    pub(crate) fn add_route(mut self, methods: &[Method], pattern: &str, host: Option<String>, handler: Arc<dyn Handler>) -> Router {
+        let segments = match parse_pattern(pattern, &self.converters) {
+            Ok(segments) => segments,
+            Err(msg) => panic!("{}", msg)
+        };
+        let host = host.map(|host| host.to_ascii_lowercase());
+        let route = Route { segments, methods: methods.to_vec(), host, handler };
+        let rank = route.rank();
+        let position = self.routes.iter().position(|existing| existing.rank() > rank).unwrap_or(self.routes.len());
+        self.routes.insert(position, route);
+        self.last_added = Some(position);
+        self
+    }
+
+    /// Name the route added last, so `url_for` can build its URL.
+    ///
+    /// # Panics
+    /// When no route was added yet or the name is already taken.
+    pub fn name(mut self, name: &str) -> Router {
+        let route = match self.last_added {
+            Some(position) => &self.routes[position],
+            None => panic!("Router::name({}) called before any route was added", name)
+        };
+        let routes = Arc::make_mut(&mut self.url_map.routes);
+        if routes.contains_key(name) {
+            panic!("Route name {} is already taken", name);
+        }
+        routes.insert(name.to_string(), route.segments.clone());
+        self
+    }
+
+    /// Mount the routes of `blueprint` under its prefix.
+    pub fn blueprint(self, blueprint: Blueprint) -> Router {
+        blueprint.mount(self)
+    }
+
+    pub fn url_for(&self, name: &str, params: &[(&str, &dyn Display)]) -> Result<String, FlaskError> {
+        self.url_map.url_for(name, params)
+    }
+
+    pub fn url_map(&self) -> &UrlMap {
+        &self.url_map
+    }
+
+    pub fn get<H: Handler>(self, pattern: &str, handler: H) -> Router {
+        self.route(&[Method::GET], pattern, handler)
+    }
+
+    pub fn post<H: Handler>(self, pattern: &str, handler: H) -> Router {
+        self.route(&[Method::POST], pattern, handler)
+    }
+
+    pub fn put<H: Handler>(self, pattern: &str, handler: H) -> Router {
+        self.route(&[Method::PUT], pattern, handler)
+    }
+
+    pub fn patch<H: Handler>(self, pattern: &str, handler: H) -> Router {
+        self.route(&[Method::PATCH], pattern, handler)
+    }
+
+    pub fn delete<H: Handler>(self, pattern: &str, handler: H) -> Router {
+        self.route(&[Method::DELETE], pattern, handler)
+    }
+}
+
+impl Handler for Router {
+    fn handle(&self, mut req: Request<Vec<u8>>) -> Response<Vec<u8>> {
+        let path = req.uri().path().to_string();
+        let parts: Option<Vec<String>> = match path.strip_prefix('/') {
+            Some("") => Some(Vec::new()),
+            Some(rest) => rest.split('/').map(percent_decode).collect(),
+            None => None
+        };
+        let parts = match parts {
+            Some(parts) => parts,
+            None => return FlaskError::NotFound(format!("No route matches {}", path)).to_response()
+        };
+
+        let host = request_host(&req);
+        let mut allowed: Vec<Method> = Vec::new();
+        for route in self.routes.iter() {
+            let mut params = Vec::new();
+            if !route.matches_host(host.as_deref()) || !match_segments(&route.segments, &parts, &mut params) {
+                continue;
+            }
+            if route.allows(req.method()) {
+                req.extensions_mut().insert(PathParams { params });
+                req.extensions_mut().insert(self.url_map.clone());
+                return route.handler.handle(req);
+            }
+            allowed.extend(route.methods.iter().cloned());
+        }
+
+        if allowed.is_empty() {
+            return FlaskError::NotFound(format!("No route matches {}", path)).to_response();
+        }
+        let mut resp = match *req.method() {
+            Method::OPTIONS => Response::new(Vec::new()),
+            _ => FlaskError::MethodNotAllowed(format!("Method {} is not allowed for {}", req.method(), path)).to_response()
+        };
+        resp.headers_mut().insert(header::ALLOW, allow_header(allowed));
+        resp
+    }
+}
+
+
+//#################################################################################################################
+// test cases go below here
+//#################################################################################################################
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::StatusCode;
+
+    // responds with the captured params as `name=value` pairs
+    fn show_params(req: Request<Vec<u8>>) -> Response<Vec<u8>> {
+        let params = req.extensions().get::<PathParams>().unwrap();
+        let pairs: Vec<String> = params.iter().map(|(name, value)| format!("{}={}", name, value)).collect();
+        Response::new(pairs.join("&").into_bytes())
+    }
+
+    fn call(router: &Router, method: Method, path: &str) -> Response<Vec<u8>> {
+        router.handle(Request::builder().method(method).uri(path).body(Vec::new()).unwrap())
+    }
+
+    fn body(resp: &Response<Vec<u8>>) -> &str {
+        std::str::from_utf8(resp.body()).unwrap()
+    }
+
+    #[test]
+    fn test_params_and_converters() {
+        let router = Router::new()
+            .get("/users/<int:id>/posts/<slug>", show_params)
+            .get("/items/<uuid:item>", show_params)
+            .get("/files/<path:file>/raw", show_params)
+            .get("/", show_params);
+
+        assert_eq!(body(&call(&router, Method::GET, "/users/42/posts/hello-world")), "id=42&slug=hello-world");
+        assert_eq!(body(&call(&router, Method::GET, "/items/123e4567-e89b-12d3-a456-426614174000")), "item=123e4567-e89b-12d3-a456-426614174000");
+        assert_eq!(body(&call(&router, Method::GET, "/files/a/b/c.txt/raw")), "file=a/b/c.txt");
+        assert_eq!(call(&router, Method::GET, "/").status(), StatusCode::OK);
+
+        assert_eq!(call(&router, Method::GET, "/users/abc/posts/x").status(), StatusCode::NOT_FOUND);
+        assert_eq!(call(&router, Method::GET, "/items/not-a-uuid").status(), StatusCode::NOT_FOUND);
+        assert_eq!(call(&router, Method::GET, "/files/raw").status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_typed_param_access() {
+        let router = Router::new().get("/users/<int:id>", |req: Request<Vec<u8>>| {
+            let id: u32 = req.extensions().get::<PathParams>().unwrap().parse("id").unwrap();
+            Response::new((id + 1).to_string().into_bytes())
+        });
+        assert_eq!(body(&call(&router, Method::GET, "/users/41")), "42");
+    }
+
+    #[test]
+    fn test_custom_converter_and_decoding() {
+        let router = Router::new()
+            .converter("lang", |value: &str| value == "en" || value == "fr")
+            .get("/<lang:lang>/pages/<title>", show_params);
+
+        assert_eq!(body(&call(&router, Method::GET, "/fr/pages/caf%C3%A9%20noir")), "lang=fr&title=café noir");
+        assert_eq!(call(&router, Method::GET, "/de/pages/x").status(), StatusCode::NOT_FOUND);
+        assert_eq!(call(&router, Method::GET, "/en/pages/bad%zz").status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_encoded_slash_stays_in_path_placeholders() {
+        let router = Router::new()
+            .get("/pages/<title>", show_params)
+            .get("/files/<path:file>", show_params);
+
+        assert_eq!(call(&router, Method::GET, "/pages/a%2Fb").status(), StatusCode::NOT_FOUND);
+        assert_eq!(call(&router, Method::GET, "/pages/a%2fb").status(), StatusCode::NOT_FOUND);
+        assert_eq!(body(&call(&router, Method::GET, "/files/a%2Fb/c")), "file=a/b/c");
+
+        let router = url_router();
+        assert!(router.url_for("post", &[("id", &1), ("slug", &"a/b")]).is_err());
+    }
+
+    #[test]
+    fn test_static_segments_take_precedence() {
+        let router = Router::new()
+            .get("/users/<name>", show_params)
+            .get("/users/me", |_req: Request<Vec<u8>>| Response::new(b"me".to_vec()));
+
+        assert_eq!(body(&call(&router, Method::GET, "/users/me")), "me");
+        assert_eq!(body(&call(&router, Method::GET, "/users/bob")), "name=bob");
+    }
+
+    #[test]
+    fn test_method_dispatch_and_405() {
+        let router = Router::new()
+            .get("/things", |_req: Request<Vec<u8>>| Response::new(b"list".to_vec()))
+            .post("/things", |_req: Request<Vec<u8>>| Response::new(b"create".to_vec()));
+
+        assert_eq!(body(&call(&router, Method::GET, "/things")), "list");
+        assert_eq!(body(&call(&router, Method::POST, "/things")), "create");
+        assert_eq!(body(&call(&router, Method::HEAD, "/things")), "list");
+
+        let resp = call(&router, Method::DELETE, "/things");
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(resp.headers()[header::ALLOW], "GET, POST, HEAD, OPTIONS");
+
+        let resp = call(&router, Method::OPTIONS, "/things");
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::ALLOW], "GET, POST, HEAD, OPTIONS");
+
+        assert_eq!(call(&router, Method::GET, "/nothing").status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_router_errors_reach_error_handlers() {
+        use crate::httpx::error_handlers::ErrorHandlers;
+
+        let router = Router::new().get("/things", |_req: Request<Vec<u8>>| Response::new(b"list".to_vec()));
+        let not_found = router.handle(Request::get("/nothing").body(Vec::new()).unwrap());
+        assert!(matches!(not_found.extensions().get::<FlaskError>(), Some(FlaskError::NotFound(_))));
+
+        let handlers = ErrorHandlers::new(router)
+            .on_error(FlaskError::NotFound, |mut resp: Response<Vec<u8>>| {
+                *resp.body_mut() = b"custom 404".to_vec();
+                resp
+            })
+            .on_error(FlaskError::MethodNotAllowed, |mut resp: Response<Vec<u8>>| {
+                *resp.body_mut() = b"custom 405".to_vec();
+                resp
+            });
+        assert_eq!(handlers.handle(Request::get("/nothing").body(Vec::new()).unwrap()).body(), b"custom 404");
+        let resp = handlers.handle(Request::delete("/things").body(Vec::new()).unwrap());
+        assert_eq!(resp.body(), b"custom 405");
+        assert_eq!(resp.headers()[header::ALLOW], "GET, HEAD, OPTIONS");
+    }
+
+    fn url_router() -> Router {
+        Router::new()
+            .get("/", show_params).name("index")
+            .get("/users/<int:id>/posts/<slug>", show_params).name("post")
+            .get("/files/<path:file>", show_params).name("file")
+    }
+
+    #[test]
+    fn test_url_for() {
+        let router = url_router();
+        assert_eq!(router.url_for("index", &[]).unwrap(), "/");
+        assert_eq!(router.url_for("post", &[("id", &7), ("slug", &"hello world")]).unwrap(), "/users/7/posts/hello%20world");
+        assert_eq!(router.url_for("file", &[("file", &"docs/a b.txt")]).unwrap(), "/files/docs/a%20b.txt");
+        assert_eq!(
+            router.url_for("post", &[("slug", &"x"), ("page", &2), ("q", &"a&b"), ("id", &1)]).unwrap(),
+            "/users/1/posts/x?page=2&q=a%26b"
+        );
+    }
+
+    #[test]
+    fn test_url_for_round_trips_through_dispatch() {
+        let router = url_router();
+        let url = router.url_for("post", &[("id", &3), ("slug", &"café & ü?")]).unwrap();
+        assert_eq!(body(&call(&router, Method::GET, &url)), "id=3&slug=café & ü?");
+    }
+
+    #[test]
+    fn test_url_for_errors() {
+        let router = url_router();
+        assert_eq!(router.url_for("missing", &[]).err().unwrap().get_msg(), "url_for: no route named missing");
+        assert_eq!(router.url_for("post", &[("id", &1)]).err().unwrap().get_msg(), "url_for: route post needs parameter slug");
+        assert_eq!(
+            router.url_for("post", &[("id", &"abc"), ("slug", &"x")]).err().unwrap().get_msg(),
+            "url_for: invalid value \"abc\" for parameter id of route post"
+        );
+    }
+
+    #[test]
+    fn test_url_map_in_request_extensions() {
+        let router = Router::new()
+            .get("/login", |_req: Request<Vec<u8>>| Response::new(Vec::new())).name("login")
+            .get("/old", |req: Request<Vec<u8>>| {
+                let location = req.extensions().get::<UrlMap>().unwrap().url_for("login", &[("next", &"/old")]).unwrap();
+                Response::builder().status(StatusCode::FOUND).header(header::LOCATION, location).body(Vec::new()).unwrap()
+            });
+        assert_eq!(call(&router, Method::GET, "/old").headers()[header::LOCATION], "/login?next=%2Fold");
+    }
+
+    #[test]
+    #[should_panic(expected = "Route name index is already taken")]
+    fn test_duplicate_route_name_panics() {
+        let _ = url_router().get("/home", show_params).name("index");
+    }
+
+    #[test]
+    #[should_panic(expected = "unknown converter float")]
+    fn test_unknown_converter_panics() {
+        let _ = Router::new().get("/price/<float:amount>", show_params);
+    }
+
+    #[test]
+    fn test_pattern_errors() {
+        let converters = Router::new().converters;
+        assert!(parse_pattern("users", &converters).is_err());
+        assert!(parse_pattern("/users/id<int:id>", &converters).is_err());
+        assert!(parse_pattern("/<a>/<a>", &converters).is_err());
+    }
+}
@@ -0,0 +1,539 @@
+use super::{
+    errors::FlaskError,
+    header_has_token,
+    read_http_request_from,
+    write_http_response
+};
+
+use http::{header, HeaderValue, Method, Request, Response, StatusCode};
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DEFAULT_WORKERS: usize = 8;
+const DEFAULT_QUEUE_SIZE: usize = 64;
+const DEFAULT_KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_REQUESTS_PER_CONNECTION: usize = 1000;
+// how often blocked accept and idle keep-alive waits look at the shutdown flag
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Turns a request into a response. Implemented for any matching closure.
+pub trait Handler: Send + Sync + 'static {
+    fn handle(&self, req: Request<Vec<u8>>) -> Response<Vec<u8>>;
+}
+
+impl<F> Handler for F
+where
+    F: Fn(Request<Vec<u8>>) -> Response<Vec<u8>> + Send + Sync + 'static
+{
+    fn handle(&self, req: Request<Vec<u8>>) -> Response<Vec<u8>> {
+        self(req)
+    }
+}
+
+/// Stops a running server. The server finishes the requests it already read,
+/// closes idle keep-alive connections and then returns from `serve`.
+#[derive(Clone, Debug, Default)]
+pub struct ShutdownHandle {
+    flag: Arc<AtomicBool>,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+}
+
+#[derive(Clone)]
+struct ConnectionConfig {
+    keep_alive_timeout: Duration,
+    request_timeout: Duration,
+    max_requests_per_connection: usize,
+}
+
+/// A blocking HTTP/1.1 server running a `Handler` on a fixed pool of worker
+/// threads. Each worker serves one connection at a time, keep-alive included.
+pub struct Server {
+    handler: Arc<dyn Handler>,
+    workers: usize,
+    queue_size: usize,
+    config: ConnectionConfig,
+    shutdown: ShutdownHandle,
+    shutdown_on_signals: bool,
+}
+
+impl Server {
+    pub fn new<H: Handler>(handler: H) -> Server {
+        Server {
+            handler: Arc::new(handler),
+            workers: DEFAULT_WORKERS,
+            queue_size: DEFAULT_QUEUE_SIZE,
+            config: ConnectionConfig {
+                keep_alive_timeout: DEFAULT_KEEP_ALIVE_TIMEOUT,
+                request_timeout: DEFAULT_REQUEST_TIMEOUT,
+                max_requests_per_connection: DEFAULT_MAX_REQUESTS_PER_CONNECTION,
+            },
+            shutdown: ShutdownHandle::default(),
+            shutdown_on_signals: false,
+        }
+    }
+
+    /// Number of worker threads, at least one.
+    pub fn workers(mut self, workers: usize) -> Server {
+        self.workers = workers.max(1);
+        self
+    }
+
+    /// Accepted connections allowed to wait for a free worker. Connections
+    /// beyond that are answered with 503 Service Unavailable and closed.
+    pub fn queue_size(mut self, queue_size: usize) -> Server {
+        self.queue_size = queue_size;
+        self
+    }
+
+    /// How long an idle keep-alive connection is kept open. Also bounds each
+    /// single read while a request arrives; see `request_timeout` for the total.
+    pub fn keep_alive_timeout(mut self, timeout: Duration) -> Server {
+        self.config.keep_alive_timeout = timeout;
+        self
+    }
+
+    /// How long a client may take to send a whole request, head and body, once
+    /// it started. A client trickling bytes is cut off when this runs out.
+    pub fn request_timeout(mut self, timeout: Duration) -> Server {
+        self.config.request_timeout = timeout;
+        self
+    }
+
+    pub fn max_requests_per_connection(mut self, max_requests: usize) -> Server {
+        self.config.max_requests_per_connection = max_requests.max(1);
+        self
+    }
+
+    /// Shut down gracefully on SIGINT or SIGTERM.
+    pub fn shutdown_on_signals(mut self) -> Server {
+        self.shutdown_on_signals = true;
+        self
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Bind `addr` and serve until shut down.
+    pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<(), FlaskError> {
+        match TcpListener::bind(addr) {
+            Ok(listener) => self.serve(listener),
+            Err(io_err) => Err( FlaskError::InternalServerError(format!("Unable to bind listener: {}", io_err)) )
+        }
+    }
+
+    /// Accept connections on `listener` until shut down, then wait for the
+    /// workers to finish the connections they hold.
+    pub fn serve(self, listener: TcpListener) -> Result<(), FlaskError> {
+        if self.shutdown_on_signals {
+            for signal in [signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
+                if let Err(io_err) = signal_hook::flag::register(signal, self.shutdown.flag.clone()) {
+                    return Err( FlaskError::InternalServerError(format!("Unable to register signal handler: {}", io_err)) );
+                }
+            }
+        }
+        if let Err(io_err) = listener.set_nonblocking(true) {
+            return Err( FlaskError::InternalServerError(io_err.to_string()) );
+        }
+
+        let (sender, receiver) = mpsc::sync_channel::<TcpStream>(self.queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers: Vec<thread::JoinHandle<()>> = (0..self.workers)
+            .map(|_| {
+                let receiver = receiver.clone();
+                let handler = self.handler.clone();
+                let config = self.config.clone();
+                let shutdown = self.shutdown.clone();
+                thread::spawn(move || worker_loop(&receiver, |stream| serve_connection(stream, &*handler, &config, &shutdown)))
+            })
+            .collect();
+
+        while !self.shutdown.is_shutdown() {
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                // nothing to accept yet; aborted handshakes and fd exhaustion
+                // should not stop the server either
+                Err(_) => {
+                    thread::sleep(SHUTDOWN_POLL_INTERVAL);
+                    continue;
+                }
+            };
+            // accepted sockets inherit non-blocking mode on some platforms
+            if stream.set_nonblocking(false).is_err() {
+                continue;
+            }
+            match sender.try_send(stream) {
+                Ok(_) => {},
+                Err(TrySendError::Full(stream)) => reject_busy(stream),
+                Err(TrySendError::Disconnected(_)) => break
+            }
+        }
+
+        drop(sender);
+        for worker in workers {
+            let _ = worker.join();
+        }
+        Ok(())
+    }
+}
+
+fn reject_busy(mut stream: TcpStream) {
+    let mut resp = Response::new(b"Server is busy".to_vec());
+    *resp.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    resp.headers_mut().insert(header::CONNECTION, HeaderValue::from_static("close"));
+    let _ = write_http_response(&mut stream, &resp);
+}
+
+fn worker_loop<F: Fn(TcpStream)>(receiver: &Mutex<Receiver<TcpStream>>, serve: F) {
+    loop {
+        // the lock is released as soon as a connection is received
+        let stream = match receiver.lock().unwrap().recv() {
+            Ok(stream) => stream,
+            Err(_) => return
+        };
+        // a panic anywhere in serving, not only in the handler, costs that
+        // connection (it is dropped, so closed) but never the worker
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| serve(stream)));
+    }
+}
+
+/// A connection whose reads fail with `TimedOut` once `deadline` has passed.
+/// Each read blocks for at most `read_timeout` and never past the deadline.
+struct DeadlineStream {
+    stream: TcpStream,
+    deadline: Option<Instant>,
+    read_timeout: Duration,
+}
+
+impl Read for DeadlineStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(deadline) = self.deadline {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err( io::Error::new(ErrorKind::TimedOut, "request timeout elapsed") );
+            }
+            self.stream.set_read_timeout(Some((deadline - now).min(self.read_timeout)))?;
+        }
+        self.stream.read(buf)
+    }
+}
+
+impl Write for DeadlineStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// Wait for the next request to start arriving. Gives up once the connection
+/// has been idle for `idle_timeout`, is closed, or the server shuts down.
+fn wait_for_request(reader: &mut BufReader<DeadlineStream>, idle_timeout: Duration, shutdown: &ShutdownHandle) -> bool {
+    if !reader.buffer().is_empty() {
+        return true;
+    }
+    let deadline = Instant::now() + idle_timeout;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return false;
+        }
+        let wait = (deadline - now).min(SHUTDOWN_POLL_INTERVAL);
+        if reader.get_ref().stream.set_read_timeout(Some(wait)).is_err() {
+            return false;
+        }
+        match reader.fill_buf() {
+            Ok(buf) => return !buf.is_empty(),
+            Err(io_err) if matches!(io_err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted) => {
+                if shutdown.is_shutdown() {
+                    return false;
+                }
+            },
+            Err(_) => return false
+        }
+    }
+}
+
+fn call_handler(handler: &dyn Handler, req: Request<Vec<u8>>) -> Response<Vec<u8>> {
+    match panic::catch_unwind(AssertUnwindSafe(|| handler.handle(req))) {
+        Ok(resp) => resp,
+        Err(_) => FlaskError::InternalServerError("Request handler panicked".to_string()).to_response()
+    }
+}
+
+// a HEAD response announces the length of the body a GET would get, without sending it
+fn strip_head_body(resp: &mut Response<Vec<u8>>) {
+    if resp.body().is_empty() {
+        return;
+    }
+    if !resp.headers().contains_key(header::CONTENT_LENGTH) && !resp.headers().contains_key(header::TRANSFER_ENCODING) {
+        let length = HeaderValue::from(resp.body().len());
+        resp.headers_mut().insert(header::CONTENT_LENGTH, length);
+    }
+    resp.body_mut().clear();
+}
+
+/// Read one request from `reader`, answer it with `handler` and write the
+/// response. Returns whether the connection may carry another request;
+/// `must_close` is asked after the handler ran.
+pub(crate) fn serve_request<S: Read + Write>(reader: &mut BufReader<S>, handler: &dyn Handler, must_close: &dyn Fn() -> bool) -> bool {
+    let req = match read_http_request_from(reader) {
+        Ok(req) => req,
+        Err(flask_err) => {
+            // the rest of the stream cannot be framed, so answer and hang up
+            let mut resp = flask_err.to_response();
+            resp.headers_mut().insert(header::CONNECTION, HeaderValue::from_static("close"));
+            let _ = write_http_response(reader.get_mut(), &resp);
+            return false;
+        }
+    };
+
+    let method = req.method().clone();
+    let client_close = header_has_token(req.headers(), header::CONNECTION, "close");
+    let mut resp = call_handler(handler, req);
+
+    let close = client_close || must_close() || header_has_token(resp.headers(), header::CONNECTION, "close");
+    if close {
+        resp.headers_mut().insert(header::CONNECTION, HeaderValue::from_static("close"));
+    }
+    if method == Method::HEAD {
+        strip_head_body(&mut resp);
+    }
+    write_http_response(reader.get_mut(), &resp).is_ok() && !close
+}
+
+fn serve_connection(stream: TcpStream, handler: &dyn Handler, config: &ConnectionConfig, shutdown: &ShutdownHandle) {
+    let stream = DeadlineStream { stream, deadline: None, read_timeout: config.keep_alive_timeout };
+    let mut reader = BufReader::new(stream);
+    let mut served = 0;
+    loop {
+        reader.get_mut().deadline = None;
+        if !wait_for_request(&mut reader, config.keep_alive_timeout, shutdown) {
+            return;
+        }
+        // the clock starts with the first byte of the request
+        reader.get_mut().deadline = Some(Instant::now() + config.request_timeout);
+        served += 1;
+        let must_close = || served >= config.max_requests_per_connection || shutdown.is_shutdown();
+        if !serve_request(&mut reader, handler, &must_close) {
+            return;
+        }
+    }
+}
+
+//#################################################################################################################
+// test cases go below here
+//#################################################################################################################
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::httpx::{read_http_response_for, write_http_request};
+    use std::net::SocketAddr;
+
+    fn start(server: Server) -> (SocketAddr, ShutdownHandle, thread::JoinHandle<Result<(), FlaskError>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = server.shutdown_handle();
+        let running = thread::spawn(move || server.serve(listener));
+        (addr, handle, running)
+    }
+
+    fn echo_path(req: Request<Vec<u8>>) -> Response<Vec<u8>> {
+        Response::new(req.uri().path().as_bytes().to_vec())
+    }
+
+    fn get(method: Method, path: &str) -> Request<Vec<u8>> {
+        Request::builder().method(method).uri(path).header("host", "localhost").body(Vec::new()).unwrap()
+    }
+
+    fn exchange(reader: &mut BufReader<TcpStream>, req: &Request<Vec<u8>>) -> Response<Vec<u8>> {
+        write_http_request(reader.get_mut(), req).unwrap();
+        read_http_response_for(reader, req.method()).unwrap()
+    }
+
+    #[test]
+    fn test_keep_alive_connection() {
+        let (addr, handle, running) = start(Server::new(echo_path).workers(2));
+        let mut reader = BufReader::new(TcpStream::connect(addr).unwrap());
+
+        assert_eq!(exchange(&mut reader, &get(Method::GET, "/one")).body(), b"/one");
+        let resp = exchange(&mut reader, &get(Method::GET, "/two"));
+        assert_eq!(resp.body(), b"/two");
+        assert!(!resp.headers().contains_key(header::CONNECTION));
+
+        handle.shutdown();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_connection_close_and_request_limit() {
+        let (addr, handle, running) = start(Server::new(echo_path).max_requests_per_connection(2));
+        let mut reader = BufReader::new(TcpStream::connect(addr).unwrap());
+
+        assert!(!exchange(&mut reader, &get(Method::GET, "/1")).headers().contains_key(header::CONNECTION));
+        assert_eq!(exchange(&mut reader, &get(Method::GET, "/2")).headers()[header::CONNECTION], "close");
+
+        let mut reader = BufReader::new(TcpStream::connect(addr).unwrap());
+        let mut req = get(Method::GET, "/bye");
+        req.headers_mut().insert(header::CONNECTION, HeaderValue::from_static("close"));
+        assert_eq!(exchange(&mut reader, &req).headers()[header::CONNECTION], "close");
+
+        handle.shutdown();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_malformed_request_gets_error_response() {
+        let (addr, handle, running) = start(Server::new(echo_path));
+        let mut reader = BufReader::new(TcpStream::connect(addr).unwrap());
+        reader.get_mut().write_all(b"GET / HTTP/1.1\r\nContent-Length: nope\r\n\r\n").unwrap();
+
+        let resp = read_http_response_for(&mut reader, &Method::GET).unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(resp.body(), b"Invalid Content-Length: nope");
+        assert_eq!(resp.headers()[header::CONNECTION], "close");
+
+        handle.shutdown();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_request_timeout_cuts_off_trickling_client() {
+        let server = Server::new(echo_path).keep_alive_timeout(Duration::from_millis(300)).request_timeout(Duration::from_millis(500));
+        let (addr, handle, running) = start(server);
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        // every byte arrives well within the per-read timeout
+        let started = Instant::now();
+        stream.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        let mut trickle = stream.try_clone().unwrap();
+        let trickling = thread::spawn(move || {
+            while started.elapsed() < Duration::from_secs(3) && trickle.write_all(b"x").is_ok() {
+                thread::sleep(Duration::from_millis(100));
+            }
+        });
+        let mut answer = Vec::new();
+        let _ = stream.read_to_end(&mut answer);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        trickling.join().unwrap();
+
+        handle.shutdown();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_handler_panic_becomes_500() {
+        let (addr, handle, running) = start(Server::new(|req: Request<Vec<u8>>| {
+            if req.uri().path() == "/boom" {
+                panic!("boom");
+            }
+            Response::new(Vec::new())
+        }).workers(1));
+        let mut reader = BufReader::new(TcpStream::connect(addr).unwrap());
+
+        assert_eq!(exchange(&mut reader, &get(Method::GET, "/boom")).status(), StatusCode::INTERNAL_SERVER_ERROR);
+        // the single worker survived the panic
+        assert_eq!(exchange(&mut reader, &get(Method::GET, "/fine")).status(), StatusCode::OK);
+
+        handle.shutdown();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_panic_while_serving_keeps_the_worker() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (sender, receiver) = mpsc::sync_channel::<TcpStream>(4);
+        let clients: Vec<TcpStream> = (0..3).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        for _ in 0..3 {
+            sender.send(listener.accept().unwrap().0).unwrap();
+        }
+        drop(sender);
+
+        // e.g. the request parser panicking on hostile input, outside the handler
+        let served = std::sync::atomic::AtomicUsize::new(0);
+        worker_loop(&Mutex::new(receiver), |_stream| {
+            served.fetch_add(1, Ordering::SeqCst);
+            panic!("parser bug");
+        });
+        assert_eq!(served.load(Ordering::SeqCst), 3);
+        drop(clients);
+    }
+
+    #[test]
+    fn test_head_response_has_no_body() {
+        let (addr, handle, running) = start(Server::new(echo_path));
+        let mut reader = BufReader::new(TcpStream::connect(addr).unwrap());
+
+        let resp = exchange(&mut reader, &get(Method::HEAD, "/length"));
+        assert_eq!(resp.headers()[header::CONTENT_LENGTH], "7");
+        assert!(resp.body().is_empty());
+        assert_eq!(exchange(&mut reader, &get(Method::GET, "/after")).body(), b"/after");
+
+        handle.shutdown();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_busy_server_rejects_with_503() {
+        let (addr, handle, running) = start(Server::new(|req: Request<Vec<u8>>| {
+            thread::sleep(Duration::from_millis(300));
+            echo_path(req)
+        }).workers(1).queue_size(0));
+        // let the worker reach its receive before the first connection is handed over
+        thread::sleep(Duration::from_millis(50));
+
+        let mut first = BufReader::new(TcpStream::connect(addr).unwrap());
+        write_http_request(first.get_mut(), &get(Method::GET, "/slow")).unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        let mut second = BufReader::new(TcpStream::connect(addr).unwrap());
+        let resp = read_http_response_for(&mut second, &Method::GET).unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(read_http_response_for(&mut first, &Method::GET).unwrap().body(), b"/slow");
+
+        handle.shutdown();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_graceful_shutdown_drains_in_flight_request() {
+        let (addr, handle, running) = start(Server::new(|req: Request<Vec<u8>>| {
+            thread::sleep(Duration::from_millis(200));
+            echo_path(req)
+        }));
+        let mut busy = BufReader::new(TcpStream::connect(addr).unwrap());
+        let mut idle = BufReader::new(TcpStream::connect(addr).unwrap());
+        write_http_request(busy.get_mut(), &get(Method::GET, "/drain")).unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        handle.shutdown();
+        let resp = read_http_response_for(&mut busy, &Method::GET).unwrap();
+        assert_eq!(resp.body(), b"/drain");
+        assert_eq!(resp.headers()[header::CONNECTION], "close");
+        running.join().unwrap().unwrap();
+
+        // the idle keep-alive connection was closed without a response
+        assert!(idle.fill_buf().unwrap().is_empty());
+        assert!(TcpStream::connect(addr).is_err());
+    }
+}
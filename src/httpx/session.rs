@@ -0,0 +1,460 @@
+use super::{
+    cookies::{Cookies, SameSite, SetCookie},
+    errors::FlaskError,
+    middleware::{Middleware, Next},
+    query::{encode_component, QueryParams}
+};
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64;
+use hmac::{Hmac, Mac};
+use http::{header, HeaderValue, Request, Response};
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// keeps these signatures apart from anything else signed with the same secret key
+const SIGNATURE_SALT: &[u8] = b"flask.session.cookie";
+const DEFAULT_COOKIE_NAME: &str = "session";
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(31 * 24 * 60 * 60);
+// what browsers reliably store for one cookie, name and attributes included
+const DEFAULT_MAX_SIZE: usize = 4093;
+
+#[derive(Default)]
+pub(crate) struct SessionState {
+    pub(crate) data: BTreeMap<String, String>,
+    pub(crate) modified: bool,
+    pub(crate) accessed: bool,
+    pub(crate) regenerate: bool,
+}
+
+/// The session of the current request, Flask's `session`: a string map
+/// stored in a cookie. `SessionMiddleware` puts it in the request extensions,
+/// where views find it (or take it as an extractor); `StoredSessionMiddleware`
+/// does the same for sessions kept on the server. It is a handle, so changes
+/// made through any clone are saved when the view returns.
+#[derive(Clone, Default)]
+pub struct Session {
+    state: Arc<Mutex<SessionState>>,
+}
+
+impl Session {
+    pub(crate) fn with_data(data: BTreeMap<String, String>, modified: bool) -> Session {
+        let state = SessionState { data, modified, ..SessionState::default() };
+        Session { state: Arc::new(Mutex::new(state)) }
+    }
+
+    pub(crate) fn state(&self) -> MutexGuard<'_, SessionState> {
+        self.state.lock().unwrap()
+    }
+
+    fn read<T>(&self, read: impl FnOnce(&BTreeMap<String, String>) -> T) -> T {
+        let mut state = self.state.lock().unwrap();
+        state.accessed = true;
+        read(&state.data)
+    }
+
+    fn write<T>(&self, write: impl FnOnce(&mut BTreeMap<String, String>) -> T) -> T {
+        let mut state = self.state.lock().unwrap();
+        state.accessed = true;
+        state.modified = true;
+        write(&mut state.data)
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.read(|data| data.get(key).cloned())
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.read(|data| data.contains_key(key))
+    }
+
+    pub fn keys(&self) -> Vec<String> {
+        self.read(|data| data.keys().cloned().collect())
+    }
+
+    pub fn len(&self) -> usize {
+        self.read(|data| data.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.read(|data| data.is_empty())
+    }
+
+    /// Set `key`, returning the previous value.
+    pub fn insert(&self, key: &str, value: &str) -> Option<String> {
+        self.write(|data| data.insert(key.to_string(), value.to_string()))
+    }
+
+    pub fn remove(&self, key: &str) -> Option<String> {
+        self.write(|data| data.remove(key))
+    }
+
+    /// Empty the session; the response then deletes the cookie.
+    pub fn clear(&self) {
+        self.write(|data| data.clear())
+    }
+
+    /// Renew the session on the next response, e.g. after a login. A
+    /// server-side session moves to a new session ID, so an ID an attacker
+    /// planted beforehand (session fixation) becomes worthless; a cookie
+    /// session is simply re-issued.
+    pub fn regenerate(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.modified = true;
+        state.regenerate = true;
+    }
+
+    /// True when the response will carry a new session cookie.
+    pub fn is_modified(&self) -> bool {
+        self.state.lock().unwrap().modified
+    }
+}
+
+/// Where a session cookie is sent, shared by both session middlewares.
+pub(crate) struct CookieOptions {
+    pub(crate) name: String,
+    pub(crate) path: String,
+    pub(crate) domain: Option<String>,
+    pub(crate) secure: bool,
+    pub(crate) same_site: SameSite,
+}
+
+impl CookieOptions {
+    pub(crate) fn new(name: &str) -> CookieOptions {
+        CookieOptions { name: name.to_string(), path: "/".to_string(), domain: None, secure: false, same_site: SameSite::Lax }
+    }
+
+    pub(crate) fn set_cookie(&self, value: &str) -> SetCookie {
+        let cookie = SetCookie::new(&self.name, value)
+            .path(&self.path)
+            .secure(self.secure)
+            .http_only(true)
+            .same_site(self.same_site);
+        match &self.domain {
+            Some(domain) => cookie.domain(domain),
+            None => cookie
+        }
+    }
+
+    pub(crate) fn removal(&self) -> SetCookie {
+        self.set_cookie("").max_age(Duration::ZERO).expires(UNIX_EPOCH)
+    }
+}
+
+// a response that depends on the session must not be cached for other users
+pub(crate) fn vary_on_cookie(resp: &mut Response<Vec<u8>>) {
+    if !resp.headers().get_all(header::VARY).iter().any(|value| value == "Cookie") {
+        resp.headers_mut().append(header::VARY, HeaderValue::from_static("Cookie"));
+    }
+}
+
+/// Keeps the session in a cookie signed with HMAC-SHA256, like Flask's
+/// default session interface. The data is readable by the client but cannot
+/// be changed without the secret key.
+///
+/// A cookie that is malformed, signed with an unknown key or older than
+/// `max_age` is ignored, and the view sees an empty session. The cookie is
+/// re-issued when the view changed the session, and when it was signed with a
+/// fallback key, so rotating keys moves active sessions over to the new one. A
+/// session that no longer fits in `max_size` bytes is a 500.
+///
+/// ```ignore
+/// let app = Chain::new(router).wrap(SessionMiddleware::new(b"current key").fallback_key(b"previous key"));
+/// ```
+pub struct SessionMiddleware {
+    keys: Vec<Vec<u8>>,
+    cookie: CookieOptions,
+    max_age: Duration,
+    max_size: usize,
+}
+
+impl SessionMiddleware {
+    /// # Panics
+    /// When `secret_key` is empty.
+    pub fn new(secret_key: &[u8]) -> SessionMiddleware {
+        if secret_key.is_empty() {
+            panic!("SessionMiddleware needs a non-empty secret key");
+        }
+        SessionMiddleware {
+            keys: vec![secret_key.to_vec()],
+            cookie: CookieOptions::new(DEFAULT_COOKIE_NAME),
+            max_age: DEFAULT_MAX_AGE,
+            max_size: DEFAULT_MAX_SIZE,
+        }
+    }
+
+    /// Also accept cookies signed with `key`, e.g. the secret key used before
+    /// the current one. Fallback keys are tried in the order they were added.
+    ///
+    /// # Panics
+    /// When `key` is empty.
+    pub fn fallback_key(mut self, key: &[u8]) -> SessionMiddleware {
+        if key.is_empty() {
+            panic!("SessionMiddleware::fallback_key needs a non-empty key");
+        }
+        self.keys.push(key.to_vec());
+        self
+    }
+
+    pub fn cookie_name(mut self, cookie_name: &str) -> SessionMiddleware {
+        self.cookie.name = cookie_name.to_string();
+        self
+    }
+
+    /// How long a cookie stays valid after it was issued; 31 days by default.
+    pub fn max_age(mut self, max_age: Duration) -> SessionMiddleware {
+        self.max_age = max_age;
+        self
+    }
+
+    /// The largest `Set-Cookie` value to send, 4093 bytes by default.
+    pub fn max_size(mut self, max_size: usize) -> SessionMiddleware {
+        self.max_size = max_size;
+        self
+    }
+
+    pub fn path(mut self, path: &str) -> SessionMiddleware {
+        self.cookie.path = path.to_string();
+        self
+    }
+
+    pub fn domain(mut self, domain: &str) -> SessionMiddleware {
+        self.cookie.domain = Some(domain.to_string());
+        self
+    }
+
+    pub fn secure(mut self, secure: bool) -> SessionMiddleware {
+        self.cookie.secure = secure;
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> SessionMiddleware {
+        self.cookie.same_site = same_site;
+        self
+    }
+
+    fn signature(key: &[u8], signed: &str) -> Hmac<Sha256> {
+        // HMAC takes keys of any length
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+        mac.update(SIGNATURE_SALT);
+        mac.update(signed.as_bytes());
+        mac
+    }
+
+    // "payload.timestamp.signature", all of it valid cookie-octets
+    fn sign(&self, data: &BTreeMap<String, String>, issued: u64) -> String {
+        let signed = format!("{}.{}", BASE64.encode(encode_data(data)), issued);
+        let signature = Self::signature(&self.keys[0], &signed).finalize().into_bytes();
+        format!("{}.{}", signed, BASE64.encode(signature))
+    }
+
+    // the session data, and whether it was signed with a fallback key
+    fn verify(&self, value: &str, now: u64) -> Option<(BTreeMap<String, String>, bool)> {
+        let (signed, signature) = value.rsplit_once('.')?;
+        let signature = BASE64.decode(signature).ok()?;
+        let key_index = self.keys.iter().position(|key| Self::signature(key, signed).verify_slice(&signature).is_ok())?;
+
+        let (payload, issued) = signed.split_once('.')?;
+        let issued: u64 = issued.parse().ok()?;
+        if issued > now || now - issued > self.max_age.as_secs() {
+            return None;
+        }
+        let payload = String::from_utf8(BASE64.decode(payload).ok()?).ok()?;
+        Some( (decode_data(&payload)?, key_index > 0) )
+    }
+
+    fn load(&self, cookies: &Cookies, now: u64) -> Session {
+        let verified = cookies.get_all(&self.cookie.name).find_map(|value| self.verify(value, now));
+        match verified {
+            Some((data, rotated)) => Session::with_data(data, rotated),
+            None => Session::default()
+        }
+    }
+
+    fn save(&self, session: &Session, had_cookie: bool, now: u64, resp: &mut Response<Vec<u8>>) -> Result<(), FlaskError> {
+        let state = session.state();
+        if state.accessed {
+            vary_on_cookie(resp);
+        }
+        if !state.modified {
+            return Ok(());
+        }
+        if state.data.is_empty() {
+            if had_cookie {
+                self.cookie.removal().append_to(resp.headers_mut())?;
+            }
+            return Ok(());
+        }
+
+        let cookie = self.cookie.set_cookie(&self.sign(&state.data, now)).max_age(self.max_age);
+        let value = cookie.build()?;
+        if value.len() > self.max_size {
+            let msg = format!("Session cookie of {} bytes exceeds the limit of {}", value.len(), self.max_size);
+            return Err( FlaskError::InternalServerError(msg) );
+        }
+        resp.headers_mut().append(header::SET_COOKIE, value);
+        Ok(())
+    }
+}
+
+// session data as "key=value&..." pairs, the way both middlewares keep it
+pub(crate) fn encode_data(data: &BTreeMap<String, String>) -> String {
+    let encoded: Vec<String> = data.iter()
+        .map(|(key, value)| format!("{}={}", encode_component(key), encode_component(value)))
+        .collect();
+    encoded.join("&")
+}
+
+pub(crate) fn decode_data(encoded: &str) -> Option<BTreeMap<String, String>> {
+    let params = QueryParams::parse(encoded).ok()?;
+    Some( params.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect() )
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|since_epoch| since_epoch.as_secs()).unwrap_or(0)
+}
+
+impl Middleware for SessionMiddleware {
+    fn handle(&self, mut req: Request<Vec<u8>>, next: Next<'_>) -> Response<Vec<u8>> {
+        let now = unix_now();
+        let cookies = Cookies::from_headers(req.headers());
+        let had_cookie = cookies.contains(&self.cookie.name);
+        let session = self.load(&cookies, now);
+        req.extensions_mut().insert(session.clone());
+
+        let mut resp = next.run(req);
+        match self.save(&session, had_cookie, now, &mut resp) {
+            Ok(()) => resp,
+            Err(flask_err) => flask_err.to_response()
+        }
+    }
+}
+
+
+//#################################################################################################################
+// test cases go below here
+//#################################################################################################################
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::httpx::middleware::Chain;
+    use crate::httpx::server::Handler;
+    use http::StatusCode;
+
+    // a counter view: increments "visits", or clears the session on /logout
+    fn app(middleware: SessionMiddleware) -> Chain {
+        Chain::new(|req: Request<Vec<u8>>| {
+            let session = req.extensions().get::<Session>().unwrap().clone();
+            match req.uri().path() {
+                "/logout" => session.clear(),
+                "/peek" => {},
+                "/big" => { session.insert("blob", &"x".repeat(5000)); },
+                _ => {
+                    let visits: u32 = session.get("visits").and_then(|visits| visits.parse().ok()).unwrap_or(0);
+                    session.insert("visits", &(visits + 1).to_string());
+                    session.insert("user", "ann lee & co");
+                }
+            }
+            Response::new(session.get("visits").unwrap_or_default().into_bytes())
+        }).wrap(middleware)
+    }
+
+    fn request(path: &str, cookie: Option<&str>) -> Request<Vec<u8>> {
+        let mut builder = Request::get(path);
+        if let Some(cookie) = cookie {
+            builder = builder.header(header::COOKIE, format!("session={}", cookie));
+        }
+        builder.body(Vec::new()).unwrap()
+    }
+
+    // the session cookie value set by a response
+    fn issued(resp: &Response<Vec<u8>>) -> Option<String> {
+        let set_cookie = resp.headers().get(header::SET_COOKIE)?.to_str().unwrap();
+        let (pair, _) = set_cookie.split_once(';')?;
+        Some(pair.strip_prefix("session=")?.to_string())
+    }
+
+    #[test]
+    fn test_round_trip_and_reissue() {
+        let counter = app(SessionMiddleware::new(b"secret"));
+        let resp = counter.handle(request("/", None));
+        assert_eq!(resp.body(), b"1");
+        assert_eq!(resp.headers()[header::VARY], "Cookie");
+        let set_cookie = resp.headers()[header::SET_COOKIE].to_str().unwrap();
+        assert!(set_cookie.ends_with("; Path=/; Max-Age=2678400; HttpOnly; SameSite=Lax"));
+        let cookie = issued(&resp).unwrap();
+
+        let resp = counter.handle(request("/", Some(&cookie)));
+        assert_eq!(resp.body(), b"2");
+        let cookie = issued(&resp).unwrap();
+
+        // reading only sends no new cookie
+        let resp = counter.handle(request("/peek", Some(&cookie)));
+        assert_eq!(resp.body(), b"2");
+        assert!(!resp.headers().contains_key(header::SET_COOKIE));
+    }
+
+    #[test]
+    fn test_tampered_cookie_is_an_empty_session() {
+        let counter = app(SessionMiddleware::new(b"secret"));
+        let cookie = issued(&counter.handle(request("/", None))).unwrap();
+        let (payload, rest) = cookie.split_once('.').unwrap();
+        let forged_payload = BASE64.encode("visits=99");
+        assert_ne!(payload, forged_payload);
+
+        let forged = format!("{}.{}", forged_payload, rest);
+        assert_eq!(counter.handle(request("/peek", Some(&forged))).body(), b"");
+        assert_eq!(counter.handle(request("/peek", Some("garbage"))).body(), b"");
+
+        let other_key = app_with_cookie(SessionMiddleware::new(b"other secret"));
+        assert_eq!(counter.handle(request("/peek", Some(&other_key))).body(), b"");
+    }
+
+    fn app_with_cookie(middleware: SessionMiddleware) -> String {
+        issued(&app(middleware).handle(request("/", None))).unwrap()
+    }
+
+    #[test]
+    fn test_expired_cookie() {
+        let middleware = SessionMiddleware::new(b"secret").max_age(Duration::from_secs(60));
+        let data: BTreeMap<String, String> = [("visits".to_string(), "5".to_string())].into_iter().collect();
+        let fresh = middleware.sign(&data, unix_now() - 30);
+        let stale = middleware.sign(&data, unix_now() - 120);
+        let counter = app(middleware);
+        assert_eq!(counter.handle(request("/peek", Some(&fresh))).body(), b"5");
+        assert_eq!(counter.handle(request("/peek", Some(&stale))).body(), b"");
+    }
+
+    #[test]
+    fn test_key_rotation() {
+        let old_cookie = app_with_cookie(SessionMiddleware::new(b"old key"));
+        let rotating = app(SessionMiddleware::new(b"new key").fallback_key(b"old key"));
+
+        // accepted, and re-issued under the new key without any change by the view
+        let resp = rotating.handle(request("/peek", Some(&old_cookie)));
+        assert_eq!(resp.body(), b"1");
+        let new_cookie = issued(&resp).unwrap();
+        let new_only = app(SessionMiddleware::new(b"new key"));
+        assert_eq!(new_only.handle(request("/peek", Some(&new_cookie))).body(), b"1");
+        assert_eq!(new_only.handle(request("/peek", Some(&old_cookie))).body(), b"");
+    }
+
+    #[test]
+    fn test_clear_removes_cookie() {
+        let counter = app(SessionMiddleware::new(b"secret"));
+        let cookie = issued(&counter.handle(request("/", None))).unwrap();
+        let resp = counter.handle(request("/logout", Some(&cookie)));
+        assert_eq!(resp.headers()[header::SET_COOKIE], "session=; Path=/; Expires=Thu, 01 Jan 1970 00:00:00 GMT; Max-Age=0; HttpOnly; SameSite=Lax");
+        // nothing to remove without a cookie
+        assert!(!counter.handle(request("/logout", None)).headers().contains_key(header::SET_COOKIE));
+    }
+
+    #[test]
+    fn test_size_limit() {
+        let resp = app(SessionMiddleware::new(b"secret")).handle(request("/big", None));
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(!resp.headers().contains_key(header::SET_COOKIE));
+    }
+}
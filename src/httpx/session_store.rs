@@ -0,0 +1,612 @@
+use super::{
+    cookies::{Cookies, SameSite},
+    errors::FlaskError,
+    middleware::{Middleware, Next},
+    session::{decode_data, encode_data, vary_on_cookie, CookieOptions, Session}
+};
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64;
+use http::{Request, Response};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+const DEFAULT_COOKIE_NAME: &str = "session_id";
+const DEFAULT_MAX_IDLE: Duration = Duration::from_secs(30 * 60);
+const SESSION_ID_BYTES: usize = 32;
+// base64url without padding of SESSION_ID_BYTES
+const SESSION_ID_LEN: usize = 43;
+
+/// A new session ID: 256 random bits from the operating system, base64url encoded.
+pub fn new_session_id() -> Result<String, FlaskError> {
+    let mut bytes = [0u8; SESSION_ID_BYTES];
+    match getrandom::getrandom(&mut bytes) {
+        Ok(()) => Ok( BASE64.encode(bytes) ),
+        Err(err) => Err( FlaskError::InternalServerError(format!("Cannot generate a session ID: {}", err)) )
+    }
+}
+
+/// True for strings shaped like `new_session_id` output. Stores only ever see
+/// such IDs, so a file name built from one cannot leave the store directory.
+pub fn is_valid_session_id(id: &str) -> bool {
+    id.len() == SESSION_ID_LEN && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+fn invalid_id(id: &str) -> FlaskError {
+    FlaskError::InternalServerError(format!("Invalid session ID {:?}", id))
+}
+
+/// Storage for server-side sessions, shared by all worker threads.
+///
+/// A session counts as used whenever it is loaded or saved; one unused for
+/// longer than `max_idle` is gone. Errors are 500s.
+pub trait SessionStore: Send + Sync + 'static {
+    /// The data saved under `id`, refreshing its last use. An unknown or
+    /// idle session is `None`, and an idle one is removed.
+    fn load(&self, id: &str, max_idle: Duration) -> Result<Option<BTreeMap<String, String>>, FlaskError>;
+
+    /// Save `data` under `id`, replacing what was there.
+    fn save(&self, id: &str, data: &BTreeMap<String, String>) -> Result<(), FlaskError>;
+
+    /// Forget `id`; unknown IDs are not an error.
+    fn delete(&self, id: &str) -> Result<(), FlaskError>;
+
+    /// Remove all sessions unused for longer than `max_idle` and return how
+    /// many there were. Run it now and then, e.g. from a timer thread.
+    fn remove_expired(&self, max_idle: Duration) -> Result<usize, FlaskError>;
+}
+
+/// Lets the middleware and, say, a cleanup thread share one store.
+impl<S: SessionStore> SessionStore for Arc<S> {
+    fn load(&self, id: &str, max_idle: Duration) -> Result<Option<BTreeMap<String, String>>, FlaskError> {
+        (**self).load(id, max_idle)
+    }
+
+    fn save(&self, id: &str, data: &BTreeMap<String, String>) -> Result<(), FlaskError> {
+        (**self).save(id, data)
+    }
+
+    fn delete(&self, id: &str) -> Result<(), FlaskError> {
+        (**self).delete(id)
+    }
+
+    fn remove_expired(&self, max_idle: Duration) -> Result<usize, FlaskError> {
+        (**self).remove_expired(max_idle)
+    }
+}
+
+// ***************************************************************************************************************
+// in-memory store
+// ***************************************************************************************************************
+
+struct StoredSession {
+    data: BTreeMap<String, String>,
+    last_used: Instant,
+}
+
+/// Sessions kept in the process; they are lost on restart and not shared
+/// between processes.
+#[derive(Default)]
+pub struct MemoryStore {
+    sessions: Mutex<HashMap<String, StoredSession>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl SessionStore for MemoryStore {
+    fn load(&self, id: &str, max_idle: Duration) -> Result<Option<BTreeMap<String, String>>, FlaskError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let now = Instant::now();
+        match sessions.get_mut(id) {
+            Some(stored) if now.duration_since(stored.last_used) <= max_idle => {
+                stored.last_used = now;
+                Ok( Some(stored.data.clone()) )
+            },
+            Some(_) => {
+                sessions.remove(id);
+                Ok(None)
+            },
+            None => Ok(None)
+        }
+    }
+
+    fn save(&self, id: &str, data: &BTreeMap<String, String>) -> Result<(), FlaskError> {
+        let stored = StoredSession { data: data.clone(), last_used: Instant::now() };
+        self.sessions.lock().unwrap().insert(id.to_string(), stored);
+        Ok(())
+    }
+
+    fn delete(&self, id: &str) -> Result<(), FlaskError> {
+        self.sessions.lock().unwrap().remove(id);
+        Ok(())
+    }
+
+    fn remove_expired(&self, max_idle: Duration) -> Result<usize, FlaskError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let before = sessions.len();
+        let now = Instant::now();
+        sessions.retain(|_, stored| now.duration_since(stored.last_used) <= max_idle);
+        Ok( before - sessions.len() )
+    }
+}
+
+// ***************************************************************************************************************
+// file store
+// ***************************************************************************************************************
+
+/// One file per session in a directory, named after the session ID, so
+/// sessions survive restarts and can be shared by processes on one host. The
+/// file's modification time is the session's last use.
+///
+/// Files are replaced atomically by writing a uniquely named temporary file
+/// and renaming it, so concurrent writers never see each other's half-written
+/// files. `remove_expired` also sweeps temporary files left by a crash.
+pub struct FileStore {
+    dir: PathBuf,
+    // serializes the read-check-write sequences of this process's threads
+    lock: Mutex<()>,
+}
+
+fn io_error(action: &str, path: &Path, io_err: io::Error) -> FlaskError {
+    FlaskError::InternalServerError(format!("Cannot {} {}: {}", action, path.display(), io_err))
+}
+
+impl FileStore {
+    /// A store in `dir`, which is created if needed.
+    pub fn new<P: AsRef<Path>>(dir: P) -> Result<FileStore, FlaskError> {
+        let dir = dir.as_ref().to_path_buf();
+        match fs::create_dir_all(&dir) {
+            Ok(()) => Ok( FileStore { dir, lock: Mutex::new(()) } ),
+            Err(io_err) => Err( io_error("create session directory", &dir, io_err) )
+        }
+    }
+
+    fn path(&self, id: &str) -> Result<PathBuf, FlaskError> {
+        match is_valid_session_id(id) {
+            true => Ok( self.dir.join(id) ),
+            false => Err( invalid_id(id) )
+        }
+    }
+
+    fn idle_for(path: &Path) -> io::Result<Duration> {
+        let modified = fs::metadata(path)?.modified()?;
+        // a clock set back makes a session look fresh rather than expired
+        Ok( SystemTime::now().duration_since(modified).unwrap_or(Duration::ZERO) )
+    }
+
+    fn remove(path: &Path) -> Result<(), FlaskError> {
+        match fs::remove_file(path) {
+            Ok(()) => Ok(()),
+            Err(io_err) if io_err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(io_err) => Err( io_error("remove session file", path, io_err) )
+        }
+    }
+}
+
+impl SessionStore for FileStore {
+    fn load(&self, id: &str, max_idle: Duration) -> Result<Option<BTreeMap<String, String>>, FlaskError> {
+        let path = self.path(id)?;
+        let _guard = self.lock.lock().unwrap();
+        match Self::idle_for(&path) {
+            Ok(idle) if idle <= max_idle => {},
+            Ok(_) => return Self::remove(&path).map(|_| None),
+            Err(io_err) if io_err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(io_err) => return Err( io_error("read session file", &path, io_err) )
+        }
+
+        let encoded = match fs::read_to_string(&path) {
+            Ok(encoded) => encoded,
+            Err(io_err) => return Err( io_error("read session file", &path, io_err) )
+        };
+        let touched = File::options().write(true).open(&path).and_then(|file| file.set_modified(SystemTime::now()));
+        if let Err(io_err) = touched {
+            return Err( io_error("update session file", &path, io_err) );
+        }
+        match decode_data(&encoded) {
+            Some(data) => Ok( Some(data) ),
+            None => Err( FlaskError::InternalServerError(format!("Corrupt session file {}", path.display())) )
+        }
+    }
+
+    fn save(&self, id: &str, data: &BTreeMap<String, String>) -> Result<(), FlaskError> {
+        let path = self.path(id)?;
+        // not a valid session ID, so never mistaken for a session; the random
+        // part keeps writers in other processes off this file
+        let temp = self.dir.join(format!("{}.{}.tmp", id, new_session_id()?));
+        let _guard = self.lock.lock().unwrap();
+        if let Err(io_err) = fs::write(&temp, encode_data(data)) {
+            let _ = fs::remove_file(&temp);
+            return Err( io_error("write session file", &temp, io_err) );
+        }
+        match fs::rename(&temp, &path) {
+            Ok(()) => Ok(()),
+            Err(io_err) => Err( io_error("write session file", &path, io_err) )
+        }
+    }
+
+    fn delete(&self, id: &str) -> Result<(), FlaskError> {
+        let path = self.path(id)?;
+        let _guard = self.lock.lock().unwrap();
+        Self::remove(&path)
+    }
+
+    fn remove_expired(&self, max_idle: Duration) -> Result<usize, FlaskError> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(io_err) => return Err( io_error("list session directory", &self.dir, io_err) )
+        };
+        let _guard = self.lock.lock().unwrap();
+        let mut removed = 0;
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let is_session = name.to_str().map(is_valid_session_id).unwrap_or(false);
+            let is_temp = name.to_str().map(|name| name.ends_with(".tmp")).unwrap_or(false);
+            let path = entry.path();
+            if !(is_session || is_temp) || !Self::idle_for(&path).map(|idle| idle > max_idle).unwrap_or(false) {
+                continue;
+            }
+            Self::remove(&path)?;
+            if is_session {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+// ***************************************************************************************************************
+// middleware
+// ***************************************************************************************************************
+
+/// Sessions kept on the server, in any `SessionStore`, with only a random
+/// session ID in the cookie. Unlike `SessionMiddleware` there is no size
+/// limit and the data is never visible to the client.
+///
+/// Views use the same `Session` as with cookie sessions:
+///
+/// ```ignore
+/// let store = Arc::new(FileStore::new("/var/lib/app/sessions")?);
+/// let app = Chain::new(router).wrap(StoredSessionMiddleware::new(store.clone()).secure(true));
+/// ```
+///
+/// A session ID the store does not know is never adopted: the request gets
+/// an empty session, and a fresh ID once something is saved. Call
+/// `Session::regenerate` on login so the session moves to a new ID too. The
+/// cookie has no `Max-Age` and expires with the browser session; on the
+/// server a session ends after `max_idle` without requests.
+pub struct StoredSessionMiddleware<S: SessionStore> {
+    store: S,
+    cookie: CookieOptions,
+    max_idle: Duration,
+}
+
+impl<S: SessionStore> StoredSessionMiddleware<S> {
+    pub fn new(store: S) -> StoredSessionMiddleware<S> {
+        StoredSessionMiddleware {
+            store,
+            cookie: CookieOptions::new(DEFAULT_COOKIE_NAME),
+            max_idle: DEFAULT_MAX_IDLE,
+        }
+    }
+
+    /// The cookie holding the session ID, `session_id` by default.
+    pub fn cookie_name(mut self, cookie_name: &str) -> StoredSessionMiddleware<S> {
+        self.cookie.name = cookie_name.to_string();
+        self
+    }
+
+    /// How long a session survives without requests, 30 minutes by default.
+    pub fn max_idle(mut self, max_idle: Duration) -> StoredSessionMiddleware<S> {
+        self.max_idle = max_idle;
+        self
+    }
+
+    pub fn path(mut self, path: &str) -> StoredSessionMiddleware<S> {
+        self.cookie.path = path.to_string();
+        self
+    }
+
+    pub fn domain(mut self, domain: &str) -> StoredSessionMiddleware<S> {
+        self.cookie.domain = Some(domain.to_string());
+        self
+    }
+
+    pub fn secure(mut self, secure: bool) -> StoredSessionMiddleware<S> {
+        self.cookie.secure = secure;
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> StoredSessionMiddleware<S> {
+        self.cookie.same_site = same_site;
+        self
+    }
+
+    // the session of the first session ID cookie the store knows, and that ID
+    fn load(&self, cookies: &Cookies) -> Result<(Session, Option<String>), FlaskError> {
+        for id in cookies.get_all(&self.cookie.name).filter(|id| is_valid_session_id(id)) {
+            if let Some(data) = self.store.load(id, self.max_idle)? {
+                return Ok( (Session::with_data(data, false), Some(id.to_string())) );
+            }
+        }
+        Ok( (Session::default(), None) )
+    }
+
+    fn save(&self, session: &Session, id: Option<String>, had_cookie: bool, resp: &mut Response<Vec<u8>>) -> Result<(), FlaskError> {
+        let state = session.state();
+        if state.accessed {
+            vary_on_cookie(resp);
+        }
+        if !state.modified {
+            return Ok(());
+        }
+        if state.data.is_empty() {
+            if let Some(id) = &id {
+                self.store.delete(id)?;
+            }
+            if had_cookie {
+                self.cookie.removal().append_to(resp.headers_mut())?;
+            }
+            return Ok(());
+        }
+
+        let id = match id {
+            Some(id) if !state.regenerate => return self.store.save(&id, &state.data),
+            Some(old_id) => {
+                self.store.delete(&old_id)?;
+                new_session_id()?
+            },
+            None => new_session_id()?
+        };
+        self.store.save(&id, &state.data)?;
+        self.cookie.set_cookie(&id).append_to(resp.headers_mut())
+    }
+}
+
+impl<S: SessionStore> Middleware for StoredSessionMiddleware<S> {
+    fn handle(&self, mut req: Request<Vec<u8>>, next: Next<'_>) -> Response<Vec<u8>> {
+        let cookies = Cookies::from_headers(req.headers());
+        let had_cookie = cookies.contains(&self.cookie.name);
+        let (session, id) = match self.load(&cookies) {
+            Ok(loaded) => loaded,
+            Err(flask_err) => return flask_err.to_response()
+        };
+        req.extensions_mut().insert(session.clone());
+
+        let mut resp = next.run(req);
+        match self.save(&session, id, had_cookie, &mut resp) {
+            Ok(()) => resp,
+            Err(flask_err) => flask_err.to_response()
+        }
+    }
+}
+
+
+//#################################################################################################################
+// test cases go below here
+//#################################################################################################################
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::httpx::middleware::Chain;
+    use crate::httpx::server::Handler;
+    use http::header;
+    use std::thread;
+
+    // a counter view: increments "visits", logs in on /login and clears the session on /logout
+    fn app<S: SessionStore>(middleware: StoredSessionMiddleware<S>) -> Chain {
+        Chain::new(|req: Request<Vec<u8>>| {
+            let session = req.extensions().get::<Session>().unwrap().clone();
+            match req.uri().path() {
+                "/login" => {
+                    session.regenerate();
+                    session.insert("user", "ann");
+                },
+                "/logout" => session.clear(),
+                "/peek" => {},
+                _ => {
+                    let visits: u32 = session.get("visits").and_then(|visits| visits.parse().ok()).unwrap_or(0);
+                    session.insert("visits", &(visits + 1).to_string());
+                }
+            }
+            Response::new(session.get("visits").unwrap_or_default().into_bytes())
+        }).wrap(middleware)
+    }
+
+    fn request(path: &str, id: Option<&str>) -> Request<Vec<u8>> {
+        let mut builder = Request::get(path);
+        if let Some(id) = id {
+            builder = builder.header(header::COOKIE, format!("session_id={}", id));
+        }
+        builder.body(Vec::new()).unwrap()
+    }
+
+    // the session ID set by a response
+    fn issued(resp: &Response<Vec<u8>>) -> Option<String> {
+        let set_cookie = resp.headers().get(header::SET_COOKIE)?.to_str().unwrap();
+        let (pair, _) = set_cookie.split_once(';')?;
+        Some(pair.strip_prefix("session_id=")?.to_string())
+    }
+
+    fn temp_store_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("flask-sessions-{}-{}", name, std::process::id()))
+    }
+
+    fn data(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn test_session_ids() {
+        let id = new_session_id().unwrap();
+        assert!(is_valid_session_id(&id));
+        assert_ne!(id, new_session_id().unwrap());
+        assert!(!is_valid_session_id("../../etc/passwd"));
+        assert!(!is_valid_session_id(&format!("{}.tmp", id)));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let store = Arc::new(MemoryStore::new());
+        let counter = app(StoredSessionMiddleware::new(store.clone()));
+        let resp = counter.handle(request("/", None));
+        assert_eq!(resp.body(), b"1");
+        assert_eq!(resp.headers()[header::VARY], "Cookie");
+        let set_cookie = resp.headers()[header::SET_COOKIE].to_str().unwrap();
+        assert!(set_cookie.ends_with("; Path=/; HttpOnly; SameSite=Lax"));
+        let id = issued(&resp).unwrap();
+        assert!(is_valid_session_id(&id));
+
+        // the ID stays, only the stored data changes
+        let resp = counter.handle(request("/", Some(&id)));
+        assert_eq!(resp.body(), b"2");
+        assert!(!resp.headers().contains_key(header::SET_COOKIE));
+        assert_eq!(store.load(&id, DEFAULT_MAX_IDLE).unwrap(), Some(data(&[("visits", "2")])));
+
+        let resp = counter.handle(request("/logout", Some(&id)));
+        assert!(resp.headers()[header::SET_COOKIE].to_str().unwrap().starts_with("session_id=; "));
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn test_unknown_ids_are_not_adopted() {
+        let store = Arc::new(MemoryStore::new());
+        let counter = app(StoredSessionMiddleware::new(store.clone()));
+        let planted = new_session_id().unwrap();
+        let resp = counter.handle(request("/", Some(&planted)));
+        assert_eq!(resp.body(), b"1");
+        assert_ne!(issued(&resp).unwrap(), planted);
+        assert_eq!(store.load(&planted, DEFAULT_MAX_IDLE).unwrap(), None);
+
+        assert_eq!(counter.handle(request("/peek", Some("../../etc/passwd"))).body(), b"");
+    }
+
+    #[test]
+    fn test_regenerate_on_login() {
+        let store = Arc::new(MemoryStore::new());
+        let counter = app(StoredSessionMiddleware::new(store.clone()));
+        let before = issued(&counter.handle(request("/", None))).unwrap();
+
+        let resp = counter.handle(request("/login", Some(&before)));
+        let after = issued(&resp).unwrap();
+        assert_ne!(after, before);
+        assert_eq!(store.load(&before, DEFAULT_MAX_IDLE).unwrap(), None);
+        assert_eq!(store.load(&after, DEFAULT_MAX_IDLE).unwrap(), Some(data(&[("user", "ann"), ("visits", "1")])));
+        assert_eq!(store.len(), 1);
+    }
+
+    #[test]
+    fn test_idle_expiry() {
+        let max_idle = Duration::from_secs(1);
+        let dir = temp_store_dir("idle");
+        let stores: Vec<Box<dyn SessionStore>> = vec![Box::new(MemoryStore::new()), Box::new(FileStore::new(&dir).unwrap())];
+        for store in stores {
+            let (kept, dropped, swept) = (new_session_id().unwrap(), new_session_id().unwrap(), new_session_id().unwrap());
+            for id in [&kept, &dropped, &swept] {
+                store.save(id, &data(&[("user", "ann")])).unwrap();
+            }
+            thread::sleep(Duration::from_millis(600));
+            assert!(store.load(&kept, max_idle).unwrap().is_some());
+            thread::sleep(Duration::from_millis(600));
+
+            // every load counts as a use
+            assert!(store.load(&kept, max_idle).unwrap().is_some());
+            assert_eq!(store.load(&dropped, max_idle).unwrap(), None);
+            assert_eq!(store.remove_expired(max_idle).unwrap(), 1);
+            assert_eq!(store.load(&swept, Duration::from_secs(60)).unwrap(), None);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_store() {
+        let dir = temp_store_dir("files");
+        let store = FileStore::new(&dir).unwrap();
+        let id = new_session_id().unwrap();
+        let saved = data(&[("cart", "pen & paper"), ("user", "ann lee")]);
+        store.save(&id, &saved).unwrap();
+        assert_eq!(store.load(&id, DEFAULT_MAX_IDLE).unwrap(), Some(saved.clone()));
+
+        // a second store on the same directory sees the session, e.g. after a restart
+        let reopened = FileStore::new(&dir).unwrap();
+        assert_eq!(reopened.load(&id, DEFAULT_MAX_IDLE).unwrap(), Some(saved));
+        reopened.delete(&id).unwrap();
+        reopened.delete(&id).unwrap();
+        assert_eq!(store.load(&id, DEFAULT_MAX_IDLE).unwrap(), None);
+
+        assert!(store.save("../escape", &data(&[])).is_err());
+        assert!(store.load("../escape", DEFAULT_MAX_IDLE).is_err());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_store_shared_between_processes() {
+        // two stores on one directory have separate locks, like two processes
+        let dir = temp_store_dir("shared");
+        let id = new_session_id().unwrap();
+        let writers: Vec<_> = (0..2).map(|writer| {
+            let store = FileStore::new(&dir).unwrap();
+            let id = id.clone();
+            thread::spawn(move || {
+                for round in 0..50 {
+                    store.save(&id, &data(&[("writer", &writer.to_string()), ("round", &round.to_string())])).unwrap();
+                }
+            })
+        }).collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        let store = FileStore::new(&dir).unwrap();
+        assert_eq!(store.load(&id, DEFAULT_MAX_IDLE).unwrap().unwrap()["round"], "49");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        // a temporary file left behind by a crashed writer is swept once stale
+        let leftover = dir.join(format!("{}.{}.tmp", id, new_session_id().unwrap()));
+        fs::write(&leftover, "half").unwrap();
+        let long_ago = SystemTime::now() - Duration::from_secs(3600);
+        File::options().write(true).open(&leftover).unwrap().set_modified(long_ago).unwrap();
+        assert_eq!(store.remove_expired(Duration::from_secs(60)).unwrap(), 0);
+        assert!(!leftover.exists());
+        assert!(store.load(&id, DEFAULT_MAX_IDLE).unwrap().is_some());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_workers() {
+        let dir = temp_store_dir("threads");
+        let counters = [
+            app(StoredSessionMiddleware::new(MemoryStore::new())),
+            app(StoredSessionMiddleware::new(FileStore::new(&dir).unwrap())),
+        ];
+        for counter in &counters {
+            thread::scope(|scope| {
+                for _ in 0..8 {
+                    scope.spawn(|| {
+                        let id = issued(&counter.handle(request("/", None))).unwrap();
+                        for _ in 0..20 {
+                            counter.handle(request("/", Some(&id)));
+                        }
+                        assert_eq!(counter.handle(request("/peek", Some(&id))).body(), b"21");
+                    });
+                }
+            });
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,228 @@
+use super::errors::FlaskError;
+
+use http::{Method, Request};
+use http::uri::Authority;
+use std::fmt;
+use std::io::{self, BufReader, prelude::*};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+
+const CONNECTION_ESTABLISHED: &[u8] = b"HTTP/1.1 200 Connection Established\r\n\r\n";
+const BAD_GATEWAY: &[u8] = b"HTTP/1.1 502 Bad Gateway\r\ncontent-length: 0\r\nconnection: close\r\n\r\n";
+
+/// The `host:port` a CONNECT request asks to be tunnelled to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectTarget {
+    pub host: String,
+    pub port: u16,
+}
+
+impl ConnectTarget {
+    /// Parse an authority-form request target. CONNECT targets carry no scheme,
+    /// path or userinfo and must name a port.
+    pub fn parse(target: &str) -> Result<ConnectTarget, FlaskError> {
+        let bad_target = || {
+            let msg = format!("Malformed CONNECT target: {}", target);
+            FlaskError::BadRequest(msg)
+        };
+        if target.contains(['/', '?', '#', '@']) {
+            return Err( bad_target() );
+        }
+        let authority: Authority = target.parse().map_err(|_| bad_target())?;
+        let port = authority.port_u16().ok_or_else(bad_target)?;
+        let host = authority.host();
+        if host.is_empty() {
+            return Err( bad_target() );
+        }
+        // Authority::host keeps the brackets around IPv6 literals
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        Ok( ConnectTarget { host: host.to_string(), port } )
+    }
+
+    pub fn from_request<B>(req: &Request<B>) -> Result<ConnectTarget, FlaskError> {
+        if req.method() != Method::CONNECT {
+            let msg = format!("Expected a CONNECT request, got {}", req.method());
+            return Err( FlaskError::BadRequest(msg) );
+        }
+        match req.uri().authority() {
+            Some(authority) => ConnectTarget::parse(authority.as_str()),
+            None => ConnectTarget::parse(&req.uri().to_string())
+        }
+    }
+}
+
+impl fmt::Display for ConnectTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.host.contains(':') {
+            true => write!(f, "[{}]:{}", self.host, self.port),
+            false => write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// Bytes copied in each direction over the life of a tunnel.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TunnelStats {
+    pub client_to_target: u64,
+    pub target_to_client: u64,
+}
+
+fn connect_target(target: &ConnectTarget, timeout: Duration) -> Result<TcpStream, FlaskError> {
+    let addrs = match (target.host.as_str(), target.port).to_socket_addrs() {
+        Ok(addrs) => addrs,
+        Err(io_err) => {
+            let msg = format!("Error resolving CONNECT target {}: {}", target, io_err);
+            return Err( FlaskError::BadGateway(msg) );
+        }
+    };
+    let mut last_err = format!("No addresses found for CONNECT target {}", target);
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(io_err) => last_err = format!("Error connecting to CONNECT target {}: {}", target, io_err)
+        }
+    }
+    Err( FlaskError::BadGateway(last_err) )
+}
+
+// copy until EOF, then pass the EOF on by closing our write half
+fn pipe(mut from: TcpStream, mut to: TcpStream) -> u64 {
+    let copied = io::copy(&mut from, &mut to).unwrap_or(0);
+    let _ = to.shutdown(Shutdown::Write);
+    copied
+}
+
+/// Copy bytes both ways between `client` and `target` until each side has closed.
+pub fn splice(client: TcpStream, target: TcpStream) -> Result<TunnelStats, FlaskError> {
+    let (client_read, target_read) = match (client.try_clone(), target.try_clone()) {
+        (Ok(client_read), Ok(target_read)) => (client_read, target_read),
+        _ => return Err( FlaskError::InternalServerError("Error cloning tunnel streams".to_string()) )
+    };
+    let upstream = thread::spawn(move || pipe(client_read, target));
+    let target_to_client = pipe(target_read, client);
+    let client_to_target = upstream.join().unwrap_or(0);
+    Ok( TunnelStats { client_to_target, target_to_client } )
+}
+
+/// Answer a CONNECT request read from `reader`: dial the target, reply
+/// `200 Connection Established` and splice the two connections together.
+/// Bytes the client sent ahead of the reply are forwarded first.
+pub fn tunnel<B>(reader: BufReader<TcpStream>, req: &Request<B>, connect_timeout: Duration) -> Result<TunnelStats, FlaskError> {
+    let target = ConnectTarget::from_request(req)?;
+    let buffered = reader.buffer().to_vec();
+    let mut client = reader.into_inner();
+
+    let mut upstream = match connect_target(&target, connect_timeout) {
+        Ok(stream) => stream,
+        Err(flask_err) => {
+            let _ = client.write_all(BAD_GATEWAY);
+            return Err(flask_err);
+        }
+    };
+
+    let handoff = client.write_all(CONNECTION_ESTABLISHED)
+        .and_then(|_| client.flush())
+        .and_then(|_| upstream.write_all(&buffered));
+    if let Err(io_err) = handoff {
+        let msg = format!("Error establishing tunnel to {}: {}", target, io_err);
+        return Err( FlaskError::ClientClosedRequest(msg) );
+    }
+
+    let mut stats = splice(client, upstream)?;
+    stats.client_to_target += buffered.len() as u64;
+    Ok(stats)
+}
+
+
+//#################################################################################################################
+// test cases go below here
+//#################################################################################################################
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::httpx::read_http_request_from;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_parse_authority_form() {
+        let target = ConnectTarget::parse("example.com:443").unwrap();
+        assert_eq!(target, ConnectTarget { host: "example.com".to_string(), port: 443 });
+
+        let target = ConnectTarget::parse("[::1]:8443").unwrap();
+        assert_eq!(target.host, "::1");
+        assert_eq!(target.to_string(), "[::1]:8443");
+    }
+
+    #[test]
+    fn test_parse_rejects_non_authority_targets() {
+        for target in ["example.com", "https://example.com:443", "/index.html", "user@example.com:443", "example.com:443/x"] {
+            let flask_err = ConnectTarget::parse(target).err().unwrap();
+            assert_eq!(flask_err.get_msg(), format!("Malformed CONNECT target: {}", target));
+        }
+    }
+
+    #[test]
+    fn test_tunnel_splices_both_directions() {
+        // the target echoes everything back in upper case
+        let target = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target_addr = target.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = target.accept().unwrap();
+            let mut data = Vec::new();
+            stream.read_to_end(&mut data).unwrap();
+            stream.write_all(&data.to_ascii_uppercase()).unwrap();
+        });
+
+        let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+        let proxy_thread = thread::spawn(move || {
+            let (stream, _) = proxy.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let req = read_http_request_from(&mut reader).unwrap();
+            tunnel(reader, &req, Duration::from_secs(1)).unwrap()
+        });
+
+        let mut client = TcpStream::connect(proxy_addr).unwrap();
+        let payload = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\nearly ", target_addr, target_addr);
+        client.write_all(payload.as_bytes()).unwrap();
+
+        let mut reply = vec![0u8; CONNECTION_ESTABLISHED.len()];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(reply, CONNECTION_ESTABLISHED);
+
+        client.write_all(b"tunnelled bytes").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut echoed = String::new();
+        client.read_to_string(&mut echoed).unwrap();
+        assert_eq!(echoed, "EARLY TUNNELLED BYTES");
+
+        let stats = proxy_thread.join().unwrap();
+        assert_eq!(stats, TunnelStats { client_to_target: 21, target_to_client: 21 });
+    }
+
+    #[test]
+    fn test_tunnel_reports_bad_gateway() {
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+        let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+        let proxy_thread = thread::spawn(move || {
+            let (stream, _) = proxy.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let req = read_http_request_from(&mut reader).unwrap();
+            tunnel(reader, &req, Duration::from_secs(1))
+        });
+
+        let mut client = TcpStream::connect(proxy_addr).unwrap();
+        client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", closed).as_bytes()).unwrap();
+        let mut reply = String::new();
+        client.read_to_string(&mut reply).unwrap();
+        assert!(reply.starts_with("HTTP/1.1 502 Bad Gateway\r\n"));
+
+        match proxy_thread.join().unwrap() {
+            Err(FlaskError::BadGateway(_)) => {},
+            other => panic!("expected BadGateway, got {:?}", other.map(|_| ()))
+        }
+    }
+}
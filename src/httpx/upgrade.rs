@@ -0,0 +1,200 @@
+use super::{
+    errors::FlaskError,
+    header_has_token,
+    write_http_response
+};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use http::{header, Method, Request, Response, StatusCode, Version};
+use sha1::{Digest, Sha1};
+use std::io::{BufReader, Read, Write};
+
+// RFC 6455 section 1.3
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const WEBSOCKET_VERSION: &str = "13";
+
+/// A connection handed off after a successful upgrade. `buffered` holds the
+/// bytes the HTTP reader had already pulled off the stream past the end of the
+/// request; they belong to the new protocol and must be consumed first.
+#[derive(Debug)]
+pub struct Upgraded<S> {
+    pub stream: S,
+    pub buffered: Vec<u8>,
+}
+
+impl<S> Upgraded<S> {
+    fn from_reader(reader: BufReader<S>) -> Upgraded<S> {
+        let buffered = reader.buffer().to_vec();
+        Upgraded { stream: reader.into_inner(), buffered }
+    }
+
+    pub fn into_parts(self) -> (S, Vec<u8>) {
+        (self.stream, self.buffered)
+    }
+}
+
+/// True for requests asking to switch protocols (`Connection: Upgrade` plus an `Upgrade` header).
+pub fn is_upgrade_request<B>(req: &Request<B>) -> bool {
+    header_has_token(req.headers(), header::CONNECTION, "upgrade") && req.headers().contains_key(header::UPGRADE)
+}
+
+/// The protocol named in the `Upgrade` header of an upgrade request.
+pub fn upgrade_protocol<B>(req: &Request<B>) -> Option<&str> {
+    if !is_upgrade_request(req) {
+        return None;
+    }
+    req.headers().get(header::UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim())
+}
+
+pub fn is_websocket_upgrade<B>(req: &Request<B>) -> bool {
+    is_upgrade_request(req) && header_has_token(req.headers(), header::UPGRADE, "websocket")
+}
+
+/// The `Sec-WebSocket-Accept` value for a client's `Sec-WebSocket-Key`.
+pub fn websocket_accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.trim().as_bytes());
+    hasher.update(WEBSOCKET_GUID.as_bytes());
+    BASE64.encode(hasher.finalize())
+}
+
+fn validate_websocket_request<B>(req: &Request<B>) -> Result<String, FlaskError> {
+    if req.method() != Method::GET {
+        let msg = format!("WebSocket handshake requires GET, got {}", req.method());
+        return Err( FlaskError::BadRequest(msg) );
+    }
+    if req.version() != Version::HTTP_11 {
+        return Err( FlaskError::BadRequest("WebSocket handshake requires HTTP/1.1".to_string()) );
+    }
+    if !is_websocket_upgrade(req) {
+        return Err( FlaskError::BadRequest("Not a WebSocket upgrade request".to_string()) );
+    }
+
+    let headers = req.headers();
+    match headers.get(header::SEC_WEBSOCKET_VERSION).and_then(|val| val.to_str().ok()) {
+        Some(version) if version.trim() == WEBSOCKET_VERSION => {},
+        Some(version) => {
+            let msg = format!("Unsupported Sec-WebSocket-Version: {}", version);
+            return Err( FlaskError::BadRequest(msg) );
+        },
+        None => return Err( FlaskError::BadRequest("Missing Sec-WebSocket-Version header".to_string()) )
+    }
+
+    let key = match headers.get(header::SEC_WEBSOCKET_KEY).and_then(|val| val.to_str().ok()) {
+        Some(key) => key.trim(),
+        None => return Err( FlaskError::BadRequest("Missing Sec-WebSocket-Key header".to_string()) )
+    };
+    // the key must be a base64 encoded 16-byte nonce
+    match BASE64.decode(key) {
+        Ok(nonce) if nonce.len() == 16 => Ok(key.to_string()),
+        _ => {
+            let msg = format!("Invalid Sec-WebSocket-Key: {}", key);
+            Err( FlaskError::BadRequest(msg) )
+        }
+    }
+}
+
+/// Validate a WebSocket upgrade request read from `reader` and answer it with
+/// `101 Switching Protocols`. On success the raw stream is handed back together
+/// with any bytes already buffered past the request.
+pub fn accept_websocket<S: Read + Write, B>(mut reader: BufReader<S>, req: &Request<B>) -> Result<Upgraded<S>, FlaskError> {
+    let key = validate_websocket_request(req)?;
+
+    let resp = match Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::UPGRADE, "websocket")
+        .header(header::CONNECTION, "Upgrade")
+        .header(header::SEC_WEBSOCKET_ACCEPT, websocket_accept_key(&key))
+        .body(Vec::new()) {
+        Ok(resp) => resp,
+        Err(http_err) => return Err( FlaskError::InternalServerError(http_err.to_string()) )
+    };
+    write_http_response(reader.get_mut(), &resp)?;
+
+    Ok( Upgraded::from_reader(reader) )
+}
+
+
+//#################################################################################################################
+// test cases go below here
+//#################################################################################################################
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::httpx::{read_http_request_from, read_http_response_from};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    const HANDSHAKE: &str = "GET /chat HTTP/1.1\r\n\
+        Host: server.example.com\r\n\
+        Upgrade: websocket\r\n\
+        Connection: keep-alive, Upgrade\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+        Sec-WebSocket-Version: 13\r\n\r\n";
+
+    fn parse(raw: &str) -> Request<Vec<u8>> {
+        let mut reader = BufReader::new(raw.as_bytes());
+        read_http_request_from(&mut reader).unwrap()
+    }
+
+    #[test]
+    fn test_websocket_accept_key_rfc_example() {
+        assert_eq!(websocket_accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn test_upgrade_detection() {
+        let req = parse(HANDSHAKE);
+        assert!(is_upgrade_request(&req));
+        assert!(is_websocket_upgrade(&req));
+        assert_eq!(upgrade_protocol(&req), Some("websocket"));
+
+        let plain = parse("GET / HTTP/1.1\r\nUpgrade: websocket\r\n\r\n");
+        assert!(!is_upgrade_request(&plain));
+        assert_eq!(upgrade_protocol(&plain), None);
+    }
+
+    #[test]
+    fn test_validate_rejects_bad_key() {
+        let raw = HANDSHAKE.replace("dGhlIHNhbXBsZSBub25jZQ==", "c2hvcnQ=");
+        let flask_err = validate_websocket_request(&parse(&raw)).err().unwrap();
+        assert_eq!(flask_err.get_msg(), "Invalid Sec-WebSocket-Key: c2hvcnQ=");
+    }
+
+    #[test]
+    fn test_validate_rejects_wrong_version() {
+        let raw = HANDSHAKE.replace("Sec-WebSocket-Version: 13", "Sec-WebSocket-Version: 8");
+        let flask_err = validate_websocket_request(&parse(&raw)).err().unwrap();
+        assert_eq!(flask_err.get_msg(), "Unsupported Sec-WebSocket-Version: 8");
+    }
+
+    #[test]
+    fn test_accept_websocket_keeps_buffered_bytes() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let req = read_http_request_from(&mut reader).unwrap();
+            let upgraded = accept_websocket(reader, &req).unwrap();
+            upgraded.buffered
+        });
+
+        // the client sends its first frame bytes right behind the handshake
+        let mut client = TcpStream::connect(addr).unwrap();
+        let mut payload = HANDSHAKE.as_bytes().to_vec();
+        payload.extend_from_slice(b"early-bytes");
+        client.write_all(&payload).unwrap();
+
+        let mut reader = BufReader::new(client);
+        let resp = read_http_response_from(&mut reader).unwrap();
+        assert_eq!(resp.status(), StatusCode::SWITCHING_PROTOCOLS);
+        assert_eq!(resp.headers()["sec-websocket-accept"], "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+        assert_eq!(server.join().unwrap(), b"early-bytes");
+    }
+}
@@ -0,0 +1,634 @@
+use super::{
+    errors::FlaskError,
+    forward::remove_hop_by_hop_headers,
+    read_http_response_for,
+    write_http_request
+};
+
+use http::{HeaderName, Request, Response};
+use std::io::BufReader;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const VIRTUAL_NODES_PER_UPSTREAM: usize = 100;
+const DEFAULT_MAX_FAILURES: usize = 3;
+const DEFAULT_EJECTION_TIME: Duration = Duration::from_secs(30);
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+// ***************************************************************************
+// selection strategies
+// ***************************************************************************
+
+/// What a consistent-hash balancer hashes to pick an upstream.
+#[derive(Clone, Debug)]
+pub enum HashKey {
+    Header(HeaderName),
+    Path,
+}
+
+#[derive(Clone, Debug)]
+pub enum Balancer {
+    RoundRobin,
+    LeastConnections,
+    ConsistentHash(HashKey),
+}
+
+// FNV-1a, so the hash ring is stable across processes and rust releases
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// ***************************************************************************
+// upstreams
+// ***************************************************************************
+
+#[derive(Debug)]
+pub struct Upstream {
+    addr: SocketAddr,
+    active: AtomicUsize,
+    failures: AtomicUsize,
+    healthy: AtomicBool,
+    ejected_until: Mutex<Option<Instant>>,
+}
+
+impl Upstream {
+    fn new(addr: SocketAddr) -> Upstream {
+        Upstream {
+            addr,
+            active: AtomicUsize::new(0),
+            failures: AtomicUsize::new(0),
+            healthy: AtomicBool::new(true),
+            ejected_until: Mutex::new(None),
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Number of requests currently in flight to this upstream.
+    pub fn active_connections(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::SeqCst)
+    }
+
+    pub fn is_ejected(&self) -> bool {
+        match *self.ejected_until.lock().unwrap() {
+            Some(until) => Instant::now() < until,
+            None => false
+        }
+    }
+
+    pub fn is_available(&self) -> bool {
+        self.is_healthy() && !self.is_ejected()
+    }
+
+    fn record_success(&self) {
+        self.failures.store(0, Ordering::SeqCst);
+    }
+
+    fn record_failure(&self, max_failures: usize, ejection_time: Duration) {
+        let failures = self.failures.fetch_add(1, Ordering::SeqCst) + 1;
+        if failures >= max_failures {
+            self.failures.store(0, Ordering::SeqCst);
+            *self.ejected_until.lock().unwrap() = Some(Instant::now() + ejection_time);
+        }
+    }
+}
+
+/// An upstream picked by the pool. The upstream counts as having an active
+/// connection until the guard is dropped.
+#[derive(Debug)]
+pub struct UpstreamGuard {
+    upstream: Arc<Upstream>,
+}
+
+impl UpstreamGuard {
+    fn new(upstream: Arc<Upstream>) -> UpstreamGuard {
+        upstream.active.fetch_add(1, Ordering::SeqCst);
+        UpstreamGuard { upstream }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.upstream.addr
+    }
+}
+
+impl Drop for UpstreamGuard {
+    fn drop(&mut self) {
+        self.upstream.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// ***************************************************************************
+// health checks
+// ***************************************************************************
+
+#[derive(Clone, Debug)]
+pub struct HealthCheck {
+    pub path: String,
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl HealthCheck {
+    pub fn new(path: &str) -> HealthCheck {
+        HealthCheck {
+            path: path.to_string(),
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(2),
+        }
+    }
+
+    pub fn interval(mut self, interval: Duration) -> HealthCheck {
+        self.interval = interval;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> HealthCheck {
+        self.timeout = timeout;
+        self
+    }
+
+    fn probe(&self, addr: SocketAddr) -> bool {
+        let req = match Request::get(self.path.as_str())
+            .header(http::header::HOST, addr.to_string())
+            .header(http::header::CONNECTION, "close")
+            .body(Vec::new()) {
+            Ok(req) => req,
+            Err(_) => return false
+        };
+        match send_request(addr, &req, self.timeout, self.timeout) {
+            Ok(resp) => resp.status().is_success(),
+            Err(_) => false
+        }
+    }
+}
+
+fn connect(addr: SocketAddr, timeout: Duration) -> Result<TcpStream, FlaskError> {
+    match TcpStream::connect_timeout(&addr, timeout) {
+        Ok(stream) => Ok(stream),
+        Err(io_err) => {
+            let msg = format!("Error connecting to upstream {}: {}", addr, io_err);
+            Err( FlaskError::BadGateway(msg) )
+        }
+    }
+}
+
+fn send_request(addr: SocketAddr, req: &Request<Vec<u8>>, connect_timeout: Duration, io_timeout: Duration) -> Result<Response<Vec<u8>>, FlaskError> {
+    let mut stream = connect(addr, connect_timeout)?;
+    // a failure to set timeouts only means we may block longer than asked
+    let _ = stream.set_read_timeout(Some(io_timeout));
+    let _ = stream.set_write_timeout(Some(io_timeout));
+    write_http_request(&mut stream, req)?;
+    // the method decides framing: a HEAD response advertises a length but has no body
+    read_http_response_for(&mut BufReader::new(stream), req.method())
+}
+
+// ***************************************************************************
+// the pool
+// ***************************************************************************
+
+#[derive(Debug)]
+pub struct UpstreamPool {
+    upstreams: Vec<Arc<Upstream>>,
+    balancer: Balancer,
+    next: AtomicUsize,
+    ring: Vec<(u64, usize)>,
+    max_failures: usize,
+    ejection_time: Duration,
+    connect_timeout: Duration,
+    io_timeout: Duration,
+    health_check: Option<HealthCheck>,
+}
+
+impl UpstreamPool {
+    pub fn new(addrs: Vec<SocketAddr>, balancer: Balancer) -> UpstreamPool {
+        let upstreams: Vec<Arc<Upstream>> = addrs.into_iter()
+            .map(|addr| Arc::new(Upstream::new(addr)))
+            .collect();
+
+        let mut ring = Vec::with_capacity(upstreams.len() * VIRTUAL_NODES_PER_UPSTREAM);
+        for (idx, upstream) in upstreams.iter().enumerate() {
+            for vnode in 0..VIRTUAL_NODES_PER_UPSTREAM {
+                let node_key = format!("{}#{}", upstream.addr, vnode);
+                ring.push((fnv1a(node_key.as_bytes()), idx));
+            }
+        }
+        ring.sort();
+
+        UpstreamPool {
+            upstreams,
+            balancer,
+            next: AtomicUsize::new(0),
+            ring,
+            max_failures: DEFAULT_MAX_FAILURES,
+            ejection_time: DEFAULT_EJECTION_TIME,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            io_timeout: DEFAULT_IO_TIMEOUT,
+            health_check: None,
+        }
+    }
+
+    /// Eject an upstream for `ejection_time` after `max_failures` consecutive failures.
+    pub fn passive_ejection(mut self, max_failures: usize, ejection_time: Duration) -> UpstreamPool {
+        self.max_failures = max_failures.max(1);
+        self.ejection_time = ejection_time;
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> UpstreamPool {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// How long a read from or write to an upstream may block before the
+    /// exchange fails and counts against the upstream.
+    pub fn io_timeout(mut self, timeout: Duration) -> UpstreamPool {
+        self.io_timeout = timeout;
+        self
+    }
+
+    pub fn health_check(mut self, health_check: HealthCheck) -> UpstreamPool {
+        self.health_check = Some(health_check);
+        self
+    }
+
+    pub fn upstreams(&self) -> &[Arc<Upstream>] {
+        &self.upstreams
+    }
+
+    pub fn select(&self, req: &Request<Vec<u8>>) -> Result<UpstreamGuard, FlaskError> {
+        let selected = match &self.balancer {
+            Balancer::RoundRobin => self.select_round_robin(),
+            Balancer::LeastConnections => self.select_least_connections(),
+            Balancer::ConsistentHash(key) => self.select_consistent_hash(key, req),
+        };
+        match selected {
+            Some(idx) => Ok( UpstreamGuard::new(self.upstreams[idx].clone()) ),
+            None => Err( FlaskError::BadGateway("No healthy upstream available".to_string()) )
+        }
+    }
+
+    fn select_round_robin(&self) -> Option<usize> {
+        let count = self.upstreams.len();
+        for _ in 0..count {
+            let idx = self.next.fetch_add(1, Ordering::SeqCst) % count;
+            if self.upstreams[idx].is_available() {
+                return Some(idx);
+            }
+        }
+        None
+    }
+
+    fn select_least_connections(&self) -> Option<usize> {
+        let count = self.upstreams.len();
+        if count == 0 {
+            return None;
+        }
+        // rotate the starting point so ties are spread round-robin
+        let start = self.next.fetch_add(1, Ordering::SeqCst) % count;
+        (0..count)
+            .map(|offset| (start + offset) % count)
+            .filter(|idx| self.upstreams[*idx].is_available())
+            .min_by_key(|idx| self.upstreams[*idx].active_connections())
+    }
+
+    fn select_consistent_hash(&self, key: &HashKey, req: &Request<Vec<u8>>) -> Option<usize> {
+        if self.ring.is_empty() {
+            return None;
+        }
+        let hash_input: &[u8] = match key {
+            HashKey::Header(name) => match req.headers().get(name) {
+                Some(value) => value.as_bytes(),
+                None => b""
+            },
+            HashKey::Path => req.uri().path().as_bytes(),
+        };
+        let hash = fnv1a(hash_input);
+        let start = self.ring.partition_point(|(node_hash, _)| *node_hash < hash);
+        (0..self.ring.len())
+            .map(|offset| self.ring[(start + offset) % self.ring.len()].1)
+            .find(|idx| self.upstreams[*idx].is_available())
+    }
+
+    /// Send `req` to an upstream picked by the balancer and read its response,
+    /// without hop-by-hop headers in either direction. Failed exchanges,
+    /// including ones that time out, count towards the upstream's passive ejection.
+    pub fn forward(&self, req: &Request<Vec<u8>>) -> Result<Response<Vec<u8>>, FlaskError> {
+        let guard = self.select(req)?;
+        let upstream = guard.upstream.clone();
+
+        let mut outgoing = Request::new(req.body().clone());
+        *outgoing.method_mut() = req.method().clone();
+        *outgoing.uri_mut() = req.uri().clone();
+        *outgoing.version_mut() = req.version();
+        *outgoing.headers_mut() = remove_hop_by_hop_headers(req.headers());
+
+        let result = send_request(upstream.addr, &outgoing, self.connect_timeout, self.io_timeout).map(|mut resp| {
+            *resp.headers_mut() = remove_hop_by_hop_headers(resp.headers());
+            resp
+        });
+        match &result {
+            Ok(_) => upstream.record_success(),
+            Err(_) => upstream.record_failure(self.max_failures, self.ejection_time),
+        }
+        result
+    }
+
+    /// Run one round of active health checks against every upstream.
+    pub fn check_health(&self) {
+        if let Some(health_check) = &self.health_check {
+            for upstream in self.upstreams.iter() {
+                let healthy = health_check.probe(upstream.addr);
+                upstream.healthy.store(healthy, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// Run health checks every `interval` on a background thread. The thread
+    /// exits once the last reference to the pool is dropped.
+    pub fn spawn_health_checks(pool: &Arc<UpstreamPool>) -> Option<thread::JoinHandle<()>> {
+        let interval = pool.health_check.as_ref()?.interval;
+        let weak_pool: Weak<UpstreamPool> = Arc::downgrade(pool);
+        let handle = thread::spawn(move || {
+            loop {
+                match weak_pool.upgrade() {
+                    Some(pool) => pool.check_health(),
+                    None => break
+                }
+                thread::sleep(interval);
+            }
+        });
+        Some(handle)
+    }
+}
+
+
+//#################################################################################################################
+// test cases go below here
+//#################################################################################################################
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    // a local stand-in for a backend replica: answers every request with its
+    // own name, and /health with `health_status`
+    fn spawn_backend(name: &'static str, health_status: u16) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue
+                };
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                        break;
+                    }
+                }
+                let (status, body) = match request_line.starts_with("GET /health ") {
+                    true => (health_status, "health"),
+                    false => (200, name)
+                };
+                let payload = format!("HTTP/1.1 {} Status\r\ncontent-length: {}\r\n\r\n{}", status, body.len(), body);
+                let _ = stream.write_all(payload.as_bytes());
+            }
+        });
+        addr
+    }
+
+    fn closed_port() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    fn get(path: &str) -> Request<Vec<u8>> {
+        Request::get(path).body(Vec::new()).unwrap()
+    }
+
+    fn body_of(resp: Response<Vec<u8>>) -> String {
+        String::from_utf8(resp.into_body()).unwrap()
+    }
+
+    #[test]
+    fn test_round_robin_cycles_through_upstreams() {
+        let a = spawn_backend("a", 200);
+        let b = spawn_backend("b", 200);
+        let pool = UpstreamPool::new(vec![a, b], Balancer::RoundRobin);
+
+        let bodies: Vec<String> = (0..4)
+            .map(|_| body_of(pool.forward(&get("/")).unwrap()))
+            .collect();
+        assert_eq!(bodies, vec!["a", "b", "a", "b"]);
+    }
+
+    #[test]
+    fn test_least_connections_avoids_busy_upstream() {
+        let a = spawn_backend("a", 200);
+        let b = spawn_backend("b", 200);
+        let pool = UpstreamPool::new(vec![a, b], Balancer::LeastConnections);
+
+        let busy = pool.select(&get("/")).unwrap();
+        for _ in 0..3 {
+            let guard = pool.select(&get("/")).unwrap();
+            assert_ne!(guard.addr(), busy.addr());
+        }
+        drop(busy);
+        assert!(pool.upstreams().iter().all(|u| u.active_connections() == 0));
+    }
+
+    #[test]
+    fn test_consistent_hash_by_header_is_sticky() {
+        let addrs = vec![spawn_backend("a", 200), spawn_backend("b", 200), spawn_backend("c", 200)];
+        let key = HashKey::Header(HeaderName::from_static("x-user"));
+        let pool = UpstreamPool::new(addrs, Balancer::ConsistentHash(key));
+
+        for user in ["alice", "bob", "carol"] {
+            let req = Request::get("/").header("x-user", user).body(Vec::new()).unwrap();
+            let first = pool.select(&req).unwrap().addr();
+            for _ in 0..5 {
+                assert_eq!(pool.select(&req).unwrap().addr(), first);
+            }
+        }
+    }
+
+    #[test]
+    fn test_consistent_hash_by_path_skips_ejected_upstream() {
+        let addrs = vec![spawn_backend("a", 200), spawn_backend("b", 200)];
+        let pool = UpstreamPool::new(addrs, Balancer::ConsistentHash(HashKey::Path))
+            .passive_ejection(1, Duration::from_secs(60));
+
+        let req = get("/some/path");
+        let first = pool.select(&req).unwrap().addr();
+        let upstream = pool.upstreams().iter().find(|u| u.addr() == first).unwrap();
+        upstream.record_failure(1, Duration::from_secs(60));
+
+        assert_ne!(pool.select(&req).unwrap().addr(), first);
+    }
+
+    #[test]
+    fn test_passive_ejection_after_consecutive_failures() {
+        let good = spawn_backend("good", 200);
+        let bad = closed_port();
+        let pool = UpstreamPool::new(vec![bad, good], Balancer::RoundRobin)
+            .passive_ejection(2, Duration::from_secs(60));
+
+        let mut failures = 0;
+        for _ in 0..6 {
+            match pool.forward(&get("/")) {
+                Ok(resp) => assert_eq!(body_of(resp), "good"),
+                Err(FlaskError::BadGateway(_)) => failures += 1,
+                Err(err) => panic!("unexpected error {:?}", err),
+            }
+        }
+        assert_eq!(failures, 2);
+        assert!(pool.upstreams()[0].is_ejected());
+        assert!(!pool.upstreams()[1].is_ejected());
+    }
+
+    #[test]
+    fn test_stalled_upstream_times_out_and_is_ejected() {
+        // accepts connections but never answers
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stalled = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let _held: Vec<_> = listener.incoming().collect();
+        });
+        let pool = UpstreamPool::new(vec![stalled], Balancer::RoundRobin)
+            .io_timeout(Duration::from_millis(200))
+            .passive_ejection(1, Duration::from_secs(60));
+
+        let started = Instant::now();
+        assert!(pool.forward(&get("/")).is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(pool.upstreams()[0].is_ejected());
+    }
+
+    #[test]
+    fn test_forward_strips_hop_by_hop_headers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let backend = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut head = String::new();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                    break;
+                }
+                head.push_str(&line);
+            }
+            let payload = "HTTP/1.1 200 OK\r\nconnection: keep-alive, x-internal\r\nkeep-alive: timeout=5\r\nx-internal: 1\r\ncontent-length: 2\r\n\r\nok";
+            stream.write_all(payload.as_bytes()).unwrap();
+            head
+        });
+
+        let pool = UpstreamPool::new(vec![addr], Balancer::RoundRobin);
+        let req = Request::get("/")
+            .header("Connection", "keep-alive, x-hop")
+            .header("X-Hop", "1")
+            .header("Proxy-Authorization", "Basic Zm9vOmJhcg==")
+            .header("X-Kept", "yes")
+            .body(Vec::new())
+            .unwrap();
+        let resp = pool.forward(&req).unwrap();
+        assert!(!resp.headers().contains_key("connection"));
+        assert!(!resp.headers().contains_key("keep-alive"));
+        assert!(!resp.headers().contains_key("x-internal"));
+        assert_eq!(body_of(resp), "ok");
+
+        let head = backend.join().unwrap();
+        assert!(head.contains("x-kept: yes\r\n"));
+        assert!(!head.contains("connection"));
+        assert!(!head.contains("x-hop"));
+        assert!(!head.contains("proxy-authorization"));
+    }
+
+    #[test]
+    fn test_head_response_is_read_without_a_body() {
+        // answers HEAD with the length a GET would have, then keeps the connection open
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (done_tx, done_rx) = std::sync::mpsc::channel::<()>();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                    break;
+                }
+            }
+            stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 5\r\n\r\n").unwrap();
+            let _ = done_rx.recv();
+        });
+
+        let pool = UpstreamPool::new(vec![addr], Balancer::RoundRobin)
+            .io_timeout(Duration::from_millis(500))
+            .passive_ejection(1, Duration::from_secs(60));
+        let req = Request::head("/").body(Vec::new()).unwrap();
+        let resp = pool.forward(&req).unwrap();
+        assert_eq!(resp.headers()["content-length"], "5");
+        assert!(resp.body().is_empty());
+        assert!(!pool.upstreams()[0].is_ejected());
+        done_tx.send(()).unwrap();
+    }
+
+    #[test]
+    fn test_all_upstreams_down() {
+        let pool = UpstreamPool::new(vec![closed_port()], Balancer::RoundRobin)
+            .passive_ejection(1, Duration::from_secs(60));
+        assert!(pool.forward(&get("/")).is_err());
+
+        let flask_err = pool.forward(&get("/")).err().unwrap();
+        assert_eq!(flask_err.get_msg(), "No healthy upstream available");
+    }
+
+    #[test]
+    fn test_active_health_checks() {
+        let healthy = spawn_backend("healthy", 200);
+        let sick = spawn_backend("sick", 503);
+        let pool = UpstreamPool::new(vec![healthy, sick], Balancer::RoundRobin)
+            .health_check(HealthCheck::new("/health").timeout(Duration::from_secs(1)));
+
+        pool.check_health();
+        assert!(pool.upstreams()[0].is_healthy());
+        assert!(!pool.upstreams()[1].is_healthy());
+
+        for _ in 0..3 {
+            assert_eq!(body_of(pool.forward(&get("/")).unwrap()), "healthy");
+        }
+    }
+
+    #[test]
+    fn test_health_check_thread_stops_with_pool() {
+        let pool = Arc::new(
+            UpstreamPool::new(vec![spawn_backend("a", 200)], Balancer::RoundRobin)
+                .health_check(HealthCheck::new("/health").interval(Duration::from_millis(10)))
+        );
+        let handle = UpstreamPool::spawn_health_checks(&pool).unwrap();
+        drop(pool);
+        handle.join().unwrap();
+    }
+}
@@ -3,4 +3,6 @@ extern crate nom;
 
 mod combinators;
 pub mod httpx;
+pub mod testing;
+pub mod ws;
 
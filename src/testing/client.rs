@@ -0,0 +1,142 @@
+use super::duplex::{duplex, DuplexStream};
+
+use crate::httpx::server::{serve_request, Handler};
+use crate::httpx::{read_http_response_for, write_http_request, FlaskError};
+
+use http::{Method, Request, Response};
+use std::io::{BufReader, Write};
+use std::sync::Arc;
+
+/// Drives a handler the way the server would, without sockets. Every request
+/// is written with the crate's request writer into an in-memory stream, read
+/// back by the server's parser, dispatched, and its response written and parsed
+/// again, so framing and header handling are exercised end to end.
+pub struct TestClient {
+    handler: Arc<dyn Handler>,
+}
+
+impl TestClient {
+    pub fn new<H: Handler>(handler: H) -> TestClient {
+        TestClient { handler: Arc::new(handler) }
+    }
+
+    pub fn send(&self, req: &Request<Vec<u8>>) -> Result<Response<Vec<u8>>, FlaskError> {
+        let (mut client, server) = duplex();
+        write_http_request(&mut client, req)?;
+        self.exchange(client, server, req.method())
+    }
+
+    /// Send raw request bytes, e.g. to test how malformed requests are answered.
+    pub fn send_raw(&self, raw: &[u8]) -> Result<Response<Vec<u8>>, FlaskError> {
+        let (mut client, server) = duplex();
+        if let Err(io_err) = client.write_all(raw) {
+            return Err( FlaskError::InternalServerError(io_err.to_string()) );
+        }
+        let method = match raw.starts_with(b"HEAD ") {
+            true => Method::HEAD,
+            false => Method::GET
+        };
+        self.exchange(client, server, &method)
+    }
+
+    fn exchange(&self, client: DuplexStream, server: DuplexStream, method: &Method) -> Result<Response<Vec<u8>>, FlaskError> {
+        // the whole request is written, so a truncated one reads as end of stream
+        client.shutdown_write();
+        let mut server = BufReader::new(server);
+        serve_request(&mut server, &*self.handler, &|| false);
+        drop(server);
+        read_http_response_for(&mut BufReader::new(client), method)
+    }
+
+    /// Send `req`, panicking if it cannot be written or the response cannot be parsed.
+    pub fn request(&self, req: Request<Vec<u8>>) -> Response<Vec<u8>> {
+        match self.send(&req) {
+            Ok(resp) => resp,
+            Err(flask_err) => panic!("TestClient: {} {} failed: {}", req.method(), req.uri(), flask_err)
+        }
+    }
+
+    pub fn get(&self, uri: &str) -> Response<Vec<u8>> {
+        self.request(build(Method::GET, uri, Vec::new()))
+    }
+
+    pub fn head(&self, uri: &str) -> Response<Vec<u8>> {
+        self.request(build(Method::HEAD, uri, Vec::new()))
+    }
+
+    pub fn post<B: Into<Vec<u8>>>(&self, uri: &str, body: B) -> Response<Vec<u8>> {
+        self.request(build(Method::POST, uri, body.into()))
+    }
+
+    pub fn put<B: Into<Vec<u8>>>(&self, uri: &str, body: B) -> Response<Vec<u8>> {
+        self.request(build(Method::PUT, uri, body.into()))
+    }
+
+    pub fn delete(&self, uri: &str) -> Response<Vec<u8>> {
+        self.request(build(Method::DELETE, uri, Vec::new()))
+    }
+}
+
+fn build(method: Method, uri: &str, body: Vec<u8>) -> Request<Vec<u8>> {
+    match Request::builder().method(method).uri(uri).body(body) {
+        Ok(req) => req,
+        Err(http_err) => panic!("TestClient: invalid request URI {}: {}", uri, http_err)
+    }
+}
+
+
+//#################################################################################################################
+// test cases go below here
+//#################################################################################################################
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::httpx::router::{PathParams, Router};
+    use http::{header, StatusCode};
+
+    fn app() -> Router {
+        Router::new()
+            .get("/hello/<name>", |req: Request<Vec<u8>>| {
+                let name = req.extensions().get::<PathParams>().unwrap().get("name").unwrap().to_string();
+                Response::new(format!("hello {}", name).into_bytes())
+            })
+            .post("/echo", |req: Request<Vec<u8>>| {
+                let length = req.headers()[header::CONTENT_LENGTH].to_str().unwrap().to_string();
+                Response::builder().header("x-received-length", length).body(req.into_body()).unwrap()
+            })
+            .get("/panic", |_req: Request<Vec<u8>>| -> Response<Vec<u8>> { panic!("bug") })
+    }
+
+    #[test]
+    fn test_round_trip_through_the_wire_format() {
+        let client = TestClient::new(app());
+        assert_eq!(client.get("/hello/world").body(), b"hello world");
+
+        let resp = client.post("/echo", "some body");
+        assert_eq!(resp.body(), b"some body");
+        assert_eq!(resp.headers()["x-received-length"], "9");
+        assert_eq!(resp.headers()[header::CONTENT_LENGTH], "9");
+    }
+
+    #[test]
+    fn test_server_behaviour_applies() {
+        let client = TestClient::new(app());
+        let resp = client.head("/hello/x");
+        assert_eq!(resp.headers()[header::CONTENT_LENGTH], "7");
+        assert!(resp.body().is_empty());
+
+        assert_eq!(client.get("/panic").status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(client.delete("/echo").status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[test]
+    fn test_malformed_and_truncated_requests() {
+        let client = TestClient::new(app());
+        let resp = client.send_raw(b"GET /hello/x HTTP/1.1\r\nbroken header\r\n\r\n").unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(resp.body(), b"Malformed Header: missing colon after field name");
+
+        let resp = client.send_raw(b"POST /echo HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort").unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+}
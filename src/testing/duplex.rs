@@ -0,0 +1,155 @@
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Write};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+#[derive(Default)]
+struct Pipe {
+    buf: VecDeque<u8>,
+    // no more bytes will be written: readers see end of stream once `buf` is empty
+    write_closed: bool,
+    // nobody reads any more: writers get a broken pipe
+    read_closed: bool,
+}
+
+#[derive(Default)]
+struct Channel {
+    pipe: Mutex<Pipe>,
+    readable: Condvar,
+}
+
+/// One end of an in-memory, full-duplex byte stream, standing in for a
+/// `TcpStream` in tests. Bytes written to one end are read from the other.
+/// Reads block until data arrives or the other end stops writing; dropping an
+/// end closes both directions for its peer.
+pub struct DuplexStream {
+    incoming: Arc<Channel>,
+    outgoing: Arc<Channel>,
+    read_timeout: Option<Duration>,
+}
+
+/// A connected pair of in-memory streams.
+pub fn duplex() -> (DuplexStream, DuplexStream) {
+    let a_to_b = Arc::new(Channel::default());
+    let b_to_a = Arc::new(Channel::default());
+    let a = DuplexStream { incoming: b_to_a.clone(), outgoing: a_to_b.clone(), read_timeout: None };
+    let b = DuplexStream { incoming: a_to_b, outgoing: b_to_a, read_timeout: None };
+    (a, b)
+}
+
+impl DuplexStream {
+    /// Make reads fail with `TimedOut` after waiting this long, like
+    /// `TcpStream::set_read_timeout`.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    /// Signal end of stream to the peer, like `TcpStream::shutdown(Shutdown::Write)`.
+    pub fn shutdown_write(&self) {
+        self.outgoing.pipe.lock().unwrap().write_closed = true;
+        self.outgoing.readable.notify_all();
+    }
+
+    /// Bytes written by the peer and not read yet.
+    pub fn available(&self) -> usize {
+        self.incoming.pipe.lock().unwrap().buf.len()
+    }
+}
+
+impl Read for DuplexStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut pipe = self.incoming.pipe.lock().unwrap();
+        while pipe.buf.is_empty() && !pipe.write_closed {
+            pipe = match self.read_timeout {
+                Some(timeout) => {
+                    let (pipe, wait) = self.incoming.readable.wait_timeout(pipe, timeout).unwrap();
+                    if wait.timed_out() && pipe.buf.is_empty() && !pipe.write_closed {
+                        return Err( io::Error::new(ErrorKind::TimedOut, "duplex read timed out") );
+                    }
+                    pipe
+                },
+                None => self.incoming.readable.wait(pipe).unwrap()
+            };
+        }
+        let count = buf.len().min(pipe.buf.len());
+        for (slot, byte) in buf.iter_mut().zip(pipe.buf.drain(..count)) {
+            *slot = byte;
+        }
+        Ok(count)
+    }
+}
+
+impl Write for DuplexStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut pipe = self.outgoing.pipe.lock().unwrap();
+        if pipe.read_closed || pipe.write_closed {
+            return Err( io::Error::new(ErrorKind::BrokenPipe, "duplex peer is closed") );
+        }
+        pipe.buf.extend(buf);
+        self.outgoing.readable.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for DuplexStream {
+    fn drop(&mut self) {
+        self.shutdown_write();
+        self.incoming.pipe.lock().unwrap().read_closed = true;
+    }
+}
+
+
+//#################################################################################################################
+// test cases go below here
+//#################################################################################################################
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_bytes_flow_both_ways() {
+        let (mut a, mut b) = duplex();
+        a.write_all(b"ping").unwrap();
+        b.write_all(b"pong").unwrap();
+        assert_eq!(b.available(), 4);
+
+        let mut buf = [0u8; 4];
+        b.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        a.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"pong");
+    }
+
+    #[test]
+    fn test_read_blocks_until_data_or_close() {
+        let (mut a, mut b) = duplex();
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            a.write_all(b"late").unwrap();
+        });
+        let mut received = Vec::new();
+        b.read_to_end(&mut received).unwrap();
+        writer.join().unwrap();
+        assert_eq!(received, b"late");
+    }
+
+    #[test]
+    fn test_read_timeout_and_broken_pipe() {
+        let (a, mut b) = duplex();
+        b.set_read_timeout(Some(Duration::from_millis(10)));
+        let mut buf = [0u8; 1];
+        assert_eq!(b.read(&mut buf).unwrap_err().kind(), ErrorKind::TimedOut);
+
+        drop(a);
+        assert_eq!(b.read(&mut buf).unwrap(), 0);
+        assert_eq!(b.write(b"x").unwrap_err().kind(), ErrorKind::BrokenPipe);
+    }
+}
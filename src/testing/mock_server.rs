@@ -0,0 +1,547 @@
+use crate::httpx::query::QueryParams;
+use crate::httpx::read_http_request_from;
+
+use http::{HeaderName, HeaderValue, Method, Request, StatusCode};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// ***************************************************************************
+// replies
+// ***************************************************************************
+
+/// What a mock answers. Besides plain canned responses, a reply can misbehave
+/// the way real upstreams do: arrive late, trickle in chunks, stop halfway
+/// through the body, or never come at all.
+#[derive(Clone, Debug)]
+pub struct Reply {
+    status: StatusCode,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    body: Vec<u8>,
+    template: bool,
+    delay: Duration,
+    chunk_size: Option<usize>,
+    chunk_delay: Duration,
+    truncate_at: Option<usize>,
+    hang_up: bool,
+}
+
+impl Reply {
+    pub fn new(status: StatusCode) -> Reply {
+        Reply {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+            template: false,
+            delay: Duration::ZERO,
+            chunk_size: None,
+            chunk_delay: Duration::ZERO,
+            truncate_at: None,
+            hang_up: false,
+        }
+    }
+
+    /// Close the connection right after reading the request, without answering.
+    pub fn hang_up() -> Reply {
+        Reply { hang_up: true, ..Reply::new(StatusCode::OK) }
+    }
+
+    /// # Panics
+    /// When the name or value is not a valid header.
+    pub fn header(mut self, name: &str, value: &str) -> Reply {
+        let name = HeaderName::from_bytes(name.as_bytes()).unwrap_or_else(|_| panic!("invalid header name {}", name));
+        let value = HeaderValue::from_str(value).unwrap_or_else(|_| panic!("invalid value for header {}", name));
+        self.headers.push((name, value));
+        self
+    }
+
+    pub fn body<B: Into<Vec<u8>>>(mut self, body: B) -> Reply {
+        self.body = body.into();
+        self.template = false;
+        self
+    }
+
+    /// A body with placeholders filled in from the request: `{method}`,
+    /// `{path}`, `{query}`, `{body}` and `{header.NAME}`.
+    pub fn template(mut self, template: &str) -> Reply {
+        self.body = template.as_bytes().to_vec();
+        self.template = true;
+        self
+    }
+
+    /// Wait this long before sending anything.
+    pub fn delay(mut self, delay: Duration) -> Reply {
+        self.delay = delay;
+        self
+    }
+
+    /// Send the body with chunked transfer coding, `chunk_size` bytes per chunk,
+    /// pausing `chunk_delay` after each one.
+    pub fn chunked(mut self, chunk_size: usize, chunk_delay: Duration) -> Reply {
+        self.chunk_size = Some(chunk_size.max(1));
+        self.chunk_delay = chunk_delay;
+        self
+    }
+
+    /// Announce the full body but close the connection after `bytes` of it.
+    pub fn truncate_at(mut self, bytes: usize) -> Reply {
+        self.truncate_at = Some(bytes);
+        self
+    }
+}
+
+fn render_template(template: &[u8], req: &Request<Vec<u8>>) -> Vec<u8> {
+    let template = String::from_utf8_lossy(template);
+    let mut rendered = String::new();
+    let mut rest: &str = &template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break
+        };
+        let placeholder = &rest[start + 1..end];
+        let value = match placeholder {
+            "method" => req.method().to_string(),
+            "path" => req.uri().path().to_string(),
+            "query" => req.uri().query().unwrap_or("").to_string(),
+            "body" => String::from_utf8_lossy(req.body()).to_string(),
+            _ => match placeholder.strip_prefix("header.") {
+                Some(name) => req.headers().get(name).and_then(|value| value.to_str().ok()).unwrap_or("").to_string(),
+                None => rest[start..=end].to_string()
+            }
+        };
+        rendered.push_str(&value);
+        rest = &rest[end + 1..];
+    }
+    rendered.push_str(rest);
+    rendered.into_bytes()
+}
+
+// write `reply` to `stream`; returns whether the connection can be reused
+fn write_reply(stream: &mut TcpStream, reply: &Reply, req: &Request<Vec<u8>>) -> std::io::Result<bool> {
+    thread::sleep(reply.delay);
+    if reply.hang_up {
+        return Ok(false);
+    }
+
+    let body = match reply.template {
+        true => render_template(&reply.body, req),
+        false => reply.body.clone()
+    };
+    let mut head = format!("HTTP/1.1 {} {}\r\n", reply.status.as_str(), reply.status.canonical_reason().unwrap_or(""));
+    for (name, value) in reply.headers.iter() {
+        head.push_str(&format!("{}: {}\r\n", name, value.to_str().unwrap_or("")));
+    }
+    let has_length = reply.headers.iter().any(|(name, _)| name == http::header::CONTENT_LENGTH);
+
+    if let Some(chunk_size) = reply.chunk_size {
+        head.push_str("transfer-encoding: chunked\r\n\r\n");
+        stream.write_all(head.as_bytes())?;
+        let limit = reply.truncate_at.unwrap_or(body.len()).min(body.len());
+        for chunk in body[..limit].chunks(chunk_size) {
+            stream.write_all(format!("{:x}\r\n", chunk.len()).as_bytes())?;
+            stream.write_all(chunk)?;
+            stream.write_all(b"\r\n")?;
+            stream.flush()?;
+            thread::sleep(reply.chunk_delay);
+        }
+        if reply.truncate_at.is_some() {
+            return Ok(false);
+        }
+        stream.write_all(b"0\r\n\r\n")?;
+        return Ok(true);
+    }
+
+    if !has_length {
+        head.push_str(&format!("content-length: {}\r\n", body.len()));
+    }
+    head.push_str("\r\n");
+    let sent = match reply.truncate_at {
+        Some(bytes) => &body[..bytes.min(body.len())],
+        None => &body[..]
+    };
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(sent)?;
+    stream.flush()?;
+    Ok(reply.truncate_at.is_none())
+}
+
+// ***************************************************************************
+// request matching
+// ***************************************************************************
+
+#[derive(Clone, Debug)]
+enum BodyMatcher {
+    Exact(Vec<u8>),
+    Contains(String),
+}
+
+/// An expected request and the reply to it. Only the method and path are
+/// required to match unless more matchers are added.
+#[derive(Clone, Debug)]
+pub struct Mock {
+    method: Method,
+    path: String,
+    query: Vec<(String, String)>,
+    headers: Vec<(HeaderName, String)>,
+    body: Option<BodyMatcher>,
+    reply: Reply,
+    expected_calls: Option<usize>,
+}
+
+impl Mock {
+    pub fn new(method: Method, path: &str) -> Mock {
+        Mock {
+            method,
+            path: path.to_string(),
+            query: Vec::new(),
+            headers: Vec::new(),
+            body: None,
+            reply: Reply::new(StatusCode::OK),
+            expected_calls: None,
+        }
+    }
+
+    /// Require a query parameter with this (decoded) value.
+    pub fn query(mut self, name: &str, value: &str) -> Mock {
+        self.query.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// # Panics
+    /// When `name` is not a valid header name.
+    pub fn header(mut self, name: &str, value: &str) -> Mock {
+        let name = HeaderName::from_bytes(name.as_bytes()).unwrap_or_else(|_| panic!("invalid header name {}", name));
+        self.headers.push((name, value.to_string()));
+        self
+    }
+
+    pub fn body<B: Into<Vec<u8>>>(mut self, body: B) -> Mock {
+        self.body = Some(BodyMatcher::Exact(body.into()));
+        self
+    }
+
+    pub fn body_contains(mut self, text: &str) -> Mock {
+        self.body = Some(BodyMatcher::Contains(text.to_string()));
+        self
+    }
+
+    pub fn reply(mut self, reply: Reply) -> Mock {
+        self.reply = reply;
+        self
+    }
+
+    /// Expect exactly `calls` matching requests. Without this, `assert` checks
+    /// for at least one.
+    pub fn times(mut self, calls: usize) -> Mock {
+        self.expected_calls = Some(calls);
+        self
+    }
+
+    fn matches(&self, req: &Request<Vec<u8>>) -> bool {
+        if req.method() != self.method || req.uri().path() != self.path {
+            return false;
+        }
+        let query = match QueryParams::from_request(req) {
+            Ok(query) => query,
+            Err(_) => return false
+        };
+        if !self.query.iter().all(|(name, value)| query.get_all(name).any(|actual| actual == value)) {
+            return false;
+        }
+        let headers_match = self.headers.iter().all(|(name, expected)| {
+            req.headers().get_all(name).iter().any(|value| value.to_str().map(|value| value == expected).unwrap_or(false))
+        });
+        if !headers_match {
+            return false;
+        }
+        match &self.body {
+            Some(BodyMatcher::Exact(expected)) => req.body() == expected,
+            Some(BodyMatcher::Contains(text)) => String::from_utf8_lossy(req.body()).contains(text.as_str()),
+            None => true
+        }
+    }
+}
+
+struct MockState {
+    mock: Mock,
+    calls: AtomicUsize,
+}
+
+/// Returned when a mock is registered, to check how often it was called.
+#[derive(Clone)]
+pub struct MockHandle {
+    state: Arc<MockState>,
+}
+
+impl MockHandle {
+    pub fn calls(&self) -> usize {
+        self.state.calls.load(Ordering::SeqCst)
+    }
+
+    /// # Panics
+    /// When the mock was not called as often as expected.
+    pub fn assert(&self) {
+        let mock = &self.state.mock;
+        let calls = self.calls();
+        match mock.expected_calls {
+            Some(expected) if calls != expected => {
+                panic!("mock {} {} expected {} call(s), got {}", mock.method, mock.path, expected, calls);
+            },
+            None if calls == 0 => panic!("mock {} {} was never called", mock.method, mock.path),
+            _ => {}
+        }
+    }
+}
+
+// ***************************************************************************
+// server
+// ***************************************************************************
+
+#[derive(Default)]
+struct Shared {
+    mocks: Mutex<Vec<Arc<MockState>>>,
+    unmatched: Mutex<Vec<String>>,
+    shutdown: AtomicBool,
+}
+
+impl Shared {
+    fn find(&self, req: &Request<Vec<u8>>) -> Option<Arc<MockState>> {
+        let mocks = self.mocks.lock().unwrap();
+        mocks.iter().find(|state| state.mock.matches(req)).cloned()
+    }
+}
+
+/// A scripted HTTP/1.1 server on an ephemeral local port, built on the crate's
+/// own request reader. Requests are answered by the first registered mock that
+/// matches; anything else gets a 501 and is recorded as unmatched. The server
+/// stops when dropped.
+pub struct MockServer {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+    acceptor: Option<thread::JoinHandle<()>>,
+}
+
+impl Default for MockServer {
+    fn default() -> MockServer {
+        MockServer::start()
+    }
+}
+
+impl MockServer {
+    /// # Panics
+    /// When no local port can be bound.
+    pub fn start() -> MockServer {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("MockServer: unable to bind a local port");
+        let addr = listener.local_addr().expect("MockServer: no local address");
+        listener.set_nonblocking(true).expect("MockServer: unable to configure listener");
+
+        let shared = Arc::new(Shared::default());
+        let accept_shared = shared.clone();
+        let acceptor = thread::spawn(move || {
+            while !accept_shared.shutdown.load(Ordering::SeqCst) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        let conn_shared = accept_shared.clone();
+                        thread::spawn(move || serve_mock_connection(stream, &conn_shared));
+                    },
+                    Err(_) => thread::sleep(POLL_INTERVAL)
+                }
+            }
+        });
+        MockServer { addr, shared, acceptor: Some(acceptor) }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// An absolute URL for `path` on this server.
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    pub fn mock(&self, mock: Mock) -> MockHandle {
+        let state = Arc::new(MockState { mock, calls: AtomicUsize::new(0) });
+        self.shared.mocks.lock().unwrap().push(state.clone());
+        MockHandle { state }
+    }
+
+    /// Request lines (`GET /path?query`) of the requests no mock matched.
+    pub fn unmatched(&self) -> Vec<String> {
+        self.shared.unmatched.lock().unwrap().clone()
+    }
+
+    /// # Panics
+    /// When any mock's call count is off or some request matched no mock.
+    pub fn verify(&self) {
+        for state in self.shared.mocks.lock().unwrap().iter() {
+            MockHandle { state: state.clone() }.assert();
+        }
+        let unmatched = self.unmatched();
+        if !unmatched.is_empty() {
+            panic!("MockServer got unmatched requests: {:?}", unmatched);
+        }
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, Ordering::SeqCst);
+        if let Some(acceptor) = self.acceptor.take() {
+            let _ = acceptor.join();
+        }
+    }
+}
+
+// wait for the next request on a keep-alive connection, giving up on shutdown
+fn wait_for_request(reader: &mut BufReader<TcpStream>, shared: &Shared) -> bool {
+    let idle_since = Instant::now();
+    if reader.get_ref().set_read_timeout(Some(POLL_INTERVAL)).is_err() {
+        return false;
+    }
+    loop {
+        match reader.fill_buf() {
+            Ok(buf) => return !buf.is_empty(),
+            Err(io_err) if matches!(io_err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                if shared.shutdown.load(Ordering::SeqCst) || idle_since.elapsed() > KEEP_ALIVE_TIMEOUT {
+                    return false;
+                }
+            },
+            Err(_) => return false
+        }
+    }
+}
+
+fn serve_mock_connection(stream: TcpStream, shared: &Shared) {
+    let mut reader = BufReader::new(stream);
+    while wait_for_request(&mut reader, shared) {
+        if reader.get_ref().set_read_timeout(Some(KEEP_ALIVE_TIMEOUT)).is_err() {
+            return;
+        }
+        let req = match read_http_request_from(&mut reader) {
+            Ok(req) => req,
+            Err(_) => return
+        };
+        let reply = match shared.find(&req) {
+            Some(state) => {
+                state.calls.fetch_add(1, Ordering::SeqCst);
+                state.mock.reply.clone()
+            },
+            None => {
+                let line = format!("{} {}", req.method(), req.uri());
+                shared.unmatched.lock().unwrap().push(line.clone());
+                Reply::new(StatusCode::NOT_IMPLEMENTED).body(format!("No mock matches {}", line))
+            }
+        };
+        match write_reply(reader.get_mut(), &reply, &req) {
+            Ok(true) => continue,
+            _ => return
+        }
+    }
+}
+
+
+//#################################################################################################################
+// test cases go below here
+//#################################################################################################################
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::httpx::client::Client;
+    use http::Response;
+
+    fn send(req: Request<Vec<u8>>) -> Result<Response<Vec<u8>>, crate::httpx::FlaskError> {
+        Client::new().send(req)
+    }
+
+    #[test]
+    fn test_matching_and_call_counts() {
+        let server = MockServer::start();
+        let search = server.mock(Mock::new(Method::GET, "/search")
+            .query("q", "rust lang")
+            .header("authorization", "Bearer token")
+            .reply(Reply::new(StatusCode::OK).header("content-type", "text/plain").body("found"))
+            .times(2));
+        let create = server.mock(Mock::new(Method::POST, "/items")
+            .body_contains("\"name\"")
+            .reply(Reply::new(StatusCode::CREATED)));
+
+        for _ in 0..2 {
+            let req = Request::get(server.url("/search?q=rust+lang&page=1"))
+                .header("authorization", "Bearer token")
+                .body(Vec::new()).unwrap();
+            let resp = send(req).unwrap();
+            assert_eq!(resp.body(), b"found");
+            assert_eq!(resp.headers()["content-type"], "text/plain");
+        }
+        let req = Request::post(server.url("/items")).body(b"{\"name\": \"x\"}".to_vec()).unwrap();
+        assert_eq!(send(req).unwrap().status(), StatusCode::CREATED);
+
+        search.assert();
+        create.assert();
+        server.verify();
+    }
+
+    #[test]
+    fn test_unmatched_requests_get_501() {
+        let server = MockServer::start();
+        server.mock(Mock::new(Method::GET, "/search").query("q", "x"));
+
+        let resp = send(Request::get(server.url("/search?q=y")).body(Vec::new()).unwrap()).unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_IMPLEMENTED);
+        assert_eq!(server.unmatched(), vec!["GET /search?q=y".to_string()]);
+    }
+
+    #[test]
+    #[should_panic(expected = "mock GET /once expected 1 call(s), got 2")]
+    fn test_assert_reports_wrong_call_count() {
+        let server = MockServer::start();
+        let once = server.mock(Mock::new(Method::GET, "/once").times(1));
+        for _ in 0..2 {
+            send(Request::get(server.url("/once")).body(Vec::new()).unwrap()).unwrap();
+        }
+        once.assert();
+    }
+
+    #[test]
+    fn test_templated_reply() {
+        let server = MockServer::start();
+        server.mock(Mock::new(Method::PUT, "/echo")
+            .reply(Reply::new(StatusCode::OK).template("{method} {path}?{query} by {header.x-user}: {body} {unknown}")));
+
+        let req = Request::put(server.url("/echo?a=1")).header("x-user", "ann").body(b"hi".to_vec()).unwrap();
+        assert_eq!(send(req).unwrap().body(), b"PUT /echo?a=1 by ann: hi {unknown}");
+    }
+
+    #[test]
+    fn test_slow_chunked_reply() {
+        let server = MockServer::start();
+        server.mock(Mock::new(Method::GET, "/stream")
+            .reply(Reply::new(StatusCode::OK).body("abcdefgh").delay(Duration::from_millis(30)).chunked(3, Duration::from_millis(10))));
+
+        let started = Instant::now();
+        let resp = send(Request::get(server.url("/stream")).body(Vec::new()).unwrap()).unwrap();
+        assert_eq!(resp.body(), b"abcdefgh");
+        assert!(started.elapsed() >= Duration::from_millis(60));
+    }
+
+    #[test]
+    fn test_broken_replies() {
+        let server = MockServer::start();
+        server.mock(Mock::new(Method::GET, "/truncated").reply(Reply::new(StatusCode::OK).body("0123456789").truncate_at(4)));
+        server.mock(Mock::new(Method::GET, "/truncated-chunks").reply(Reply::new(StatusCode::OK).body("0123456789").chunked(2, Duration::ZERO).truncate_at(4)));
+        server.mock(Mock::new(Method::GET, "/hang-up").reply(Reply::hang_up()));
+
+        for path in ["/truncated", "/truncated-chunks", "/hang-up"] {
+            assert!(send(Request::get(server.url(path)).body(Vec::new()).unwrap()).is_err(), "{} should fail", path);
+        }
+    }
+}
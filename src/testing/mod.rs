@@ -0,0 +1,7 @@
+mod client;
+mod duplex;
+mod mock_server;
+
+pub use client::TestClient;
+pub use duplex::{duplex, DuplexStream};
+pub use mock_server::{Mock, MockHandle, MockServer, Reply};
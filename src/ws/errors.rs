@@ -0,0 +1,43 @@
+use std::fmt;
+
+pub enum WsError {
+    ProtocolError(String),          // 1002
+    InvalidPayload(String),         // 1007
+    MessageTooBig(String),          // 1009
+    ConnectionClosed(String),
+}
+
+impl fmt::Display for WsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.get_msg())
+    }
+}
+
+impl fmt::Debug for WsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("flask::ws::Error")
+            .field(&self.get_msg())
+            .finish()
+    }
+}
+
+impl WsError {
+    pub fn get_msg(&self) -> &str {
+        match self {
+            WsError::ProtocolError(s) => s,
+            WsError::InvalidPayload(s) => s,
+            WsError::MessageTooBig(s) => s,
+            WsError::ConnectionClosed(s) => s,
+        }
+    }
+
+    /// The close code to send the peer when failing the connection for this error.
+    pub fn close_code(&self) -> Option<u16> {
+        match self {
+            WsError::ProtocolError(_) => Some(1002),
+            WsError::InvalidPayload(_) => Some(1007),
+            WsError::MessageTooBig(_) => Some(1009),
+            WsError::ConnectionClosed(_) => None,
+        }
+    }
+}
@@ -0,0 +1,591 @@
+mod errors;
+
+pub use errors::WsError;
+
+use crate::httpx::upgrade::Upgraded;
+
+use std::io::{Read, Write};
+
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 << 20;
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+// ***************************************************************************
+// frames and messages
+// ***************************************************************************
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpCode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl OpCode {
+    fn from_u8(byte: u8) -> Result<OpCode, WsError> {
+        match byte {
+            0x0 => Ok(OpCode::Continuation),
+            0x1 => Ok(OpCode::Text),
+            0x2 => Ok(OpCode::Binary),
+            0x8 => Ok(OpCode::Close),
+            0x9 => Ok(OpCode::Ping),
+            0xA => Ok(OpCode::Pong),
+            other => Err( WsError::ProtocolError(format!("Reserved opcode {:#x}", other)) )
+        }
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            OpCode::Continuation => 0x0,
+            OpCode::Text => 0x1,
+            OpCode::Binary => 0x2,
+            OpCode::Close => 0x8,
+            OpCode::Ping => 0x9,
+            OpCode::Pong => 0xA,
+        }
+    }
+
+    pub fn is_control(self) -> bool {
+        matches!(self, OpCode::Close | OpCode::Ping | OpCode::Pong)
+    }
+}
+
+/// A single frame as it appears on the wire, already unmasked.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: OpCode,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(fin: bool, opcode: OpCode, payload: Vec<u8>) -> Frame {
+        Frame { fin, opcode, payload }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CloseFrame {
+    pub code: u16,
+    pub reason: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close(Option<CloseFrame>),
+}
+
+/// Which end of the connection we are. Clients mask every frame they send,
+/// servers never do, and each side insists on the other following the rule.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Server,
+    Client,
+}
+
+// close codes a peer may legitimately put on the wire (RFC 6455 section 7.4)
+fn is_valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1011 | 3000..=4999)
+}
+
+fn parse_close_payload(payload: &[u8]) -> Result<Option<CloseFrame>, WsError> {
+    match payload.len() {
+        0 => Ok(None),
+        1 => Err( WsError::ProtocolError("Close frame payload of one byte".to_string()) ),
+        _ => {
+            let code = u16::from_be_bytes([payload[0], payload[1]]);
+            if !is_valid_close_code(code) {
+                return Err( WsError::ProtocolError(format!("Invalid close code {}", code)) );
+            }
+            match String::from_utf8(payload[2..].to_vec()) {
+                Ok(reason) => Ok( Some(CloseFrame { code, reason }) ),
+                Err(_) => Err( WsError::InvalidPayload("Close reason is not valid UTF-8".to_string()) )
+            }
+        }
+    }
+}
+
+// RFC 6455 section 5.3 asks for masking keys from a strong source of entropy
+fn random_mask() -> Result<[u8; 4], WsError> {
+    let mut mask = [0u8; 4];
+    match getrandom::getrandom(&mut mask) {
+        Ok(()) => Ok(mask),
+        Err(err) => Err( WsError::ConnectionClosed(format!("Cannot generate a masking key: {}", err)) )
+    }
+}
+
+fn apply_mask(payload: &mut [u8], mask: [u8; 4]) {
+    for (idx, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[idx % 4];
+    }
+}
+
+// ***************************************************************************
+// the connection
+// ***************************************************************************
+
+pub struct WebSocket<S> {
+    stream: S,
+    buffered: Vec<u8>,
+    role: Role,
+    max_message_size: usize,
+    close_sent: bool,
+    close_received: bool,
+}
+
+impl<S: Read + Write> WebSocket<S> {
+    pub fn new(stream: S, role: Role) -> WebSocket<S> {
+        WebSocket::with_buffered(stream, Vec::new(), role)
+    }
+
+    /// Speak WebSocket over a connection handed back by an HTTP upgrade.
+    pub fn from_upgraded(upgraded: Upgraded<S>, role: Role) -> WebSocket<S> {
+        let (stream, buffered) = upgraded.into_parts();
+        WebSocket::with_buffered(stream, buffered, role)
+    }
+
+    fn with_buffered(stream: S, buffered: Vec<u8>, role: Role) -> WebSocket<S> {
+        WebSocket {
+            stream,
+            buffered,
+            role,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            close_sent: false,
+            close_received: false,
+        }
+    }
+
+    /// Largest message, after reassembling fragments, that `read_message` accepts.
+    pub fn max_message_size(mut self, max_message_size: usize) -> WebSocket<S> {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.close_sent && self.close_received
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), WsError> {
+        let from_buffer = buf.len().min(self.buffered.len());
+        buf[..from_buffer].copy_from_slice(&self.buffered[..from_buffer]);
+        self.buffered.drain(..from_buffer);
+        match self.stream.read_exact(&mut buf[from_buffer..]) {
+            Ok(_) => Ok(()),
+            Err(io_err) => Err( WsError::ConnectionClosed(format!("Error reading frame: {}", io_err)) )
+        }
+    }
+
+    fn read_frame_limited(&mut self, limit: usize) -> Result<Frame, WsError> {
+        let mut head = [0u8; 2];
+        self.read_exact(&mut head)?;
+
+        let fin = head[0] & 0x80 != 0;
+        if head[0] & 0x70 != 0 {
+            return Err( WsError::ProtocolError("Reserved bits set without a negotiated extension".to_string()) );
+        }
+        let opcode = OpCode::from_u8(head[0] & 0x0F)?;
+        let masked = head[1] & 0x80 != 0;
+        match (self.role, masked) {
+            (Role::Server, false) => return Err( WsError::ProtocolError("Client frame is not masked".to_string()) ),
+            (Role::Client, true) => return Err( WsError::ProtocolError("Server frame is masked".to_string()) ),
+            _ => {}
+        }
+
+        let payload_len: u64 = match head[1] & 0x7F {
+            126 => {
+                let mut ext = [0u8; 2];
+                self.read_exact(&mut ext)?;
+                u16::from_be_bytes(ext) as u64
+            },
+            127 => {
+                let mut ext = [0u8; 8];
+                self.read_exact(&mut ext)?;
+                let len = u64::from_be_bytes(ext);
+                if len >> 63 != 0 {
+                    return Err( WsError::ProtocolError("Frame length has the most significant bit set".to_string()) );
+                }
+                len
+            },
+            len => len as u64
+        };
+
+        if opcode.is_control() {
+            if !fin {
+                return Err( WsError::ProtocolError("Fragmented control frame".to_string()) );
+            }
+            if payload_len > MAX_CONTROL_PAYLOAD as u64 {
+                return Err( WsError::ProtocolError("Control frame payload over 125 bytes".to_string()) );
+            }
+        } else if payload_len > limit as u64 {
+            let msg = format!("Message exceeds the maximum size of {} bytes", self.max_message_size);
+            return Err( WsError::MessageTooBig(msg) );
+        }
+
+        let mut mask = [0u8; 4];
+        if masked {
+            self.read_exact(&mut mask)?;
+        }
+        let mut payload = vec![0u8; payload_len as usize];
+        self.read_exact(&mut payload)?;
+        if masked {
+            apply_mask(&mut payload, mask);
+        }
+        Ok( Frame { fin, opcode, payload } )
+    }
+
+    /// Read the next frame off the wire with no reassembly or validation beyond framing.
+    pub fn read_frame(&mut self) -> Result<Frame, WsError> {
+        self.read_frame_limited(self.max_message_size)
+    }
+
+    pub fn write_frame(&mut self, frame: &Frame) -> Result<(), WsError> {
+        let mut out: Vec<u8> = Vec::with_capacity(frame.payload.len() + 14);
+        let fin_bit = if frame.fin { 0x80 } else { 0x00 };
+        out.push(fin_bit | frame.opcode.as_u8());
+
+        let mask_bit = if self.role == Role::Client { 0x80 } else { 0x00 };
+        let len = frame.payload.len();
+        if len < 126 {
+            out.push(mask_bit | len as u8);
+        } else if len <= u16::MAX as usize {
+            out.push(mask_bit | 126);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        } else {
+            out.push(mask_bit | 127);
+            out.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+
+        let mut payload = frame.payload.clone();
+        if self.role == Role::Client {
+            let mask = random_mask()?;
+            out.extend_from_slice(&mask);
+            apply_mask(&mut payload, mask);
+        }
+        out.extend_from_slice(&payload);
+
+        match self.stream.write_all(&out).and_then(|_| self.stream.flush()) {
+            Ok(_) => Ok(()),
+            Err(io_err) => Err( WsError::ConnectionClosed(format!("Error writing frame: {}", io_err)) )
+        }
+    }
+
+    /// Read the next complete message, reassembling fragments. Pings are answered
+    /// with a pong and a close frame is echoed before being returned.
+    pub fn read_message(&mut self) -> Result<Message, WsError> {
+        match self.read_message_inner() {
+            Ok(msg) => Ok(msg),
+            Err(ws_err) => {
+                if let Some(code) = ws_err.close_code() {
+                    // best effort: the peer may already be gone
+                    let _ = self.close(code, "");
+                }
+                Err(ws_err)
+            }
+        }
+    }
+
+    fn read_message_inner(&mut self) -> Result<Message, WsError> {
+        if self.close_received {
+            return Err( WsError::ConnectionClosed("Close frame already received".to_string()) );
+        }
+
+        let mut message: Option<(OpCode, Vec<u8>)> = None;
+        loop {
+            let received = message.as_ref().map(|(_, data)| data.len()).unwrap_or(0);
+            let frame = self.read_frame_limited(self.max_message_size - received)?;
+
+            match frame.opcode {
+                OpCode::Ping => {
+                    self.write_frame(&Frame::new(true, OpCode::Pong, frame.payload.clone()))?;
+                    if message.is_none() {
+                        return Ok( Message::Ping(frame.payload) );
+                    }
+                },
+                OpCode::Pong => {
+                    if message.is_none() {
+                        return Ok( Message::Pong(frame.payload) );
+                    }
+                },
+                OpCode::Close => {
+                    let close_frame = parse_close_payload(&frame.payload)?;
+                    self.close_received = true;
+                    if !self.close_sent {
+                        let code = close_frame.as_ref().map(|cf| cf.code).unwrap_or(1000);
+                        self.close(code, "")?;
+                    }
+                    return Ok( Message::Close(close_frame) );
+                },
+                OpCode::Text | OpCode::Binary => {
+                    if message.is_some() {
+                        return Err( WsError::ProtocolError("New data frame before the fragmented message finished".to_string()) );
+                    }
+                    message = Some((frame.opcode, frame.payload));
+                },
+                OpCode::Continuation => {
+                    match message.as_mut() {
+                        Some((_, data)) => data.extend_from_slice(&frame.payload),
+                        None => return Err( WsError::ProtocolError("Continuation frame without a message to continue".to_string()) )
+                    }
+                }
+            }
+
+            if frame.fin && !frame.opcode.is_control() {
+                // fin on a data frame always completes a message, which therefore exists
+                let (opcode, data) = message.take().unwrap_or((OpCode::Binary, Vec::new()));
+                return match opcode {
+                    OpCode::Text => match String::from_utf8(data) {
+                        Ok(text) => Ok( Message::Text(text) ),
+                        Err(_) => Err( WsError::InvalidPayload("Text message is not valid UTF-8".to_string()) )
+                    },
+                    _ => Ok( Message::Binary(data) )
+                };
+            }
+        }
+    }
+
+    pub fn send(&mut self, message: Message) -> Result<(), WsError> {
+        match message {
+            Message::Text(text) => self.write_frame(&Frame::new(true, OpCode::Text, text.into_bytes())),
+            Message::Binary(data) => self.write_frame(&Frame::new(true, OpCode::Binary, data)),
+            Message::Ping(data) => self.write_control(OpCode::Ping, data),
+            Message::Pong(data) => self.write_control(OpCode::Pong, data),
+            Message::Close(Some(close_frame)) => self.close(close_frame.code, &close_frame.reason),
+            Message::Close(None) => self.close(1000, ""),
+        }
+    }
+
+    /// Send a text or binary message split into frames of at most `fragment_size` bytes.
+    pub fn send_fragmented(&mut self, message: Message, fragment_size: usize) -> Result<(), WsError> {
+        let (opcode, data) = match message {
+            Message::Text(text) => (OpCode::Text, text.into_bytes()),
+            Message::Binary(data) => (OpCode::Binary, data),
+            control => return self.send(control),
+        };
+        let chunks: Vec<&[u8]> = data.chunks(fragment_size.max(1)).collect();
+        if chunks.is_empty() {
+            return self.write_frame(&Frame::new(true, opcode, Vec::new()));
+        }
+        for (idx, chunk) in chunks.iter().enumerate() {
+            let frame_opcode = if idx == 0 { opcode } else { OpCode::Continuation };
+            let fin = idx == chunks.len() - 1;
+            self.write_frame(&Frame::new(fin, frame_opcode, chunk.to_vec()))?;
+        }
+        Ok(())
+    }
+
+    fn write_control(&mut self, opcode: OpCode, payload: Vec<u8>) -> Result<(), WsError> {
+        if payload.len() > MAX_CONTROL_PAYLOAD {
+            return Err( WsError::ProtocolError("Control frame payload over 125 bytes".to_string()) );
+        }
+        self.write_frame(&Frame::new(true, opcode, payload))
+    }
+
+    /// Start (or complete) the closing handshake.
+    pub fn close(&mut self, code: u16, reason: &str) -> Result<(), WsError> {
+        if self.close_sent {
+            return Ok(());
+        }
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        self.write_control(OpCode::Close, payload)?;
+        self.close_sent = true;
+        Ok(())
+    }
+}
+
+
+//#################################################################################################################
+// test cases go below here
+//#################################################################################################################
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // reads come from a canned byte stream, writes are collected
+    struct Loopback {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Loopback {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Loopback {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // encode frames the way the peer with `role` would put them on the wire
+    fn wire(role: Role, frames: &[Frame]) -> Vec<u8> {
+        let mut peer = WebSocket::new(Loopback { input: Cursor::new(Vec::new()), output: Vec::new() }, role);
+        for frame in frames {
+            peer.write_frame(frame).unwrap();
+        }
+        peer.into_inner().output
+    }
+
+    fn server_for(frames: &[Frame]) -> WebSocket<Loopback> {
+        let input = wire(Role::Client, frames);
+        WebSocket::new(Loopback { input: Cursor::new(input), output: Vec::new() }, Role::Server)
+    }
+
+    fn sent_frames(ws: WebSocket<Loopback>) -> Vec<Frame> {
+        let output = ws.into_inner().output;
+        let mut client = WebSocket::new(Loopback { input: Cursor::new(output), output: Vec::new() }, Role::Client);
+        let mut frames = Vec::new();
+        while let Ok(frame) = client.read_frame() {
+            frames.push(frame);
+        }
+        frames
+    }
+
+    #[test]
+    fn test_masked_text_round_trip() {
+        let mut ws = server_for(&[Frame::new(true, OpCode::Text, b"hello".to_vec())]);
+        assert_eq!(ws.read_message().unwrap(), Message::Text("hello".to_string()));
+    }
+
+    #[test]
+    fn test_client_frames_are_masked() {
+        let bytes = wire(Role::Client, &[Frame::new(true, OpCode::Binary, vec![1, 2, 3])]);
+        assert_eq!(bytes[0], 0x82);
+        assert_eq!(bytes[1], 0x80 | 3);
+        assert_eq!(bytes.len(), 2 + 4 + 3);
+
+        // a fresh key for every frame
+        let frame = Frame::new(true, OpCode::Binary, vec![0; 4]);
+        let bytes = wire(Role::Client, &[frame.clone(), frame]);
+        assert_ne!(bytes[2..6], bytes[12..16]);
+    }
+
+    #[test]
+    fn test_extended_payload_lengths() {
+        let medium = vec![7u8; 300];
+        let large = vec![9u8; 70_000];
+        let mut ws = server_for(&[
+            Frame::new(true, OpCode::Binary, medium.clone()),
+            Frame::new(true, OpCode::Binary, large.clone()),
+        ]);
+        assert_eq!(ws.read_message().unwrap(), Message::Binary(medium));
+        assert_eq!(ws.read_message().unwrap(), Message::Binary(large));
+    }
+
+    #[test]
+    fn test_unmasked_client_frame_is_rejected() {
+        let input = wire(Role::Server, &[Frame::new(true, OpCode::Text, b"hi".to_vec())]);
+        let mut ws = WebSocket::new(Loopback { input: Cursor::new(input), output: Vec::new() }, Role::Server);
+        let ws_err = ws.read_message().err().unwrap();
+        assert_eq!(ws_err.get_msg(), "Client frame is not masked");
+
+        let frames = sent_frames(ws);
+        assert_eq!(frames[0].opcode, OpCode::Close);
+        assert_eq!(frames[0].payload[..2], 1002u16.to_be_bytes());
+    }
+
+    #[test]
+    fn test_fragmented_message_with_interleaved_ping() {
+        let mut ws = server_for(&[
+            Frame::new(false, OpCode::Text, b"hel".to_vec()),
+            Frame::new(true, OpCode::Ping, b"are you there".to_vec()),
+            Frame::new(false, OpCode::Continuation, b"lo ".to_vec()),
+            Frame::new(true, OpCode::Continuation, b"world".to_vec()),
+        ]);
+        assert_eq!(ws.read_message().unwrap(), Message::Text("hello world".to_string()));
+
+        let frames = sent_frames(ws);
+        assert_eq!(frames, vec![Frame::new(true, OpCode::Pong, b"are you there".to_vec())]);
+    }
+
+    #[test]
+    fn test_continuation_without_start_is_rejected() {
+        let mut ws = server_for(&[Frame::new(true, OpCode::Continuation, b"x".to_vec())]);
+        let ws_err = ws.read_message().err().unwrap();
+        assert_eq!(ws_err.close_code(), Some(1002));
+    }
+
+    #[test]
+    fn test_invalid_utf8_text_is_rejected() {
+        let mut ws = server_for(&[Frame::new(true, OpCode::Text, vec![0xC3, 0x28])]);
+        let ws_err = ws.read_message().err().unwrap();
+        assert_eq!(ws_err.close_code(), Some(1007));
+    }
+
+    #[test]
+    fn test_max_message_size_across_fragments() {
+        let ws = server_for(&[
+            Frame::new(false, OpCode::Binary, vec![0; 6]),
+            Frame::new(true, OpCode::Continuation, vec![0; 6]),
+        ]);
+        let mut ws = ws.max_message_size(10);
+        let ws_err = ws.read_message().err().unwrap();
+        assert_eq!(ws_err.get_msg(), "Message exceeds the maximum size of 10 bytes");
+        assert_eq!(ws_err.close_code(), Some(1009));
+    }
+
+    #[test]
+    fn test_oversized_control_frame_is_rejected() {
+        let mut ws = server_for(&[Frame::new(true, OpCode::Ping, vec![0; 126])]);
+        let ws_err = ws.read_message().err().unwrap();
+        assert_eq!(ws_err.get_msg(), "Control frame payload over 125 bytes");
+    }
+
+    #[test]
+    fn test_close_handshake_is_echoed() {
+        let mut payload = 1001u16.to_be_bytes().to_vec();
+        payload.extend_from_slice(b"going away");
+        let mut ws = server_for(&[Frame::new(true, OpCode::Close, payload)]);
+
+        let expected = CloseFrame { code: 1001, reason: "going away".to_string() };
+        assert_eq!(ws.read_message().unwrap(), Message::Close(Some(expected)));
+        assert!(ws.is_closed());
+
+        let frames = sent_frames(ws);
+        assert_eq!(frames[0].opcode, OpCode::Close);
+        assert_eq!(frames[0].payload, 1001u16.to_be_bytes().to_vec());
+    }
+
+    #[test]
+    fn test_send_fragmented() {
+        let mut ws = server_for(&[]);
+        ws.send_fragmented(Message::Binary(vec![1, 2, 3, 4, 5]), 2).unwrap();
+
+        let frames = sent_frames(ws);
+        assert_eq!(frames, vec![
+            Frame::new(false, OpCode::Binary, vec![1, 2]),
+            Frame::new(false, OpCode::Continuation, vec![3, 4]),
+            Frame::new(true, OpCode::Continuation, vec![5]),
+        ]);
+    }
+
+    #[test]
+    fn test_buffered_bytes_are_read_first() {
+        let bytes = wire(Role::Client, &[Frame::new(true, OpCode::Text, b"early".to_vec())]);
+        let (head, tail) = bytes.split_at(4);
+        let upgraded = Upgraded { stream: Loopback { input: Cursor::new(tail.to_vec()), output: Vec::new() }, buffered: head.to_vec() };
+        let mut ws = WebSocket::from_upgraded(upgraded, Role::Server);
+        assert_eq!(ws.read_message().unwrap(), Message::Text("early".to_string()));
+    }
+}